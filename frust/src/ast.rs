@@ -3,89 +3,450 @@
 
 <statement_list> ::= <statement>*
 
-<statement> ::= <let_statement>
+<statement> ::= <import_statement>
+              | <let_statement>
+              | <const_statement>
               | <assign_statement>
+              | <return_statement>
+              | <break_statement>
+              | <continue_statement>
+              | <call_statement>
               | <if_expression>
+              | <match_expression>
               | <while_expression>
+              | <for_expression>
+              | <function_definition>
+              | <struct_declaration>
+              | <enum_declaration>
+              | <block>
+
+<import_statement> ::= "import" <string> ";" | "mod" <identifier> ";"
+Only allowed at the top level, `mod util;` imports "util.fr" from the directory of the file
 
 <let_statement> ::= <let_expression> ";"
 
+<const_statement> ::= "const" <identifier> ":" <type> "=" <expression> ";"
+The initializer may only use literals, other constants and operators
+
 <assign_statement> ::=  <assign_expression> ";"
 
-<block> ::= "{" <statement_list> "}"
+<return_statement> ::= "return" <expression>? ";"
+
+<break_statement> ::= "break" ";"
+<continue_statement> ::= "continue" ";"
+
+<call_statement> ::= <call_expression> ";"
+
+<call_expression> ::= <path> "(" (<expression> ("," <expression>)*)? ")"
+
+<path> ::= (<identifier> "::")? <identifier>
+Functions, constants, structs and enums of an imported module are used through the module name:
+`util::abs(x)`, `util::LIMIT`, `util::Point { x: 1 }`, `util::Shape::Dot`
+
+<function_definition> ::= "fn" <identifier> "(" <parameter_list>? ")" ("->" <type>)? <block>
+
+<parameter_list> ::= <parameter> ("," <parameter>)*
+<parameter> ::= <identifier> ":" <type>
+Structs and enums are passed by value, functions can't return them.
+A "&mut" parameter lets a function change a variable of its caller, arrays are shared with "&"
+
+<struct_declaration> ::= "struct" <identifier> "{" (<parameter> ("," <parameter>)* ","?)? "}"
+Only at the top level, fields are integers, bool, enums or other structs
+
+<enum_declaration> ::= "enum" <identifier> "{" (<variant> ("," <variant>)* ","?)? "}"
+<variant> ::= <identifier> ("(" <type> ("," <type>)* ")")?
+Only at the top level, a value stores the index of its variant followed by the variant's fields
+
+<block> ::= "{" <statement_list> <expression>? "}"
+The trailing expression without ";" is the value of the block, otherwise it has type ()
 
 <expression> ::= <literal>
-               | <identifier>
+               | <path>
                | <binary_expression>
                | <unary_expression>
+               | <reference_expression>
+               | <deref_expression>
+               | <cast_expression>
                | <let_expression>
                | <if_expression>
+               | <match_expression>
                | <while_expression>
+               | <for_expression>
+               | <block>
                | <assign_expression>
+               | <call_expression>
+               | <array_literal>
+               | <index_expression>
+               | <struct_literal>
+               | <field_expression>
+               | <variant_expression>
 
 <binary_expression> ::= <expression> <binary_operator> <expression>
-<binary_operator> ::= "+" | "-" | "*" | "/" | "%" 
-                   | "&&" | "||" 
+<binary_operator> ::= "+" | "-" | "*" | "/" | "%"
+                   | "&&" | "||"
                    | "==" | "!=" | "<" | ">" | "<=" | ">="
+                   | "&" | "|" | "^" | "<<" | ">>" | ">>>"
+
+Precedence from lowest to highest:
+"||", "&&", "==" "!=", "<" ">" "<=" ">=", "|", "^", "&", "<<" ">>" ">>>", "+" "-", "*" "/" "%", "as", unary
 
 <unary_expression> ::= <unary_operator> <expression>
-<unary_operator> ::= "!" | "-"
+
+<cast_expression> ::= <expression> "as" <type>
+<unary_operator> ::= "!" | "-" | "~"
+
+<reference_expression> ::= "&" "mut"? <place>
+<deref_expression> ::= "*" <expression>
+Only variables, array elements, fields and dereferenced references have an address.
+Assigning through a reference or taking a "&mut" reference needs a "&mut" reference
 
 <let_expression> ::= "let" <identifier> ":" <type> "=" <expression>
 
-<if_expression> ::= "if" <expression> <block> ("else" <block>)?
+<if_expression> ::= "if" <expression> <block> ("else" (<block> | <if_expression>))?
+
+<match_expression> ::= "match" <expression> "{" (<match_arm> ","?)* "}"
+<match_arm> ::= <pattern> ("|" <pattern>)* "=>" (<block> | <expression>)
+<pattern> ::= "_" | <pattern_value> | <pattern_value> "..=" <pattern_value> | <variant_pattern>
+<pattern_value> ::= "-"? <number> | <boolean> | <path>
+<variant_pattern> ::= <path> "::" <identifier> ("(" <identifier> ("," <identifier>)* ")")?
+Arms are tried in order, an identifier in a pattern names a constant.
+A variant pattern binds the fields of the variant to new variables, "_" skips a field.
+A match on bool or an enum has to cover every value, a match on an integer needs a "_" arm
 
 <while_expression> ::= "while" <expression> <block>
 
-<assign_expression> ::= <identifier> "=" <expression>
+<for_expression> ::= "for" <identifier> "in" <expression> (".." | "..=") <expression> ("step" <expression>)? <block>
+
+<assign_expression> ::= <place> <assign_operator> <expression>
+<place> ::= <identifier> | <index_expression> | <field_expression> | <deref_expression>
+<assign_operator> ::= "=" | "+=" | "-=" | "*=" | "/=" | "%="
+                    | "&=" | "|=" | "^=" | "<<=" | ">>=" | ">>>="
+
+<array_literal> ::= "[" <expression> ("," <expression>)* "]"
+                  | "[" <expression> ";" <number> "]"
+
+<index_expression> ::= <expression> "[" <expression> "]"
+
+<struct_literal> ::= <path> "{" (<identifier> ":" <expression> ("," <identifier> ":" <expression>)* ","?)? "}"
+Not allowed directly in the head of if, while, for and match, where "{" starts the body
+
+<field_expression> ::= <expression> "." <identifier>
+
+<variant_expression> ::= <path> "::" <identifier> ("(" <expression> ("," <expression>)* ")")?
 
-<literal> ::= <number> | <boolean>
-<number> ::= <digit>+
+Builtin functions:
+print(<expression>, ...)   writes num, bool and string arguments to stdout
+println(<expression>, ...) same as print followed by a newline
+
+<literal> ::= <number> | <boolean> | <string>
+<number> ::= <digit> (<digit> | "_")*
+           | "0x" <hex_digit> (<hex_digit> | "_")*
+           | "0o" <oct_digit> (<oct_digit> | "_")*
+           | "0b" <bin_digit> (<bin_digit> | "_")*
 <boolean> ::= "true" | "false"
+<string> ::= '"' (<char> | "\" <escape>)* '"'
+<escape> ::= "n" | "t" | "r" | "0" | "\" | '"'
 
 <identifier> ::= <letter> (<letter> | <digit>)*
 
-<type> ::= "num" | "bool"
+<type> ::= "num" | "bool" | <int_type> | "[" <type> ";" <number> "]" | <path> | "&" "mut"? <type>
+<int_type> ::= "i8" | "i16" | "i32" | "i64" | "u8" | "u16" | "u32" | "u64"
 
 <digit> ::= [0-9]
+<hex_digit> ::= [0-9a-fA-F]
+<oct_digit> ::= [0-7]
+<bin_digit> ::= [01]
 <letter> ::= [a-zA-Z_]
 
  */
 
+use std::fmt;
+
+/// Node of the syntax tree with the source range it was parsed from
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+    /// Set by the type checker, code generation reads it instead of inferring again
+    pub var_type: Option<VarType>,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr {
+            kind,
+            span,
+            var_type: None,
+        }
+    }
+}
+
+/// The span and type of the node are left out, the same code parsed at another offset is the same tree
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
 
+/// Node without a place in the source
+impl From<ExprKind> for Expr {
+    fn from(kind: ExprKind) -> Self {
+        Expr::new(kind, Span::default())
+    }
+}
 
 #[derive(Debug, PartialEq, Clone)]
-pub enum Expr {
-    Number(i32),
+pub enum ExprKind {
+    /// Literals are unsigned, `-` in front of them is a unary operator
+    Number(u64),
     Bool(bool),
+    Str(String),
     Var(String),
+    /// `op_span` is the operator, errors about the operands point from there
     Binary {
         left: Box<Expr>,
         op: BinaryOp,
+        op_span: Span,
         right: Box<Expr>,
     },
     Unary {
         op: UnaryOp,
         expr: Box<Expr>,
     },
+    /// Integer conversion with truncation or sign/zero extension
+    Cast {
+        expr: Box<Expr>,
+        to: VarType,
+    },
     Let {
+        name: String,
+        name_span: Span,
+        var_type: VarType,
+        expr: Box<Expr>,
+    },
+    /// Evaluated at compile time, uses of the constant are replaced by its value
+    Const {
         name: String,
         var_type: VarType,
         expr: Box<Expr>,
     },
     If {
         condition: Box<Expr>,
-        then_branch: Vec<Expr>,
-        else_branch: Option<Vec<Expr>>,
+        then_branch: Block,
+        else_branch: Option<Block>,
+    },
+    Match {
+        scrutinee: Box<Expr>,
+        arms: Vec<MatchArm>,
     },
     While {
         condition: Box<Expr>,
-        body: Vec<Expr>,
+        body: Block,
+    },
+    /// Bounds and step are evaluated once, the loop variable is scoped to the body
+    For {
+        var: String,
+        var_span: Span,
+        start: Box<Expr>,
+        end: Box<Expr>,
+        inclusive: bool,
+        step: Option<Box<Expr>>,
+        body: Block,
     },
     Assign {
+        target: Box<Expr>,
+        expr: Box<Expr>,
+    },
+    /// `target op= expr`, the place is read and written once
+    CompoundAssign {
+        target: Box<Expr>,
+        op: BinaryOp,
+        expr: Box<Expr>,
+    },
+    Array(Vec<Expr>),
+    ArrayRepeat {
+        value: Box<Expr>,
+        count: usize,
+    },
+    Index {
+        array: Box<Expr>,
+        index: Box<Expr>,
+    },
+    /// Declared at the top level, fields are laid out in memory by `layout::TypeLayouts`
+    Struct {
+        name: String,
+        fields: Vec<Param>,
+    },
+    /// Every field is initialized once, in any order
+    StructLiteral {
         name: String,
+        fields: Vec<(String, Expr)>,
+    },
+    /// Indexing and field access dereference a reference implicitly,
+    /// the checker makes it explicit with `Deref`
+    Field {
+        base: Box<Expr>,
+        field: String,
+    },
+    /// `&place` or `&mut place`
+    Ref {
+        mutable: bool,
         expr: Box<Expr>,
     },
+    Deref(Box<Expr>),
+    /// Declared at the top level like structs
+    Enum {
+        name: String,
+        variants: Vec<EnumVariant>,
+    },
+    /// `Enum::Variant(args)`, variants without fields are written without parentheses
+    Variant {
+        enum_name: String,
+        variant: String,
+        args: Vec<Expr>,
+    },
+    Block(Block),
+    Function {
+        name: String,
+        params: Vec<Param>,
+        ret_type: Option<VarType>,
+        body: Block,
+    },
+    Call {
+        name: String,
+        args: Vec<Expr>,
+    },
+    Return(Option<Box<Expr>>),
+    Break,
+    Continue,
+    /// The module is loaded by `modules::load_program`, which replaces top level imports
+    /// with the declarations of the module
+    Import {
+        name: String,
+        path: String,
+    },
+    /// Statement with a syntax error, kept so the rest of the code is still parsed
+    Error,
+}
+
+impl Expr {
+    /// Whether the expression denotes a memory location that can be assigned to
+    pub fn is_place(&self) -> bool {
+        match &self.kind {
+            ExprKind::Var(_) | ExprKind::Index { .. } | ExprKind::Deref(_) => true,
+            ExprKind::Field { base, .. } => base.is_place(),
+            _ => false,
+        }
+    }
+
+    /// Integer expression made of literals only, its type comes from the context it is used in
+    pub fn is_untyped_int(&self) -> bool {
+        match &self.kind {
+            ExprKind::Number(_) => true,
+            ExprKind::Unary {
+                op: UnaryOp::Neg | UnaryOp::BitNot,
+                expr,
+            } => expr.is_untyped_int(),
+            ExprKind::Binary {
+                left, op, right, ..
+            } => op.is_integer_op() && left.is_untyped_int() && right.is_untyped_int(),
+            _ => false,
+        }
+    }
+}
+
+/// Byte range in the source map, every file of a program has its own range of offsets
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// Span of the same bytes in a file whose offsets start at `base`
+    pub fn shift(self, base: usize) -> Self {
+        Span::new(self.start + base, self.end + base)
+    }
+}
+
+/// Statement of a program or a block with the source it was parsed from,
+/// including its `;`
+#[derive(Debug, Clone)]
+pub struct Stmt {
+    pub expr: Expr,
+    pub span: Span,
+}
+
+impl PartialEq for Stmt {
+    fn eq(&self, other: &Self) -> bool {
+        self.expr == other.expr
+    }
+}
+
+/// Statements followed by an optional trailing expression that gives the block its value
+#[derive(Debug, PartialEq, Clone)]
+pub struct Block {
+    pub statements: Vec<Stmt>,
+    pub value: Option<Box<Expr>>,
+}
+
+/// The body runs if the value matches any of the patterns
+#[derive(Debug, PartialEq, Clone)]
+pub struct MatchArm {
+    pub patterns: Vec<Pattern>,
+    pub body: Block,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Pattern {
+    Wildcard,
+    /// Literal or constant
+    Value(Expr),
+    /// `start..=end`, both bounds included
+    Range(Expr, Expr),
+    /// `Enum::Variant(a, _)`, `None` for the fields that aren't bound
+    Variant {
+        enum_name: String,
+        variant: String,
+        bindings: Vec<Option<Binding>>,
+    },
+}
+
+/// Variable introduced by a pattern
+#[derive(Debug, Clone)]
+pub struct Binding {
+    pub name: String,
+    pub span: Span,
+}
+
+impl PartialEq for Binding {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct EnumVariant {
+    pub name: String,
+    pub fields: Vec<VarType>,
+}
+
+/// Parameter of a function or field of a struct, the span covers `name: type`
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub name: String,
+    pub var_type: VarType,
+    pub span: Span,
+}
+
+impl PartialEq for Param {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.var_type == other.var_type
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -103,16 +464,194 @@ pub enum BinaryOp {
     Gt,
     Le,
     Ge,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    /// Arithmetic shift for signed types, logical for unsigned ones
+    Shr,
+    /// Logical shift, fills with zeros
+    Ushr,
+}
+
+impl BinaryOp {
+    /// Operators taking integers and producing an integer of the same type
+    pub fn is_integer_op(&self) -> bool {
+        matches!(
+            self,
+            BinaryOp::Add
+                | BinaryOp::Sub
+                | BinaryOp::Mul
+                | BinaryOp::Div
+                | BinaryOp::Mod
+                | BinaryOp::BitAnd
+                | BinaryOp::BitOr
+                | BinaryOp::BitXor
+                | BinaryOp::Shl
+                | BinaryOp::Shr
+                | BinaryOp::Ushr
+        )
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+            BinaryOp::Eq => "==",
+            BinaryOp::Neq => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Gt => ">",
+            BinaryOp::Le => "<=",
+            BinaryOp::Ge => ">=",
+            BinaryOp::BitAnd => "&",
+            BinaryOp::BitOr => "|",
+            BinaryOp::BitXor => "^",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::Ushr => ">>>",
+        };
+        write!(f, "{}", symbol)
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum UnaryOp {
     Not,
     Neg,
+    BitNot,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            UnaryOp::Not => "!",
+            UnaryOp::Neg => "-",
+            UnaryOp::BitNot => "~",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+/// Integer types, `num` is another name for i32
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum IntType {
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl IntType {
+    pub fn from_name(name: &str) -> Option<IntType> {
+        match name {
+            "i8" => Some(IntType::I8),
+            "i16" => Some(IntType::I16),
+            "i32" => Some(IntType::I32),
+            "i64" => Some(IntType::I64),
+            "u8" => Some(IntType::U8),
+            "u16" => Some(IntType::U16),
+            "u32" => Some(IntType::U32),
+            "u64" => Some(IntType::U64),
+            _ => None,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            IntType::I8 | IntType::U8 => 8,
+            IntType::I16 | IntType::U16 => 16,
+            IntType::I32 | IntType::U32 => 32,
+            IntType::I64 | IntType::U64 => 64,
+        }
+    }
+
+    pub fn is_signed(self) -> bool {
+        matches!(
+            self,
+            IntType::I8 | IntType::I16 | IntType::I32 | IntType::I64
+        )
+    }
+
+    /// Truncates the value to the width of the type and extends it back to 64 bits
+    pub fn wrap(self, value: i64) -> i64 {
+        let shift = 64 - self.bits();
+        if self.is_signed() {
+            (value << shift) >> shift
+        } else {
+            ((value as u64) << shift >> shift) as i64
+        }
+    }
+}
+
+impl fmt::Display for IntType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            IntType::I8 => "i8",
+            IntType::I16 => "i16",
+            IntType::I32 => "num",
+            IntType::I64 => "i64",
+            IntType::U8 => "u8",
+            IntType::U16 => "u16",
+            IntType::U32 => "u32",
+            IntType::U64 => "u64",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum VarType {
-    Number,
+    Int(IntType),
     Bool,
+    /// Fixed-size array: element type and length
+    Array(Box<VarType>, usize),
+    /// Named struct, its fields are looked up in the declaration
+    Struct(String),
+    /// Named enum, like structs its values live in memory and are copied
+    Enum(String),
+    /// Address of a value of the type, `true` for `&mut`
+    Ref(Box<VarType>, bool),
+    /// Type of statements and of functions without a return type
+    Unit,
+    /// Type of expressions that never produce a value: `return`, `break`, `continue`
+    Never,
+}
+
+impl VarType {
+    pub const NUM: VarType = VarType::Int(IntType::I32);
+
+    pub fn is_scalar(&self) -> bool {
+        matches!(self, VarType::Int(_) | VarType::Bool)
+    }
+
+    /// Structs and enums, they are evaluated to their address and copied on assignment
+    pub fn is_compound(&self) -> bool {
+        matches!(self, VarType::Struct(_) | VarType::Enum(_))
+    }
+}
+
+impl fmt::Display for VarType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VarType::Int(int_type) => write!(f, "{}", int_type),
+            VarType::Bool => write!(f, "bool"),
+            VarType::Array(element, len) => write!(f, "[{}; {}]", element, len),
+            VarType::Struct(name) | VarType::Enum(name) => write!(f, "{}", name),
+            VarType::Ref(pointee, false) => write!(f, "&{}", pointee),
+            VarType::Ref(pointee, true) => write!(f, "&mut {}", pointee),
+            VarType::Unit => write!(f, "()"),
+            VarType::Never => write!(f, "!"),
+        }
+    }
 }
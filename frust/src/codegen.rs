@@ -1,92 +1,897 @@
 use crate::ast::*;
+use crate::consteval::*;
+use crate::diagnostic::{CompileError, ErrorKind};
 use crate::inst::*;
+use crate::layout::*;
 use std::collections::HashMap;
-use std::io::IoSlice;
-use std::vec;
+use std::mem;
+
+/// Size of an intermediate value pushed on the stack
+const SLOT_SIZE: i32 = 8;
+/// Return address and saved frame pointer
+const FRAME_HEADER_SIZE: i32 = 16;
+/// Number of argument registers a0-a7
+pub const MAX_ARGUMENTS: usize = 8;
+/// Linux system call numbers, the same for rv32 and rv64
+const SYS_WRITE: i32 = 64;
+const SYS_EXIT: i32 = 93;
+const STDOUT: i32 = 1;
+/// Prefix of the labels of the runtime routines, reserved for them
+pub const RUNTIME_PREFIX: &str = "__frust_";
+/// Runtime routine printing Arguments(0) as a decimal number
+const PRINT_NUM: &str = "__frust_print_num";
+/// A match is dispatched through a jump table if it has at least this many cases
+/// and they fill at least half of the table
+const JUMP_TABLE_MIN_CASES: usize = 4;
+const JUMP_TABLE_MAX_SIZE: i128 = 1024;
+/// Below this many cases the branch tree tests them one after another
+const LINEAR_SEARCH_CASES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Rv32,
+    Rv64,
+}
+
+impl Target {
+    /// Return addresses, frame and stack pointers are saved with the register width
+    fn load_pointer(self) -> Opcode {
+        match self {
+            Target::Rv32 => Opcode::Lw,
+            Target::Rv64 => Opcode::Ld,
+        }
+    }
+
+    fn store_pointer(self) -> Opcode {
+        match self {
+            Target::Rv32 => Opcode::Sw,
+            Target::Rv64 => Opcode::Sd,
+        }
+    }
+
+    /// On rv64 the result of a 32-bit operation has to come from the word form of the opcode
+    fn word_op(self, opcode: Opcode) -> Opcode {
+        match (self, opcode) {
+            (Target::Rv64, Opcode::Add) => Opcode::Addw,
+            (Target::Rv64, Opcode::Sub) => Opcode::Subw,
+            (Target::Rv64, Opcode::Mul) => Opcode::Mulw,
+            (Target::Rv64, Opcode::Div) => Opcode::Divw,
+            (Target::Rv64, Opcode::Divu) => Opcode::Divuw,
+            (Target::Rv64, Opcode::Rem) => Opcode::Remw,
+            (Target::Rv64, Opcode::Remu) => Opcode::Remuw,
+            (Target::Rv64, Opcode::Sll) => Opcode::Sllw,
+            (Target::Rv64, Opcode::Srl) => Opcode::Srlw,
+            (Target::Rv64, Opcode::Sra) => Opcode::Sraw,
+            _ => opcode,
+        }
+    }
+
+    pub fn xlen(self) -> u32 {
+        match self {
+            Target::Rv32 => 32,
+            Target::Rv64 => 64,
+        }
+    }
+
+    /// Size in bytes of an address
+    fn pointer_size(self) -> i32 {
+        self.xlen() as i32 / 8
+    }
+
+    /// Loads extend the value to the register width the same way `normalize` does
+    fn load_op(self, var_type: &VarType) -> Opcode {
+        let int_type = match var_type {
+            VarType::Int(int_type) => int_type,
+            VarType::Ref(..) => return self.load_pointer(),
+            _ => return Opcode::Lw,
+        };
+        match (int_type, self) {
+            (IntType::I8, _) => Opcode::Lb,
+            (IntType::U8, _) => Opcode::Lbu,
+            (IntType::I16, _) => Opcode::Lh,
+            (IntType::U16, _) => Opcode::Lhu,
+            (IntType::U32, Target::Rv64) => Opcode::Lwu,
+            (IntType::I32 | IntType::U32, _) => Opcode::Lw,
+            (IntType::I64 | IntType::U64, _) => Opcode::Ld,
+        }
+    }
+
+    fn store_op(self, var_type: &VarType) -> Opcode {
+        let int_type = match var_type {
+            VarType::Int(int_type) => int_type,
+            VarType::Ref(..) => return self.store_pointer(),
+            _ => return Opcode::Sw,
+        };
+        match int_type.bits() {
+            8 => Opcode::Sb,
+            16 => Opcode::Sh,
+            32 => Opcode::Sw,
+            _ => Opcode::Sd,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Variable {
+    offset: i32, // offset from frame pointer
+    var_type: VarType,
+    value: Option<i64>, // constants have no stack slot, their value is inlined
+}
+
+/// Variables declared in a block
+struct Scope {
+    variables: HashMap<String, Variable>,
+    stack_offset: i32, // stack offset at the start of the block
+}
+
+/// Jump targets of the enclosing loop
+struct LoopContext {
+    continue_label: String,
+    break_label: String,
+    temp_depth: i32, // intermediate values on the stack when the loop started
+}
+
+/// Values `low..=high` of a match go to the arm with index `arm`,
+/// bounds are ordered as the matched type is, unsigned or signed
+#[derive(Debug, Clone, Copy)]
+struct Case {
+    low: i128,
+    high: i128,
+    arm: usize,
+}
+
+/// Where the value of an array expression was pushed on the stack
+enum ArraySource {
+    Elements,
+    Repeat,
+    Address,
+}
 
 pub struct CodeGenContext {
-    symbol_table: HashMap<String, u32>, // var -> address
+    symbol_table: Vec<Scope>,
     instructions: Vec<Instruction>,
+    functions: Vec<Instruction>,
+    pending_labels: Vec<String>,
     label_count: usize,
-    stack_offset: u32,
+    stack_offset: i32,
+    frame_size: i32,
+    return_label: Option<String>,
+    loops: Vec<LoopContext>,
+    temp_depth: i32,
+    bounds_check: bool,
+    target: Target,
+    layouts: TypeLayouts,
+    strings: Vec<String>,                    // contents of .rodata
+    jump_tables: Vec<(String, Vec<String>)>, // label -> targets, also in .rodata
+    uses_print_num: bool,
+    span: Option<Span>, // statement being generated
 }
 
 impl CodeGenContext {
     pub fn new() -> Self {
-        let stack_size = 32;
         CodeGenContext {
-            symbol_table: HashMap::new(),
-            instructions: vec![Instruction::new_itype(
-                Opcode::Addi,
-                Reg::StackPointer,
-                Reg::Zero,
-                stack_size,
-            )],
+            symbol_table: vec![],
+            instructions: vec![],
+            functions: vec![],
+            pending_labels: vec![],
             label_count: 0,
-            stack_offset: stack_size,
+            stack_offset: 0,
+            frame_size: 0,
+            return_label: None,
+            loops: vec![],
+            temp_depth: 0,
+            bounds_check: false,
+            target: Target::Rv32,
+            layouts: TypeLayouts::new(Target::Rv32.pointer_size()),
+            strings: vec![],
+            jump_tables: vec![],
+            uses_print_num: false,
+            span: None,
+        }
+    }
+
+    pub fn set_target(&mut self, target: Target) {
+        self.target = target;
+    }
+
+    /// Trap with `ebreak` on out-of-range array indices
+    pub fn set_bounds_check(&mut self, enabled: bool) {
+        self.bounds_check = enabled;
+    }
+
+    /// Complete assembly file with the text and read-only data sections
+    pub fn assembly(&self) -> Vec<String> {
+        let mut lines = vec![".globl _start".to_string(), ".text".to_string()];
+        lines.extend(self.instructions.iter().map(|i| i.to_string()));
+        if !self.strings.is_empty() || !self.jump_tables.is_empty() {
+            lines.push(".section .rodata".to_string());
+            let (directive, size) = match self.target {
+                Target::Rv32 => (".word", 4),
+                Target::Rv64 => (".dword", 8),
+            };
+            for (label, targets) in &self.jump_tables {
+                lines.push(format!(".balign {}", size));
+                lines.push(format!("{}:", label));
+                lines.extend(targets.iter().map(|t| format!("{} {}", directive, t)));
+            }
+            for (i, string) in self.strings.iter().enumerate() {
+                lines.push(format!("{}:", string_label(i)));
+                lines.push(format!(".string \"{}\"", escape_string(string)));
+            }
+        }
+        lines
+    }
+
+    /// Generates the entry point for the top level statements
+    /// followed by all function definitions
+    pub fn generate_program(&mut self, program: &[Stmt]) -> Result<(), CompileError> {
+        self.layouts = TypeLayouts::from_program(program, self.target.pointer_size())?;
+        self.place_label(String::from("_start"));
+        self.emit(Instruction::new_itype(
+            Opcode::Addi,
+            Reg::Saved(0),
+            Reg::StackPointer,
+            0,
+        ));
+        let frame_index = self.allocate_frame();
+
+        self.enter_scope();
+        for stmt in program {
+            self.generate_statement(stmt)?;
+        }
+        self.exit_scope();
+        self.patch_frame(frame_index);
+
+        // exit(0)
+        self.emit(Instruction::new_itype(
+            Opcode::Addi,
+            Reg::Arguments(7),
+            Reg::Zero,
+            SYS_EXIT,
+        ));
+        self.emit(Instruction::new_itype(
+            Opcode::Addi,
+            Reg::Arguments(0),
+            Reg::Zero,
+            0,
+        ));
+        self.emit(Instruction::new_ecall());
+
+        self.instructions.append(&mut self.functions);
+        if self.uses_print_num {
+            self.generate_print_num();
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, mut instruction: Instruction) {
+        for label in self.pending_labels.drain(..) {
+            instruction.add_label(label);
         }
+        self.instructions.push(instruction);
     }
 
-    pub fn instructions(&self) -> &[Instruction] {
-        self.instructions.as_slice()
+    /// Label is attached to the next emitted instruction
+    fn place_label(&mut self, label: String) {
+        self.pending_labels.push(label);
     }
 
-    fn generate_label(&mut self, base: String) -> String {
-        let label = format!("{}_{}", base, self.label_count);
+    fn generate_label(&mut self, base: &str) -> String {
+        let label = format!(".L{}_{}", base, self.label_count);
         self.label_count += 1;
         label
     }
 
-    fn allocate_variable(&mut self, var: String) {
-        self.symbol_table.insert(var.to_string(), self.stack_offset);
-        self.stack_offset -= 8; // allocate 8 bytes for i64 (num type)
+    /// Emits the stack pointer adjustment for local variables,
+    /// its size is known only after the body is generated
+    fn allocate_frame(&mut self) -> usize {
+        self.stack_offset = 0;
+        self.frame_size = 0;
+        self.emit(Instruction::new_itype(
+            Opcode::Addi,
+            Reg::StackPointer,
+            Reg::StackPointer,
+            0,
+        ));
+        self.instructions.len() - 1
+    }
+
+    fn patch_frame(&mut self, index: usize) {
+        // Keep the stack 16-byte aligned
+        let size = (self.frame_size + 15) & !15;
+        if fits_imm12(-size) {
+            self.instructions[index].set_offset(-size);
+            return;
+        }
+        let scratch = Reg::Temp(6);
+        self.instructions[index] =
+            Instruction::new_rtype(Opcode::Sub, Reg::StackPointer, Reg::StackPointer, scratch);
+        let load = int_literal(size as i64, scratch, self.target);
+        self.instructions.splice(index..index, load);
+    }
+
+    fn enter_scope(&mut self) {
+        self.symbol_table.push(Scope {
+            variables: HashMap::new(),
+            stack_offset: self.stack_offset,
+        });
+    }
+
+    /// Stack slots of the block's variables are reused by the following code
+    fn exit_scope(&mut self) {
+        let scope = self.symbol_table.pop().expect("Scope stack is empty");
+        self.stack_offset = scope.stack_offset;
+    }
+
+    /// The value of the trailing expression is left in Temp(0)
+    fn generate_block(&mut self, block: &Block) -> Result<(), CompileError> {
+        self.enter_scope();
+        for stmt in &block.statements {
+            self.generate_statement(stmt)?;
+        }
+        if let Some(value) = &block.value {
+            self.generate(value)?;
+        }
+        self.exit_scope();
+        Ok(())
+    }
+
+    /// Errors point at the innermost statement being generated
+    fn generate_statement(&mut self, stmt: &Stmt) -> Result<(), CompileError> {
+        let outer = self.span.replace(stmt.span);
+        self.generate(&stmt.expr)?;
+        self.span = outer;
+        Ok(())
+    }
+
+    fn error(&self, kind: ErrorKind, message: String) -> CompileError {
+        CompileError {
+            span: self.span,
+            ..CompileError::new(kind, message)
+        }
+    }
+
+    /// A redeclared variable gets a new slot and shadows the old one
+    fn allocate_variable(&mut self, var: String, var_type: VarType) -> i32 {
+        let offset = self.reserve_slot(&var_type);
+        self.declare_variable(var, var_type, offset);
+        offset
+    }
+
+    /// Stack slot sized and aligned by the layout of the type
+    fn reserve_slot(&mut self, var_type: &VarType) -> i32 {
+        let size = self.layouts.size_of(var_type);
+        self.stack_offset = align_to(self.stack_offset + size, self.layouts.align_of(var_type));
+        self.frame_size = self.frame_size.max(self.stack_offset);
+        -self.stack_offset
     }
 
-    fn load_variable(&mut self, var: String, dest: Reg) -> Result<(), String> {
-        if let Some(addr) = self.symbol_table.get(&var) {
-            self.instructions.push(Instruction::new_itype(
-                Opcode::Lw,
+    fn declare_variable(&mut self, var: String, var_type: VarType, offset: i32) {
+        self.symbol_table
+            .last_mut()
+            .expect("Scope stack is empty")
+            .variables
+            .insert(
+                var,
+                Variable {
+                    offset,
+                    var_type,
+                    value: None,
+                },
+            );
+    }
+
+    fn define_constant(&mut self, name: String, constant: Constant) {
+        self.symbol_table
+            .last_mut()
+            .expect("Scope stack is empty")
+            .variables
+            .insert(
+                name,
+                Variable {
+                    offset: 0,
+                    var_type: constant.var_type,
+                    value: Some(constant.value),
+                },
+            );
+    }
+
+    fn is_constant(&self, name: &str) -> bool {
+        self.lookup_constant(name).is_some()
+    }
+
+    fn lookup_constant(&self, name: &str) -> Option<Constant> {
+        let variable = self.lookup_variable(name).ok()?;
+        Some(Constant {
+            value: variable.value?,
+            var_type: variable.var_type,
+        })
+    }
+
+    fn lookup_variable(&self, var: &str) -> Result<Variable, CompileError> {
+        self.symbol_table
+            .iter()
+            .rev()
+            .find_map(|scope| scope.variables.get(var).cloned())
+            .ok_or_else(|| {
+                let message = format!("Variable {} not declared", var);
+                self.error(ErrorKind::UnknownName, message)
+            })
+    }
+
+    /// dest = frame pointer + offset
+    fn frame_address(&mut self, offset: i32, dest: Reg) {
+        if fits_imm12(offset) {
+            self.emit(Instruction::new_itype(
+                Opcode::Addi,
+                dest,
+                Reg::Saved(0),
+                offset,
+            ));
+        } else {
+            self.load_int_literal(offset as i64, dest);
+            self.emit(Instruction::new_rtype(
+                Opcode::Add,
+                dest,
+                Reg::Saved(0),
                 dest,
-                Reg::StackPointer,
-                *addr,
             ));
-            Ok(())
+        }
+    }
+
+    fn load_variable(&mut self, var: String, dest: Reg) -> Result<(), CompileError> {
+        let variable = self.lookup_variable(&var)?;
+        if let Some(value) = variable.value {
+            self.load_int_literal(value, dest);
+        } else if matches!(variable.var_type, VarType::Array(..)) || variable.var_type.is_compound()
+        {
+            // Arrays, structs and enums are referred to by the address of their first byte
+            self.frame_address(variable.offset, dest);
+        } else {
+            self.load_at(variable.offset, &variable.var_type, dest);
+        }
+        Ok(())
+    }
+
+    /// Structs and enums are copied from the address in `src`
+    fn store_variable(&mut self, var: String, src: Reg) -> Result<(), CompileError> {
+        let variable = self.lookup_variable(&var)?;
+        if variable.var_type.is_compound() {
+            self.store_compound(&variable.var_type, src, variable.offset);
         } else {
-            Err(format!("Variable {} not declared", var))
+            self.store_at(variable.offset, &variable.var_type, src);
+        }
+        Ok(())
+    }
+
+    /// Evaluates an array expression onto the stack,
+    /// it is written to its destination by `store_array`
+    fn push_array(&mut self, expr: &Expr) -> Result<ArraySource, CompileError> {
+        match &expr.kind {
+            ExprKind::Array(elements) => {
+                for element in elements {
+                    self.generate(element)?;
+                    self.push(Reg::Temp(0));
+                }
+                Ok(ArraySource::Elements)
+            }
+            ExprKind::ArrayRepeat { value, .. } => {
+                self.generate(value)?;
+                self.push(Reg::Temp(0));
+                Ok(ArraySource::Repeat)
+            }
+            // Variables, references and the values of blocks evaluate to an address
+            _ => {
+                self.generate(expr)?;
+                self.push(Reg::Temp(0));
+                Ok(ArraySource::Address)
+            }
+        }
+    }
+
+    fn store_array(&mut self, source: ArraySource, offset: i32, element: &VarType, len: usize) {
+        let (value, dest, counter) = (Reg::Temp(0), Reg::Temp(1), Reg::Temp(2));
+        let size = self.layouts.size_of(element);
+        let (load, store) = (self.target.load_op(element), self.target.store_op(element));
+        match source {
+            ArraySource::Elements => {
+                // Elements are popped in reverse order
+                self.frame_address(offset + size * (len as i32 - 1), dest);
+                for _ in 0..len {
+                    self.pop(value);
+                    self.emit(Instruction::new_stype(store, dest, value, 0));
+                    self.emit(Instruction::new_itype(Opcode::Addi, dest, dest, -size));
+                }
+            }
+            ArraySource::Repeat => {
+                let loop_label = self.generate_label("fill");
+                self.pop(value);
+                self.frame_address(offset, dest);
+                self.load_int_literal(len as i64, counter);
+                self.place_label(loop_label.clone());
+                self.emit(Instruction::new_stype(store, dest, value, 0));
+                self.emit(Instruction::new_itype(Opcode::Addi, dest, dest, size));
+                self.emit(Instruction::new_itype(Opcode::Addi, counter, counter, -1));
+                self.emit(Instruction::new_btype(
+                    Opcode::Bne,
+                    counter,
+                    Reg::Zero,
+                    loop_label,
+                ));
+            }
+            ArraySource::Address => {
+                let loop_label = self.generate_label("copy");
+                let src = Reg::Temp(3);
+                self.pop(src);
+                self.frame_address(offset, dest);
+                self.load_int_literal(len as i64, counter);
+                self.place_label(loop_label.clone());
+                self.emit(Instruction::new_itype(load, value, src, 0));
+                self.emit(Instruction::new_stype(store, dest, value, 0));
+                self.emit(Instruction::new_itype(Opcode::Addi, src, src, size));
+                self.emit(Instruction::new_itype(Opcode::Addi, dest, dest, size));
+                self.emit(Instruction::new_itype(Opcode::Addi, counter, counter, -1));
+                self.emit(Instruction::new_btype(
+                    Opcode::Bne,
+                    counter,
+                    Reg::Zero,
+                    loop_label,
+                ));
+            }
         }
     }
 
-    fn store_variable(&mut self, var: String, src: Reg) -> Result<(), String> {
-        if let Some(addr) = self.symbol_table.get(&var) {
-            self.instructions.push(Instruction::new_stype(
-                Opcode::Sw,
-                Reg::StackPointer,
-                src,
-                *addr,
+    /// Temp(0) = address of array[index], an array behind a reference
+    /// is kept on the stack while the index is evaluated
+    fn generate_element_address(&mut self, array: &Expr, index: &Expr) -> Result<(), CompileError> {
+        let VarType::Array(element, len) = self.type_of(array)? else {
+            let message = "Only arrays can be indexed".to_string();
+            return Err(self.error(ErrorKind::InvalidOperand, message));
+        };
+        let base = match &array.kind {
+            ExprKind::Var(name) => Some(self.lookup_variable(name)?.offset),
+            ExprKind::Deref(_) => {
+                self.generate(array)?;
+                self.push(Reg::Temp(0));
+                None
+            }
+            _ => {
+                let message = "Only array variables and references can be indexed".to_string();
+                return Err(self.error(ErrorKind::InvalidPlace, message));
+            }
+        };
+
+        self.generate(index)?;
+        if self.bounds_check {
+            // Negative indices are out of range as unsigned numbers
+            let in_bounds = self.generate_label("in_bounds");
+            self.load_int_literal(len as i64, Reg::Temp(1));
+            self.emit(Instruction::new_btype(
+                Opcode::Bltu,
+                Reg::Temp(0),
+                Reg::Temp(1),
+                in_bounds.clone(),
             ));
-            Ok(())
+            self.emit(Instruction::new_ebreak());
+            self.place_label(in_bounds);
+        }
+        let shift = self.layouts.size_of(&element).trailing_zeros() as i32;
+        if shift > 0 {
+            self.emit(Instruction::new_itype(
+                Opcode::Slli,
+                Reg::Temp(0),
+                Reg::Temp(0),
+                shift,
+            ));
+        }
+        match base {
+            Some(offset) => self.frame_address(offset, Reg::Temp(1)),
+            None => self.pop(Reg::Temp(1)),
+        }
+        self.emit(Instruction::new_rtype(
+            Opcode::Add,
+            Reg::Temp(0),
+            Reg::Temp(1),
+            Reg::Temp(0),
+        ));
+        Ok(())
+    }
+
+    /// Temp(0) = address of a field, the base struct is evaluated to its address
+    fn generate_field_address(&mut self, base: &Expr, field: &str) -> Result<(), CompileError> {
+        let VarType::Struct(name) = self.type_of(base)? else {
+            let message = format!("Cannot access field {} of a non-struct value", field);
+            return Err(self.error(ErrorKind::InvalidOperand, message));
+        };
+        let offset = self.layouts.field(&name, field)?.offset;
+        self.generate(base)?;
+        self.add_offset(Reg::Temp(0), offset);
+        Ok(())
+    }
+
+    /// Temp(0) = address of a variable, an array element, a struct field
+    /// or the value a reference points to
+    fn generate_place_address(&mut self, target: &Expr) -> Result<(), CompileError> {
+        match &target.kind {
+            ExprKind::Var(name) => {
+                let variable = self.lookup_variable(name)?;
+                if variable.value.is_some() {
+                    let message = format!("Constant {} has no address", name);
+                    return Err(self.error(ErrorKind::InvalidPlace, message));
+                }
+                self.frame_address(variable.offset, Reg::Temp(0));
+                Ok(())
+            }
+            ExprKind::Index { array, index } => self.generate_element_address(array, index),
+            ExprKind::Field { base, field } => self.generate_field_address(base, field),
+            ExprKind::Deref(pointer) => self.generate(pointer),
+            _ => {
+                let message = "Invalid left-hand side of assignment".to_string();
+                Err(self.error(ErrorKind::InvalidPlace, message))
+            }
+        }
+    }
+
+    /// reg = reg + offset
+    fn add_offset(&mut self, reg: Reg, offset: i32) {
+        if offset == 0 {
+            return;
+        }
+        if fits_imm12(offset) {
+            self.emit(Instruction::new_itype(Opcode::Addi, reg, reg, offset));
         } else {
-            Err(format!("Variable {} not declared", var))
+            let scratch = Reg::Temp(6);
+            self.load_int_literal(offset as i64, scratch);
+            self.emit(Instruction::new_rtype(Opcode::Add, reg, reg, scratch));
+        }
+    }
+
+    /// Copies a struct or an enum through Temp(2), `src` and `dest` are advanced
+    /// when the offsets don't fit in an immediate
+    fn copy_compound(&mut self, var_type: &VarType, src: Reg, dest: Reg) {
+        let value = Reg::Temp(2);
+        let mut base = 0;
+        for (offset, var_type) in self.layouts.scalar_fields(var_type) {
+            if !fits_imm12(offset - base) {
+                self.add_offset(src, offset - base);
+                self.add_offset(dest, offset - base);
+                base = offset;
+            }
+            let (load, store) = (
+                self.target.load_op(&var_type),
+                self.target.store_op(&var_type),
+            );
+            self.emit(Instruction::new_itype(load, value, src, offset - base));
+            self.emit(Instruction::new_stype(store, dest, value, offset - base));
+        }
+    }
+
+    /// Copies the value at the address in `src` to a frame slot through Temp(1),
+    /// arrays are copied in a loop
+    fn store_compound(&mut self, var_type: &VarType, src: Reg, offset: i32) {
+        if let VarType::Array(element, len) = var_type {
+            self.push(src);
+            self.store_array(ArraySource::Address, offset, element, *len);
+            return;
+        }
+        self.frame_address(offset, Reg::Temp(1));
+        self.copy_compound(var_type, src, Reg::Temp(1));
+    }
+
+    /// Stores the value of `expr` to a frame slot, literals and variants are built in place
+    fn build_compound(&mut self, expr: &Expr, offset: i32) -> Result<(), CompileError> {
+        match &expr.kind {
+            ExprKind::StructLiteral { name, fields } => {
+                self.generate_struct_literal(name, fields, offset)
+            }
+            ExprKind::Variant {
+                enum_name,
+                variant,
+                args,
+            } => self.generate_variant(enum_name, variant, args, offset),
+            ExprKind::Array(_) | ExprKind::ArrayRepeat { .. } => {
+                if let VarType::Array(element, len) = self.type_of(expr)? {
+                    let source = self.push_array(expr)?;
+                    self.store_array(source, offset, &element, len);
+                }
+                Ok(())
+            }
+            _ => {
+                let var_type = self.type_of(expr)?;
+                self.generate(expr)?;
+                self.store_compound(&var_type, Reg::Temp(0), offset);
+                Ok(())
+            }
+        }
+    }
+
+    /// Stores a field of a struct or a variant at `offset`
+    fn store_field(
+        &mut self,
+        value: &Expr,
+        var_type: &VarType,
+        offset: i32,
+    ) -> Result<(), CompileError> {
+        if var_type.is_compound() {
+            return self.build_compound(value, offset);
+        }
+        self.generate(value)?;
+        self.store_at(offset, var_type, Reg::Temp(0));
+        Ok(())
+    }
+
+    /// Writes the fields of a struct literal to a frame slot, in the order they are written
+    fn generate_struct_literal(
+        &mut self,
+        name: &str,
+        fields: &[(String, Expr)],
+        offset: i32,
+    ) -> Result<(), CompileError> {
+        for (field, value) in fields {
+            let layout = self.layouts.field(name, field)?;
+            let (field_offset, var_type) = (offset + layout.offset, layout.var_type.clone());
+            self.store_field(value, &var_type, field_offset)?;
         }
+        Ok(())
+    }
+
+    /// Writes the tag and the fields of a variant to a frame slot
+    fn generate_variant(
+        &mut self,
+        name: &str,
+        variant: &str,
+        args: &[Expr],
+        offset: i32,
+    ) -> Result<(), CompileError> {
+        let (tag, layout) = self.layouts.variant(name, variant)?;
+        let fields = layout.fields.clone();
+        for (arg, (field_offset, var_type)) in args.iter().zip(fields) {
+            self.store_field(arg, &var_type, offset + field_offset)?;
+        }
+        self.load_int_literal(tag as i64, Reg::Temp(0));
+        self.store_at(offset, &TAG_TYPE, Reg::Temp(0));
+        Ok(())
+    }
+
+    /// The slots of a block are reused after it ends, so a struct or an enum
+    /// it evaluates to is copied to a slot of the enclosing scope
+    fn generate_compound_value(
+        &mut self,
+        var_type: &VarType,
+        expr: &Expr,
+    ) -> Result<(), CompileError> {
+        let offset = self.reserve_slot(var_type);
+        match &expr.kind {
+            ExprKind::Block(block) => self.generate_block(block)?,
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => self.generate_if(condition, then_branch, else_branch)?,
+            ExprKind::Match { scrutinee, arms } => self.generate_match(scrutinee, arms)?,
+            // Literals and variants get a hidden slot of their own
+            _ => {
+                self.build_compound(expr, offset)?;
+                self.frame_address(offset, Reg::Temp(0));
+                return Ok(());
+            }
+        }
+        self.store_compound(var_type, Reg::Temp(0), offset);
+        self.frame_address(offset, Reg::Temp(0));
+        Ok(())
     }
 
-    fn load_int_literal(&mut self, val: u32, dest: Reg) {
-        self.instructions.push(Instruction::new_itype(
-            Opcode::Lui,
+    /// Loads a scalar from the frame at `offset`
+    fn load_at(&mut self, offset: i32, var_type: &VarType, dest: Reg) {
+        let load = self.target.load_op(var_type);
+        if fits_imm12(offset) {
+            self.emit(Instruction::new_itype(load, dest, Reg::Saved(0), offset));
+        } else {
+            self.frame_address(offset, dest);
+            self.emit(Instruction::new_itype(load, dest, dest, 0));
+        }
+    }
+
+    /// Stores a scalar to the frame at `offset`
+    fn store_at(&mut self, offset: i32, var_type: &VarType, src: Reg) {
+        let store = self.target.store_op(var_type);
+        if fits_imm12(offset) {
+            self.emit(Instruction::new_stype(store, Reg::Saved(0), src, offset));
+        } else {
+            let scratch = Reg::Temp(6);
+            self.frame_address(offset, scratch);
+            self.emit(Instruction::new_stype(store, scratch, src, 0));
+        }
+    }
+
+    /// Saves an intermediate value on the stack
+    fn push(&mut self, src: Reg) {
+        self.temp_depth += SLOT_SIZE;
+        self.emit(Instruction::new_itype(
+            Opcode::Addi,
+            Reg::StackPointer,
+            Reg::StackPointer,
+            -SLOT_SIZE,
+        ));
+        self.emit(Instruction::new_stype(
+            self.target.store_pointer(),
+            Reg::StackPointer,
+            src,
+            0,
+        ));
+    }
+
+    fn pop(&mut self, dest: Reg) {
+        self.temp_depth -= SLOT_SIZE;
+        self.emit(Instruction::new_itype(
+            self.target.load_pointer(),
             dest,
-            Reg::Zero,
-            val >> 12,
+            Reg::StackPointer,
+            0,
         ));
-        self.instructions.push(Instruction::new_itype(
+        self.emit(Instruction::new_itype(
             Opcode::Addi,
-            dest,
-            Reg::Zero,
-            val & 0b111111111111,
+            Reg::StackPointer,
+            Reg::StackPointer,
+            SLOT_SIZE,
         ));
     }
 
+    fn mov(&mut self, dest: Reg, src: Reg) {
+        self.emit(Instruction::new_itype(Opcode::Addi, dest, src, 0));
+    }
+
+    fn load_int_literal(&mut self, val: i64, dest: Reg) {
+        for instruction in int_literal(val, dest, self.target) {
+            self.emit(instruction);
+        }
+    }
+
+    /// Registers hold integers sign-extended or zero-extended to their full width,
+    /// the bits above the type's width are restored after an operation
+    fn normalize(&mut self, reg: Reg, int_type: IntType) {
+        let (bits, xlen) = (int_type.bits(), self.target.xlen());
+        if bits == xlen {
+            return;
+        }
+        if !int_type.is_signed() {
+            self.zero_extend(reg, bits);
+        } else if bits == 32 {
+            self.emit(Instruction::new_itype(Opcode::Addiw, reg, reg, 0));
+        } else {
+            let shift = (xlen - bits) as i32;
+            self.emit(Instruction::new_itype(Opcode::Slli, reg, reg, shift));
+            self.emit(Instruction::new_itype(Opcode::Srai, reg, reg, shift));
+        }
+    }
+
+    fn zero_extend(&mut self, reg: Reg, bits: u32) {
+        if bits == 8 {
+            self.emit(Instruction::new_itype(Opcode::Andi, reg, reg, 0xff));
+            return;
+        }
+        let shift = (self.target.xlen() - bits) as i32;
+        self.emit(Instruction::new_itype(Opcode::Slli, reg, reg, shift));
+        self.emit(Instruction::new_itype(Opcode::Srli, reg, reg, shift));
+    }
+
+    /// dest = lhs op rhs computed in the width of `int_type`
+    fn emit_int_op(&mut self, opcode: Opcode, int_type: IntType, dest: Reg, lhs: Reg, rhs: Reg) {
+        let word = int_type.bits() == 32 && self.target == Target::Rv64;
+        let opcode = if word {
+            self.target.word_op(opcode)
+        } else {
+            opcode
+        };
+        self.emit(Instruction::new_rtype(opcode, dest, lhs, rhs));
+        // Word operations sign-extend their result
+        if !(word && int_type.is_signed()) {
+            self.normalize(dest, int_type);
+        }
+    }
+
     fn load_bool_literal(&mut self, val: bool, dest: Reg) {
-        self.instructions.push(Instruction::new_itype(
+        self.emit(Instruction::new_itype(
             Opcode::Addi,
             dest,
             Reg::Zero,
@@ -94,145 +899,1221 @@ impl CodeGenContext {
         ));
     }
 
-    pub fn generate(&mut self, expr: &Expr) -> Result<(), String> {
-        match expr {
-            Expr::Number(n) => {
-                self.load_int_literal(*n as u32, Reg::Temp(0));
+    /// Type the checker resolved for the expression
+    fn type_of(&self, expr: &Expr) -> Result<VarType, CompileError> {
+        expr.var_type.clone().ok_or_else(|| {
+            let message = "Expression was not type checked".to_string();
+            self.error(ErrorKind::Codegen, message)
+        })
+    }
+
+    /// Label of the string in .rodata, equal strings share one copy
+    fn intern_string(&mut self, string: &str) -> String {
+        let index = match self.strings.iter().position(|s| s == string) {
+            Some(index) => index,
+            None => {
+                self.strings.push(string.to_string());
+                self.strings.len() - 1
             }
-            Expr::Bool(b) => {
+        };
+        string_label(index)
+    }
+
+    /// write(stdout, Arguments(1), Arguments(2))
+    fn emit_write(&mut self) {
+        self.emit(Instruction::new_itype(
+            Opcode::Addi,
+            Reg::Arguments(0),
+            Reg::Zero,
+            STDOUT,
+        ));
+        self.emit(Instruction::new_itype(
+            Opcode::Addi,
+            Reg::Arguments(7),
+            Reg::Zero,
+            SYS_WRITE,
+        ));
+        self.emit(Instruction::new_ecall());
+    }
+
+    fn print_string(&mut self, string: &str) {
+        if string.is_empty() {
+            return;
+        }
+        let label = self.intern_string(string);
+        self.emit(Instruction::new_la(Reg::Arguments(1), label));
+        self.load_int_literal(string.len() as i64, Reg::Arguments(2));
+        self.emit_write();
+    }
+
+    fn generate_print(&mut self, args: &[Expr], newline: bool) -> Result<(), CompileError> {
+        for arg in args {
+            if let ExprKind::Str(string) = &arg.kind {
+                self.print_string(string);
+                continue;
+            }
+            let arg_type = self.type_of(arg)?;
+            self.generate(arg)?;
+            match arg_type {
+                VarType::Bool => {
+                    let print_label = self.generate_label("print");
+                    let true_label = self.intern_string("true");
+                    let false_label = self.intern_string("false");
+                    self.emit(Instruction::new_la(Reg::Arguments(1), false_label));
+                    self.load_int_literal(5, Reg::Arguments(2));
+                    self.emit(Instruction::new_btype(
+                        Opcode::Beq,
+                        Reg::Temp(0),
+                        Reg::Zero,
+                        print_label.clone(),
+                    ));
+                    self.emit(Instruction::new_la(Reg::Arguments(1), true_label));
+                    self.load_int_literal(4, Reg::Arguments(2));
+                    self.place_label(print_label);
+                    self.emit_write();
+                }
+                VarType::Int(int_type) => {
+                    self.uses_print_num = true;
+                    self.mov(Reg::Arguments(0), Reg::Temp(0));
+                    self.load_int_literal(!int_type.is_signed() as i64, Reg::Arguments(1));
+                    self.emit(Instruction::new_jtype(
+                        Opcode::Jal,
+                        Reg::ReturnAddress,
+                        PRINT_NUM.to_string(),
+                    ));
+                }
+                other => {
+                    let message = format!("Values of type {} can't be printed", other);
+                    return Err(self.error(ErrorKind::InvalidOperand, message));
+                }
+            }
+        }
+        if newline {
+            self.print_string("\n");
+        }
+        Ok(())
+    }
+
+    /// Leaf routine writing the digits of Arguments(0) backwards into a stack buffer,
+    /// Arguments(1) is nonzero for unsigned numbers.
+    /// The magnitude of a negative number is divided as unsigned, so the minimal value doesn't overflow
+    fn generate_print_num(&mut self) {
+        const BUFFER_SIZE: i32 = 32;
+        let (digit, value, end, ten, negative) = (
+            Reg::Temp(0),
+            Reg::Temp(1),
+            Reg::Temp(2),
+            Reg::Temp(3),
+            Reg::Temp(4),
+        );
+        let loop_label = self.generate_label("digit");
+        let write_label = self.generate_label("write");
+
+        self.place_label(PRINT_NUM.to_string());
+        self.emit(Instruction::new_itype(
+            Opcode::Addi,
+            Reg::StackPointer,
+            Reg::StackPointer,
+            -BUFFER_SIZE,
+        ));
+        self.emit(Instruction::new_itype(
+            Opcode::Addi,
+            end,
+            Reg::StackPointer,
+            BUFFER_SIZE,
+        ));
+        self.mov(value, Reg::Arguments(0));
+        self.emit(Instruction::new_itype(Opcode::Addi, ten, Reg::Zero, 10));
+        self.emit(Instruction::new_itype(Opcode::Addi, negative, Reg::Zero, 0));
+        self.emit(Instruction::new_btype(
+            Opcode::Bne,
+            Reg::Arguments(1),
+            Reg::Zero,
+            loop_label.clone(),
+        ));
+        self.emit(Instruction::new_rtype(
+            Opcode::Slt,
+            negative,
+            value,
+            Reg::Zero,
+        ));
+        self.emit(Instruction::new_btype(
+            Opcode::Beq,
+            negative,
+            Reg::Zero,
+            loop_label.clone(),
+        ));
+        self.emit(Instruction::new_rtype(Opcode::Sub, value, Reg::Zero, value));
+
+        self.place_label(loop_label.clone());
+        self.emit(Instruction::new_rtype(Opcode::Remu, digit, value, ten));
+        self.emit(Instruction::new_rtype(Opcode::Divu, value, value, ten));
+        self.emit(Instruction::new_itype(
+            Opcode::Addi,
+            digit,
+            digit,
+            b'0' as i32,
+        ));
+        self.emit(Instruction::new_itype(Opcode::Addi, end, end, -1));
+        self.emit(Instruction::new_stype(Opcode::Sb, end, digit, 0));
+        self.emit(Instruction::new_btype(
+            Opcode::Bne,
+            value,
+            Reg::Zero,
+            loop_label,
+        ));
+
+        self.emit(Instruction::new_btype(
+            Opcode::Beq,
+            negative,
+            Reg::Zero,
+            write_label.clone(),
+        ));
+        self.emit(Instruction::new_itype(
+            Opcode::Addi,
+            digit,
+            Reg::Zero,
+            b'-' as i32,
+        ));
+        self.emit(Instruction::new_itype(Opcode::Addi, end, end, -1));
+        self.emit(Instruction::new_stype(Opcode::Sb, end, digit, 0));
+
+        self.place_label(write_label);
+        self.mov(Reg::Arguments(1), end);
+        self.emit(Instruction::new_itype(
+            Opcode::Addi,
+            Reg::Arguments(2),
+            Reg::StackPointer,
+            BUFFER_SIZE,
+        ));
+        self.emit(Instruction::new_rtype(
+            Opcode::Sub,
+            Reg::Arguments(2),
+            Reg::Arguments(2),
+            end,
+        ));
+        self.emit_write();
+        self.emit(Instruction::new_itype(
+            Opcode::Addi,
+            Reg::StackPointer,
+            Reg::StackPointer,
+            BUFFER_SIZE,
+        ));
+        self.emit(Instruction::new_itype(
+            Opcode::Jalr,
+            Reg::Zero,
+            Reg::ReturnAddress,
+            0,
+        ));
+    }
+
+    fn generate_function(
+        &mut self,
+        name: &str,
+        params: &[Param],
+        ret_type: &Option<VarType>,
+        body: &Block,
+    ) -> Result<(), CompileError> {
+        if self.return_label.is_some() {
+            let message = format!("Nested function {} is not supported", name);
+            return Err(self.error(ErrorKind::Misplaced, message));
+        }
+
+        let outer_instructions = mem::take(&mut self.instructions);
+        let outer_labels = mem::take(&mut self.pending_labels);
+        // Functions see the constants declared before them at the top level
+        let globals = Scope {
+            variables: self.symbol_table[0]
+                .variables
+                .iter()
+                .filter(|(_, variable)| variable.value.is_some())
+                .map(|(name, variable)| (name.clone(), variable.clone()))
+                .collect(),
+            stack_offset: 0,
+        };
+        let outer_symbols = mem::replace(&mut self.symbol_table, vec![globals]);
+        let outer_offset = self.stack_offset;
+        let outer_frame = self.frame_size;
+        let outer_loops = mem::take(&mut self.loops);
+        let return_label = self.generate_label("ret");
+        self.return_label = Some(return_label.clone());
+
+        // Prologue
+        self.place_label(function_label(name));
+        self.emit(Instruction::new_itype(
+            Opcode::Addi,
+            Reg::StackPointer,
+            Reg::StackPointer,
+            -FRAME_HEADER_SIZE,
+        ));
+        self.emit(Instruction::new_stype(
+            self.target.store_pointer(),
+            Reg::StackPointer,
+            Reg::ReturnAddress,
+            8,
+        ));
+        self.emit(Instruction::new_stype(
+            self.target.store_pointer(),
+            Reg::StackPointer,
+            Reg::Saved(0),
+            0,
+        ));
+        self.mov(Reg::Saved(0), Reg::StackPointer);
+        let frame_index = self.allocate_frame();
+
+        self.enter_scope();
+        for (i, param) in params.iter().enumerate() {
+            self.allocate_variable(param.name.clone(), param.var_type.clone());
+            self.store_variable(param.name.clone(), Reg::Arguments(i as u8))?;
+        }
+        self.generate_block(body)?;
+        if ret_type.is_some() && body.value.is_some() {
+            self.mov(Reg::Arguments(0), Reg::Temp(0));
+        }
+        self.exit_scope();
+        self.patch_frame(frame_index);
+
+        // Epilogue
+        self.place_label(return_label);
+        self.mov(Reg::StackPointer, Reg::Saved(0));
+        self.emit(Instruction::new_itype(
+            self.target.load_pointer(),
+            Reg::Saved(0),
+            Reg::StackPointer,
+            0,
+        ));
+        self.emit(Instruction::new_itype(
+            self.target.load_pointer(),
+            Reg::ReturnAddress,
+            Reg::StackPointer,
+            8,
+        ));
+        self.emit(Instruction::new_itype(
+            Opcode::Addi,
+            Reg::StackPointer,
+            Reg::StackPointer,
+            FRAME_HEADER_SIZE,
+        ));
+        self.emit(Instruction::new_itype(
+            Opcode::Jalr,
+            Reg::Zero,
+            Reg::ReturnAddress,
+            0,
+        ));
+
+        let function = mem::replace(&mut self.instructions, outer_instructions);
+        self.functions.extend(function);
+        self.pending_labels = outer_labels;
+        self.symbol_table = outer_symbols;
+        self.stack_offset = outer_offset;
+        self.frame_size = outer_frame;
+        self.loops = outer_loops;
+        self.return_label = None;
+        Ok(())
+    }
+
+    fn generate_if(
+        &mut self,
+        condition: &Expr,
+        then_branch: &Block,
+        else_branch: &Option<Block>,
+    ) -> Result<(), CompileError> {
+        let else_label = self.generate_label("else");
+        let end_label = self.generate_label("endif");
+
+        self.generate(condition)?;
+        self.emit(Instruction::new_btype(
+            Opcode::Beq,
+            Reg::Temp(0),
+            Reg::Zero,
+            else_label.clone(),
+        ));
+
+        self.generate_block(then_branch)?;
+
+        if let Some(else_branch) = else_branch {
+            self.emit(Instruction::new_jtype(
+                Opcode::Jal,
+                Reg::Zero,
+                end_label.clone(),
+            ));
+            self.place_label(else_label);
+            self.generate_block(else_branch)?;
+            self.place_label(end_label);
+        } else {
+            self.place_label(else_label);
+        }
+        Ok(())
+    }
+
+    /// The right operand is skipped when the left one decides the result,
+    /// which ends up normalized to 0/1 in Temp(0)
+    fn generate_short_circuit(
+        &mut self,
+        left: &Expr,
+        op: &BinaryOp,
+        right: &Expr,
+    ) -> Result<(), CompileError> {
+        let (branch, end_label) = match op {
+            BinaryOp::And => (Opcode::Beq, self.generate_label("endand")),
+            _ => (Opcode::Bne, self.generate_label("endor")),
+        };
+
+        self.generate(left)?;
+        self.emit(Instruction::new_btype(
+            branch,
+            Reg::Temp(0),
+            Reg::Zero,
+            end_label.clone(),
+        ));
+        self.generate(right)?;
+
+        self.place_label(end_label);
+        self.emit(Instruction::new_rtype(
+            Opcode::Sltu,
+            Reg::Temp(0),
+            Reg::Zero,
+            Reg::Temp(0),
+        ));
+        Ok(())
+    }
+
+    /// The matched value stays in Temp(0) while it is dispatched to the arms,
+    /// the value of the taken arm is left in Temp(0)
+    /// An enum is matched on its tag, the arms copy the fields they bind from the frame
+    fn generate_match(&mut self, scrutinee: &Expr, arms: &[MatchArm]) -> Result<(), CompileError> {
+        let scrutinee_type = self.type_of(scrutinee)?;
+        let signed = match scrutinee_type {
+            VarType::Int(int_type) => int_type.is_signed(),
+            _ => false,
+        };
+        let arm_labels: Vec<String> = arms.iter().map(|_| self.generate_label("arm")).collect();
+        let end_label = self.generate_label("endmatch");
+
+        // Values matched by an earlier arm are removed from the later ones
+        let mut cases = Vec::new();
+        let mut default = None;
+        for (arm, patterns) in arms.iter().map(|arm| &arm.patterns).enumerate() {
+            for pattern in patterns {
+                let (low, high) = match pattern {
+                    Pattern::Wildcard => {
+                        default.get_or_insert(arm);
+                        continue;
+                    }
+                    Pattern::Value(value) => {
+                        let value = self.pattern_key(value, signed)?;
+                        (value, value)
+                    }
+                    Pattern::Range(start, end) => (
+                        self.pattern_key(start, signed)?,
+                        self.pattern_key(end, signed)?,
+                    ),
+                    Pattern::Variant {
+                        enum_name, variant, ..
+                    } => {
+                        let tag = self.layouts.variant(enum_name, variant)?.0 as i128;
+                        (tag, tag)
+                    }
+                };
+                if default.is_none() {
+                    add_case(&mut cases, Case { low, high, arm });
+                }
+            }
+        }
+        // Without a '_' the cases cover every value
+        let default_label = match default {
+            Some(arm) => arm_labels[arm].clone(),
+            None => end_label.clone(),
+        };
+
+        let enum_offset = match (&scrutinee_type, &scrutinee.kind) {
+            (VarType::Enum(_), ExprKind::Var(name)) => Some(self.lookup_variable(name)?.offset),
+            (VarType::Enum(_), _) => {
+                let offset = self.reserve_slot(&scrutinee_type);
+                self.build_compound(scrutinee, offset)?;
+                Some(offset)
+            }
+            _ => None,
+        };
+        match enum_offset {
+            Some(offset) => self.load_at(offset, &TAG_TYPE, Reg::Temp(0)),
+            None => self.generate(scrutinee)?,
+        }
+        let count: i128 = cases.iter().map(|case| case.high - case.low + 1).sum();
+        match (cases.first(), cases.last()) {
+            (Some(first), Some(last))
+                if cases.len() >= JUMP_TABLE_MIN_CASES
+                    && last.high - first.low < JUMP_TABLE_MAX_SIZE
+                    && (last.high - first.low + 1) <= 2 * count =>
+            {
+                self.generate_jump_table(&cases, &arm_labels, &default_label);
+            }
+            _ => self.generate_branch_tree(&cases, signed, &arm_labels, &default_label),
+        }
+
+        for (index, (arm, label)) in arms.iter().zip(arm_labels).enumerate() {
+            self.place_label(label);
+            match (enum_offset, arm.patterns.as_slice()) {
+                (
+                    Some(offset),
+                    [Pattern::Variant {
+                        enum_name,
+                        variant,
+                        bindings,
+                    }],
+                ) => {
+                    self.enter_scope();
+                    let fields = self.layouts.variant(enum_name, variant)?.1.fields.clone();
+                    for (binding, (field_offset, var_type)) in bindings.iter().zip(fields) {
+                        if let Some(binding) = binding {
+                            self.bind_field(&binding.name, var_type, offset + field_offset);
+                        }
+                    }
+                    self.generate_block(&arm.body)?;
+                    self.exit_scope();
+                }
+                _ => self.generate_block(&arm.body)?,
+            }
+            if index + 1 < arms.len() {
+                self.emit(Instruction::new_jtype(
+                    Opcode::Jal,
+                    Reg::Zero,
+                    end_label.clone(),
+                ));
+            }
+        }
+        self.place_label(end_label);
+        Ok(())
+    }
+
+    /// Declares a variable holding a copy of the field at `offset`
+    fn bind_field(&mut self, name: &str, var_type: VarType, offset: i32) {
+        let slot = self.reserve_slot(&var_type);
+        if var_type.is_compound() {
+            self.frame_address(offset, Reg::Temp(0));
+            self.store_compound(&var_type, Reg::Temp(0), slot);
+        } else {
+            self.load_at(offset, &var_type, Reg::Temp(0));
+            self.store_at(slot, &var_type, Reg::Temp(0));
+        }
+        self.declare_variable(name.to_string(), var_type, slot);
+    }
+
+    /// Value of a pattern in the order of the matched type
+    fn pattern_key(&self, value: &Expr, signed: bool) -> Result<i128, CompileError> {
+        let value = evaluate(value, self.target, &|name| self.lookup_constant(name))?.value;
+        Ok(if signed {
+            value as i128
+        } else {
+            value as u64 as i128
+        })
+    }
+
+    /// Loads a case bound into Temp(1), zero is compared with the zero register
+    fn case_bound(&mut self, key: i128) -> Reg {
+        if key == 0 {
+            return Reg::Zero;
+        }
+        self.load_int_literal(key as i64, Reg::Temp(1));
+        Reg::Temp(1)
+    }
+
+    /// Entry `value - low` of the table holds the arm label, the index is compared unsigned
+    /// so that values below the first case wrap around and go to the default arm too
+    fn generate_jump_table(&mut self, cases: &[Case], arm_labels: &[String], default: &str) {
+        let (value, index, scratch) = (Reg::Temp(0), Reg::Temp(1), Reg::Temp(2));
+        let low = cases[0].low;
+        let size = cases[cases.len() - 1].high - low + 1;
+        let mut targets = vec![default.to_string(); size as usize];
+        for case in cases {
+            for target in &mut targets[(case.low - low) as usize..=(case.high - low) as usize] {
+                *target = arm_labels[case.arm].clone();
+            }
+        }
+        let table = self.generate_label("jumptable");
+        self.jump_tables.push((table.clone(), targets));
+
+        self.load_int_literal(low as i64, scratch);
+        self.emit(Instruction::new_rtype(Opcode::Sub, index, value, scratch));
+        self.load_int_literal(size as i64, scratch);
+        self.emit(Instruction::new_btype(
+            Opcode::Bgeu,
+            index,
+            scratch,
+            default.to_string(),
+        ));
+        let shift = match self.target {
+            Target::Rv32 => 2,
+            Target::Rv64 => 3,
+        };
+        self.emit(Instruction::new_itype(Opcode::Slli, index, index, shift));
+        self.emit(Instruction::new_la(scratch, table));
+        self.emit(Instruction::new_rtype(Opcode::Add, index, index, scratch));
+        self.emit(Instruction::new_itype(
+            self.target.load_pointer(),
+            index,
+            index,
+            0,
+        ));
+        self.emit(Instruction::new_itype(Opcode::Jalr, Reg::Zero, index, 0));
+    }
+
+    /// Binary search over the sorted cases, the last few are tested one by one
+    fn generate_branch_tree(
+        &mut self,
+        cases: &[Case],
+        signed: bool,
+        arm_labels: &[String],
+        default: &str,
+    ) {
+        let value = Reg::Temp(0);
+        let (less, greater_equal) = if signed {
+            (Opcode::Blt, Opcode::Bge)
+        } else {
+            (Opcode::Bltu, Opcode::Bgeu)
+        };
+        if cases.len() > LINEAR_SEARCH_CASES {
+            let (lower, upper) = cases.split_at(cases.len() / 2);
+            let upper_label = self.generate_label("cases");
+            let bound = self.case_bound(upper[0].low);
+            self.emit(Instruction::new_btype(
+                greater_equal,
+                value,
+                bound,
+                upper_label.clone(),
+            ));
+            self.generate_branch_tree(lower, signed, arm_labels, default);
+            self.place_label(upper_label);
+            self.generate_branch_tree(upper, signed, arm_labels, default);
+            return;
+        }
+
+        for case in cases {
+            let arm = arm_labels[case.arm].clone();
+            if case.low == case.high {
+                let bound = self.case_bound(case.low);
+                self.emit(Instruction::new_btype(Opcode::Beq, value, bound, arm));
+                continue;
+            }
+            let next_label = self.generate_label("case");
+            let bound = self.case_bound(case.low);
+            self.emit(Instruction::new_btype(
+                less,
+                value,
+                bound,
+                next_label.clone(),
+            ));
+            let bound = self.case_bound(case.high);
+            self.emit(Instruction::new_btype(greater_equal, bound, value, arm));
+            self.place_label(next_label);
+        }
+        self.emit(Instruction::new_jtype(
+            Opcode::Jal,
+            Reg::Zero,
+            default.to_string(),
+        ));
+    }
+
+    /// The address of an array element or a field is computed once, it is kept in Temp(2)
+    /// while the new value is calculated
+    fn generate_compound_assign(
+        &mut self,
+        target: &Expr,
+        op: &BinaryOp,
+        expr: &Expr,
+    ) -> Result<(), CompileError> {
+        let var_type = self.type_of(target)?;
+        let (value, operand) = (Reg::Temp(0), Reg::Temp(1));
+        match &target.kind {
+            ExprKind::Var(name) => {
+                self.generate(expr)?;
+                self.load_variable(name.clone(), operand)?;
+                self.generate_binary(op, &var_type, operand, value);
+                self.store_variable(name.clone(), value)?;
+            }
+            ExprKind::Index { .. } | ExprKind::Field { .. } | ExprKind::Deref(_) => {
+                let address = Reg::Temp(2);
+                self.generate(expr)?;
+                self.push(value);
+                self.generate_place_address(target)?;
+                self.pop(operand);
+                self.mov(address, value);
+                self.emit(Instruction::new_itype(
+                    self.target.load_op(&var_type),
+                    value,
+                    address,
+                    0,
+                ));
+                self.generate_binary(op, &var_type, value, operand);
+                self.emit(Instruction::new_stype(
+                    self.target.store_op(&var_type),
+                    address,
+                    value,
+                    0,
+                ));
+            }
+            _ => {
+                let message = "Invalid left-hand side of assignment".to_string();
+                return Err(self.error(ErrorKind::InvalidPlace, message));
+            }
+        }
+        Ok(())
+    }
+
+    /// Temp(0) = lhs op rhs, unsigned types use the unsigned forms of division and comparison
+    fn generate_binary(&mut self, op: &BinaryOp, operand_type: &VarType, lhs: Reg, rhs: Reg) {
+        let dest = Reg::Temp(0);
+        let int_type = match operand_type {
+            VarType::Int(int_type) => *int_type,
+            _ => IntType::I32,
+        };
+        let signed = int_type.is_signed();
+        let less = if signed { Opcode::Slt } else { Opcode::Sltu };
+        match op {
+            BinaryOp::Add => self.emit_int_op(Opcode::Add, int_type, dest, lhs, rhs),
+            BinaryOp::Sub => self.emit_int_op(Opcode::Sub, int_type, dest, lhs, rhs),
+            BinaryOp::Mul => self.emit_int_op(Opcode::Mul, int_type, dest, lhs, rhs),
+            BinaryOp::Div => {
+                let div = if signed { Opcode::Div } else { Opcode::Divu };
+                self.emit_int_op(div, int_type, dest, lhs, rhs);
+            }
+            BinaryOp::Mod => {
+                let rem = if signed { Opcode::Rem } else { Opcode::Remu };
+                self.emit_int_op(rem, int_type, dest, lhs, rhs);
+            }
+            BinaryOp::And | BinaryOp::Or => {
+                unreachable!("Logical operators are lowered with branches")
+            }
+            // Extended operands give an extended result
+            BinaryOp::BitAnd => self.emit(Instruction::new_rtype(Opcode::And, dest, lhs, rhs)),
+            BinaryOp::BitOr => self.emit(Instruction::new_rtype(Opcode::Or, dest, lhs, rhs)),
+            BinaryOp::BitXor => self.emit(Instruction::new_rtype(Opcode::Xor, dest, lhs, rhs)),
+            BinaryOp::Shl => self.emit_int_op(Opcode::Sll, int_type, dest, lhs, rhs),
+            BinaryOp::Shr => {
+                let shift = if signed { Opcode::Sra } else { Opcode::Srl };
+                self.emit_int_op(shift, int_type, dest, lhs, rhs);
+            }
+            BinaryOp::Ushr => {
+                // The sign bits of a narrow signed value must not be shifted in
+                let bits = int_type.bits();
+                let word = bits == 32 && self.target == Target::Rv64;
+                if signed && bits < self.target.xlen() && !word {
+                    self.zero_extend(lhs, bits);
+                }
+                self.emit_int_op(Opcode::Srl, int_type, dest, lhs, rhs);
+            }
+            BinaryOp::Eq => {
+                self.emit(Instruction::new_rtype(Opcode::Sub, dest, lhs, rhs));
+                self.emit(Instruction::new_itype(Opcode::Sltiu, dest, dest, 1));
+            }
+            BinaryOp::Neq => {
+                self.emit(Instruction::new_rtype(Opcode::Sub, dest, lhs, rhs));
+                self.emit(Instruction::new_rtype(Opcode::Sltu, dest, Reg::Zero, dest));
+            }
+            BinaryOp::Lt => self.emit(Instruction::new_rtype(less, dest, lhs, rhs)),
+            BinaryOp::Gt => self.emit(Instruction::new_rtype(less, dest, rhs, lhs)),
+            BinaryOp::Le => {
+                self.emit(Instruction::new_rtype(less, dest, rhs, lhs));
+                self.emit(Instruction::new_itype(Opcode::Xori, dest, dest, 1));
+            }
+            BinaryOp::Ge => {
+                self.emit(Instruction::new_rtype(less, dest, lhs, rhs));
+                self.emit(Instruction::new_itype(Opcode::Xori, dest, dest, 1));
+            }
+        }
+    }
+
+    fn generate(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        if let ExprKind::Block(_) | ExprKind::If { .. } | ExprKind::Match { .. } = expr.kind {
+            let var_type = self.type_of(expr)?;
+            if var_type.is_compound() || matches!(var_type, VarType::Array(..)) {
+                return self.generate_compound_value(&var_type, expr);
+            }
+        }
+        match &expr.kind {
+            // The checker guarantees the literal fits in num, literals of other types are casts
+            ExprKind::Number(n) => {
+                self.load_int_literal(*n as i64, Reg::Temp(0));
+            }
+            ExprKind::Bool(b) => {
                 self.load_bool_literal(*b, Reg::Temp(0));
             }
-            Expr::Var(var) => {
+            ExprKind::Str(_) => {
+                let message = "String literals can only be printed".to_string();
+                return Err(self.error(ErrorKind::Misplaced, message));
+            }
+            ExprKind::Var(var) => {
                 self.load_variable(var.clone(), Reg::Temp(0))?;
             }
-            Expr::Assign { name, expr } => {
-                self.generate(expr)?;
-                self.store_variable(name.clone(), Reg::Temp(0))?;
+            ExprKind::Assign { target, expr } => match &target.kind {
+                ExprKind::Var(name) => {
+                    let variable = self.lookup_variable(name)?;
+                    if let VarType::Array(element, len) = &variable.var_type {
+                        let source = self.push_array(expr)?;
+                        self.store_array(source, variable.offset, element, *len);
+                    } else {
+                        // Structs and enums are copied from the address of the value
+                        self.generate(expr)?;
+                        self.store_variable(name.clone(), Reg::Temp(0))?;
+                    }
+                }
+                ExprKind::Index { .. } | ExprKind::Field { .. } | ExprKind::Deref(_) => {
+                    let var_type = self.type_of(target)?;
+                    self.generate(expr)?;
+                    self.push(Reg::Temp(0));
+                    self.generate_place_address(target)?;
+                    self.pop(Reg::Temp(3));
+                    if var_type.is_compound() {
+                        self.copy_compound(&var_type, Reg::Temp(3), Reg::Temp(0));
+                    } else {
+                        self.emit(Instruction::new_stype(
+                            self.target.store_op(&var_type),
+                            Reg::Temp(0),
+                            Reg::Temp(3),
+                            0,
+                        ));
+                    }
+                }
+                _ => {
+                    let message = "Invalid left-hand side of assignment".to_string();
+                    return Err(self.error(ErrorKind::InvalidPlace, message));
+                }
+            },
+            ExprKind::CompoundAssign { target, op, expr } => {
+                self.generate_compound_assign(target, op, expr)?;
             }
-            Expr::Let {
+            ExprKind::Let {
                 name,
-                var_type, // TODO: Add type safety
+                var_type,
                 expr,
+                ..
             } => {
-                self.allocate_variable(name.clone());
-                self.generate(expr)?;
-                self.store_variable(name.clone(), Reg::Temp(0))?;
+                // The initializer is evaluated before the new variable shadows an old one
+                if let VarType::Array(element, len) = var_type {
+                    let source = self.push_array(expr)?;
+                    let offset = self.allocate_variable(name.clone(), var_type.clone());
+                    self.store_array(source, offset, element, *len);
+                } else if var_type.is_compound() {
+                    let offset = self.reserve_slot(var_type);
+                    self.build_compound(expr, offset)?;
+                    self.declare_variable(name.clone(), var_type.clone(), offset);
+                } else {
+                    self.generate(expr)?;
+                    self.allocate_variable(name.clone(), var_type.clone());
+                    self.store_variable(name.clone(), Reg::Temp(0))?;
+                }
             }
-            Expr::Binary { left, op, right } => {
-                self.generate(left)?;
-
-                self.instructions.push(Instruction::new_itype(
-                    Opcode::Addi,
-                    Reg::Temp(1),
+            ExprKind::Const { name, expr, .. } => {
+                let constant = evaluate(expr, self.target, &|name| self.lookup_constant(name))?;
+                self.define_constant(name.clone(), constant);
+            }
+            ExprKind::Array(_) | ExprKind::ArrayRepeat { .. } => {
+                let var_type = self.type_of(expr)?;
+                self.generate_compound_value(&var_type, expr)?;
+            }
+            ExprKind::Index { array, index } => {
+                let element = self.type_of(expr)?;
+                self.generate_element_address(array, index)?;
+                self.emit(Instruction::new_itype(
+                    self.target.load_op(&element),
+                    Reg::Temp(0),
                     Reg::Temp(0),
                     0,
                 ));
-
+            }
+            ExprKind::Struct { .. } | ExprKind::Enum { .. } => {}
+            // A value built outside of a variable gets a hidden slot, its address is the value
+            ExprKind::StructLiteral { name, .. } => {
+                self.generate_compound_value(&VarType::Struct(name.clone()), expr)?;
+            }
+            ExprKind::Variant { enum_name, .. } => {
+                self.generate_compound_value(&VarType::Enum(enum_name.clone()), expr)?;
+            }
+            ExprKind::Field { base, field } => {
+                let var_type = self.type_of(expr)?;
+                self.generate_field_address(base, field)?;
+                if !var_type.is_compound() {
+                    self.emit(Instruction::new_itype(
+                        self.target.load_op(&var_type),
+                        Reg::Temp(0),
+                        Reg::Temp(0),
+                        0,
+                    ));
+                }
+            }
+            ExprKind::Ref { expr, .. } => self.generate_place_address(expr)?,
+            // Like variables, arrays, structs and enums are referred to by their address
+            ExprKind::Deref(pointer) => {
+                let var_type = self.type_of(expr)?;
+                self.generate(pointer)?;
+                if !var_type.is_compound() && !matches!(var_type, VarType::Array(..)) {
+                    self.emit(Instruction::new_itype(
+                        self.target.load_op(&var_type),
+                        Reg::Temp(0),
+                        Reg::Temp(0),
+                        0,
+                    ));
+                }
+            }
+            ExprKind::Binary {
+                left,
+                op: op @ (BinaryOp::And | BinaryOp::Or),
+                right,
+                ..
+            } => self.generate_short_circuit(left, op, right)?,
+            ExprKind::Binary {
+                left, op, right, ..
+            } => {
+                let operand_type = self.type_of(left)?;
+                self.generate(left)?;
+                self.push(Reg::Temp(0));
                 self.generate(right)?;
-
-                self.instructions.push(Instruction::new_rtype(
-                    match op {
-                        BinaryOp::Add => Opcode::Add,
-                        BinaryOp::Sub => Opcode::Sub,
-                        BinaryOp::Mul => Opcode::Mul,
-                        BinaryOp::Div => Opcode::Div,
-                        BinaryOp::Mod => Opcode::Rem,
-                        BinaryOp::And => Opcode::And,
-                        BinaryOp::Or => Opcode::Or,
-                        BinaryOp::Eq => Opcode::Seq,
-                        BinaryOp::Neq => Opcode::Sne,
-                        BinaryOp::Lt => Opcode::Slt,
-                        BinaryOp::Ge => Opcode::Sge,
-                        BinaryOp::Gt => todo!(),
-                        BinaryOp::Le => todo!(),
-                    },
-                    Reg::Temp(0),
-                    Reg::Temp(1),
-                    Reg::Temp(0),
-                ));
+                self.pop(Reg::Temp(1));
+                self.generate_binary(op, &operand_type, Reg::Temp(1), Reg::Temp(0));
             }
-            Expr::Unary { op, expr } => {
-                // TODO typesafety
-                match op {
-                    UnaryOp::Not => {
-                        self.instructions.push(Instruction::new_itype(
-                            Opcode::Xori,
-                            Reg::Temp(0),
-                            Reg::Temp(0),
-                            1,
-                        ));
+            ExprKind::Unary { op, expr } => {
+                // Negative literals are loaded directly, the magnitude of i32::MIN doesn't fit in num
+                if let (UnaryOp::Neg, ExprKind::Number(n)) = (op, &expr.kind) {
+                    self.load_int_literal(-(*n as i64), Reg::Temp(0));
+                    return Ok(());
+                }
+                let operand_type = self.type_of(expr)?;
+                self.generate(expr)?;
+                let value = Reg::Temp(0);
+                match (op, operand_type) {
+                    (UnaryOp::Neg, VarType::Int(int_type)) => {
+                        self.emit_int_op(Opcode::Sub, int_type, value, Reg::Zero, value);
                     }
-                    UnaryOp::Neg => {
-                        self.instructions.push(Instruction::new_rtype(
-                            Opcode::Sub,
-                            Reg::Temp(0),
-                            Reg::Zero,
-                            Reg::Temp(0),
-                        ));
+                    (UnaryOp::BitNot, VarType::Int(int_type)) => {
+                        self.emit(Instruction::new_itype(Opcode::Xori, value, value, -1));
+                        if !int_type.is_signed() {
+                            self.normalize(value, int_type);
+                        }
+                    }
+                    _ => {
+                        self.emit(Instruction::new_itype(Opcode::Xori, value, value, 1));
                     }
                 }
             }
-            Expr::If {
+            ExprKind::Cast { expr: source, to } => {
+                let VarType::Int(int_type) = to else {
+                    let message = format!("Cannot cast to {}", to);
+                    return Err(self.error(ErrorKind::InvalidType, message));
+                };
+                // Literal-only expressions are computed at compile time
+                if source.is_untyped_int() {
+                    let constant = evaluate(expr, self.target, &|_| None)?;
+                    self.load_int_literal(constant.value, Reg::Temp(0));
+                } else {
+                    self.generate(source)?;
+                    self.normalize(Reg::Temp(0), *int_type);
+                }
+            }
+            ExprKind::If {
                 condition,
                 then_branch,
                 else_branch,
-            } => {
-                self.generate(condition)?;
-                let condition_index = self.instructions.len();
+            } => self.generate_if(condition, then_branch, else_branch)?,
+            ExprKind::Match { scrutinee, arms } => self.generate_match(scrutinee, arms)?,
+            ExprKind::Block(block) => self.generate_block(block)?,
+            ExprKind::While { condition, body } => {
+                let start_label = self.generate_label("while");
+                let end_label = self.generate_label("endwhile");
 
-                self.instructions.push(Instruction::new_itype(
+                self.place_label(start_label.clone());
+                self.generate(condition)?;
+                self.emit(Instruction::new_btype(
                     Opcode::Beq,
                     Reg::Temp(0),
                     Reg::Zero,
-                    0,
+                    end_label.clone(),
                 ));
 
-                for expr in then_branch {
-                    self.generate(expr)?;
+                self.loops.push(LoopContext {
+                    continue_label: start_label.clone(),
+                    break_label: end_label.clone(),
+                    temp_depth: self.temp_depth,
+                });
+                self.generate_block(body)?;
+                self.loops.pop();
+                self.emit(Instruction::new_jtype(Opcode::Jal, Reg::Zero, start_label));
+                self.place_label(end_label);
+            }
+            ExprKind::For {
+                var,
+                start,
+                end,
+                inclusive,
+                step,
+                body,
+                ..
+            } => {
+                let body_label = self.generate_label("for");
+                let next_label = self.generate_label("next");
+                let end_label = self.generate_label("endfor");
+                // Hidden variables, their names can't clash with identifiers
+                let counter = format!("{}.counter", body_label);
+                let limit = format!("{}.end", body_label);
+                let stride = format!("{}.step", body_label);
+                let (value, bound, increment) = (Reg::Temp(0), Reg::Temp(1), Reg::Temp(2));
+
+                self.generate(start)?;
+                self.push(value);
+                self.generate(end)?;
+                self.push(value);
+                match step {
+                    Some(step) => self.generate(step)?,
+                    None => self.load_int_literal(1, value),
                 }
-                if else_branch.is_some() {
-                    self.instructions
-                        .push(Instruction::new_jtype(Opcode::Jal, 0));
+
+                self.enter_scope();
+                self.allocate_variable(stride.clone(), VarType::NUM);
+                self.store_variable(stride.clone(), value)?;
+                self.pop(value);
+                self.allocate_variable(limit.clone(), VarType::NUM);
+                self.store_variable(limit.clone(), value)?;
+                self.pop(value);
+                self.allocate_variable(counter.clone(), VarType::NUM);
+                self.store_variable(counter.clone(), value)?;
+
+                self.load_variable(limit.clone(), bound)?;
+                if *inclusive {
+                    self.emit(Instruction::new_btype(
+                        Opcode::Blt,
+                        bound,
+                        value,
+                        end_label.clone(),
+                    ));
+                } else {
+                    self.emit(Instruction::new_btype(
+                        Opcode::Bge,
+                        value,
+                        bound,
+                        end_label.clone(),
+                    ));
                 }
-                let end_then_index = self.instructions.len();
-                
-                self.instructions[condition_index]
-                    .set_offset(end_then_index as i32 - 1 - (condition_index as i32));
 
-                if let Some(else_branch) = else_branch {
-                    for expr in else_branch {
-                        self.generate(expr)?;
-                    }
-                    let end_else_index = self.instructions.len();
-                    self.instructions[end_then_index - 1]
-                        .set_offset(end_else_index as i32 - (end_then_index as i32));
+                // The checker rejects constant steps that aren't positive, others make the loop empty
+                let runtime_step = step
+                    .as_ref()
+                    .is_some_and(|step| !is_constant(step, &|name| self.is_constant(name)));
+                if runtime_step {
+                    self.load_variable(stride.clone(), increment)?;
+                    self.emit(Instruction::new_btype(
+                        Opcode::Bge,
+                        Reg::Zero,
+                        increment,
+                        end_label.clone(),
+                    ));
                 }
-            }
-            Expr::While { condition, body } => {
-                let label = self.generate_label(String::from("while"));
-                let condition_start = self.instructions.len();
-                self.generate(condition)?;
-                let condition_end = self.instructions.len();
 
-                self.instructions.push(Instruction::new_itype(
-                    Opcode::Beq,
-                    Reg::Temp(0),
-                    Reg::Zero,
-                    0,
-                ));
+                // The body gets a copy of the counter, assigning to it doesn't affect the loop
+                self.place_label(body_label.clone());
+                self.load_variable(counter.clone(), value)?;
+                self.allocate_variable(var.clone(), VarType::NUM);
+                self.store_variable(var.clone(), value)?;
+                self.loops.push(LoopContext {
+                    continue_label: next_label.clone(),
+                    break_label: end_label.clone(),
+                    temp_depth: self.temp_depth,
+                });
+                self.generate_block(body)?;
+                self.loops.pop();
 
-                for expr in body {
-                    self.generate(expr)?;
+                // Compare the distance to the end with the step, so the counter never overflows
+                self.place_label(next_label);
+                self.load_variable(counter.clone(), value)?;
+                self.load_variable(limit, bound)?;
+                self.load_variable(stride, increment)?;
+                self.emit(Instruction::new_rtype(Opcode::Sub, bound, bound, value));
+                if *inclusive {
+                    self.emit(Instruction::new_btype(
+                        Opcode::Bltu,
+                        bound,
+                        increment,
+                        end_label.clone(),
+                    ));
+                } else {
+                    self.emit(Instruction::new_btype(
+                        Opcode::Bgeu,
+                        increment,
+                        bound,
+                        end_label.clone(),
+                    ));
+                }
+                self.emit(Instruction::new_rtype(Opcode::Add, value, value, increment));
+                self.store_variable(counter, value)?;
+                self.emit(Instruction::new_jtype(Opcode::Jal, Reg::Zero, body_label));
+                self.place_label(end_label);
+                self.exit_scope();
+            }
+            ExprKind::Import { name, .. } => {
+                let message = format!(
+                    "Module {} can only be imported at the top level of a file",
+                    name
+                );
+                return Err(self.error(ErrorKind::Misplaced, message));
+            }
+            ExprKind::Error => {
+                let message = "Cannot generate code for a syntax error".to_string();
+                return Err(self.error(ErrorKind::Codegen, message));
+            }
+            ExprKind::Break | ExprKind::Continue => {
+                let Some(context) = self.loops.last() else {
+                    let message = "'break' or 'continue' outside of a loop".to_string();
+                    return Err(self.error(ErrorKind::Misplaced, message));
+                };
+                let label = if matches!(expr.kind, ExprKind::Break) {
+                    context.break_label.clone()
+                } else {
+                    context.continue_label.clone()
+                };
+                // Drop intermediate values pushed inside the loop
+                let extra = self.temp_depth - context.temp_depth;
+                if extra > 0 {
+                    self.emit(Instruction::new_itype(
+                        Opcode::Addi,
+                        Reg::StackPointer,
+                        Reg::StackPointer,
+                        extra,
+                    ));
+                }
+                self.emit(Instruction::new_jtype(Opcode::Jal, Reg::Zero, label));
+            }
+            ExprKind::Function {
+                name,
+                params,
+                ret_type,
+                body,
+            } => {
+                self.generate_function(name, params, ret_type, body)?;
+            }
+            ExprKind::Call { name, args } if name == "print" || name == "println" => {
+                self.generate_print(args, name == "println")?;
+            }
+            ExprKind::Call { name, args } => {
+                for arg in args {
+                    self.generate(arg)?;
+                    self.push(Reg::Temp(0));
+                }
+                for i in (0..args.len()).rev() {
+                    self.pop(Reg::Arguments(i as u8));
                 }
-                let jmp_index = self.instructions.len();
-                self.instructions.push(Instruction::new_jtype(
+                self.emit(Instruction::new_jtype(
                     Opcode::Jal,
-                    (condition_start as i32 - 1 - jmp_index as i32) as u32,
+                    Reg::ReturnAddress,
+                    function_label(name),
                 ));
-                self.instructions[condition_end]
-                    .set_offset(jmp_index as i32 - condition_end as i32);
+                self.mov(Reg::Temp(0), Reg::Arguments(0));
+            }
+            ExprKind::Return(expr) => {
+                let Some(return_label) = self.return_label.clone() else {
+                    let message = "'return' outside of function".to_string();
+                    return Err(self.error(ErrorKind::Misplaced, message));
+                };
+                if let Some(expr) = expr {
+                    self.generate(expr)?;
+                    self.mov(Reg::Arguments(0), Reg::Temp(0));
+                }
+                self.emit(Instruction::new_jtype(Opcode::Jal, Reg::Zero, return_label));
             }
         }
         Ok(())
     }
 }
+
+/// `module::function` isn't a valid assembly symbol, identifiers can't contain '.'
+/// so `module.function` doesn't collide with other functions
+fn function_label(name: &str) -> String {
+    name.replace("::", ".")
+}
+
+/// Adds the part of the case not covered by the earlier ones, keeping the cases sorted
+fn add_case(cases: &mut Vec<Case>, case: Case) {
+    let mut low = case.low;
+    let mut uncovered = Vec::new();
+    for covered in cases.iter().filter(|covered| covered.high >= case.low) {
+        if covered.low > case.high {
+            break;
+        }
+        if covered.low > low {
+            uncovered.push(Case {
+                low,
+                high: covered.low - 1,
+                arm: case.arm,
+            });
+        }
+        low = low.max(covered.high + 1);
+    }
+    if low <= case.high {
+        uncovered.push(Case { low, ..case });
+    }
+    cases.extend(uncovered);
+    cases.sort_by_key(|case| case.low);
+}
+
+fn string_label(index: usize) -> String {
+    format!(".Lstring_{}", index)
+}
+
+/// Escapes a string for the `.string` directive
+fn escape_string(string: &str) -> String {
+    let mut escaped = String::new();
+    for byte in string.bytes() {
+        match byte {
+            b'\\' => escaped.push_str("\\\\"),
+            b'"' => escaped.push_str("\\\""),
+            b'\n' => escaped.push_str("\\n"),
+            b'\t' => escaped.push_str("\\t"),
+            0x20..=0x7e => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    escaped
+}
+
+fn fits_imm12(val: i32) -> bool {
+    (-2048..2048).contains(&val)
+}
+
+/// Instructions loading a constant into dest, on rv32 only its lower 32 bits are used
+fn int_literal(val: i64, dest: Reg, target: Target) -> Vec<Instruction> {
+    if let Ok(val) = i32::try_from(val) {
+        return int_literal_32(val, dest, target);
+    }
+    if target == Target::Rv32 {
+        return int_literal_32(val as i32, dest, target);
+    }
+    // The upper part is built first and shifted in place, addi adds the sign-extended lower 12 bits
+    let lower = (val << 52) >> 52;
+    let mut instructions = int_literal(val.wrapping_sub(lower) >> 12, dest, target);
+    instructions.push(Instruction::new_itype(Opcode::Slli, dest, dest, 12));
+    if lower != 0 {
+        instructions.push(Instruction::new_itype(
+            Opcode::Addi,
+            dest,
+            dest,
+            lower as i32,
+        ));
+    }
+    instructions
+}
+
+/// Instructions loading a 32-bit constant into dest
+fn int_literal_32(val: i32, dest: Reg, target: Target) -> Vec<Instruction> {
+    // addi sign-extends its 12-bit immediate, so lui gets the rounded upper part
+    let lower = (val << 20) >> 20;
+    if lower == val {
+        return vec![Instruction::new_itype(Opcode::Addi, dest, Reg::Zero, val)];
+    }
+    let upper = (val.wrapping_sub(lower) as u32 >> 12) as i32;
+    // On rv64 lui sign-extends to 64 bits, addiw keeps the sum a 32-bit number
+    let add = match target {
+        Target::Rv32 => Opcode::Addi,
+        Target::Rv64 => Opcode::Addiw,
+    };
+    vec![
+        Instruction::new_utype(Opcode::Lui, dest, upper),
+        Instruction::new_itype(add, dest, dest, lower),
+    ]
+}
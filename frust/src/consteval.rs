@@ -0,0 +1,179 @@
+use crate::ast::*;
+use crate::codegen::Target;
+use crate::diagnostic::{CompileError, ErrorKind};
+
+/// Value of a constant expression as it would be held in a register:
+/// integers are extended to 64 bits according to their type, bools are 0 or 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Constant {
+    pub value: i64,
+    pub var_type: VarType,
+}
+
+/// Evaluates a checked constant expression the way the generated code would compute it,
+/// `lookup` gives the values of named constants
+pub fn evaluate(
+    expr: &Expr,
+    target: Target,
+    lookup: &dyn Fn(&str) -> Option<Constant>,
+) -> Result<Constant, CompileError> {
+    Evaluator { target, lookup }.evaluate(expr, IntType::I32)
+}
+
+/// Only literals, constants and operators can be evaluated at compile time
+pub fn is_constant(expr: &Expr, is_named_constant: &dyn Fn(&str) -> bool) -> bool {
+    match &expr.kind {
+        ExprKind::Number(_) | ExprKind::Bool(_) => true,
+        ExprKind::Var(name) => is_named_constant(name),
+        ExprKind::Unary { expr, .. } | ExprKind::Cast { expr, .. } => {
+            is_constant(expr, is_named_constant)
+        }
+        ExprKind::Binary { left, right, .. } => {
+            is_constant(left, is_named_constant) && is_constant(right, is_named_constant)
+        }
+        _ => false,
+    }
+}
+
+struct Evaluator<'a> {
+    target: Target,
+    lookup: &'a dyn Fn(&str) -> Option<Constant>,
+}
+
+impl Evaluator<'_> {
+    /// Literals have type `literal`, it is num unless a literal-only expression is cast as a whole
+    /// Errors point at the expression that can't be evaluated
+    fn evaluate(&self, expr: &Expr, literal: IntType) -> Result<Constant, CompileError> {
+        match &expr.kind {
+            ExprKind::Number(n) => Ok(int(literal.wrap(*n as i64), literal)),
+            ExprKind::Bool(b) => Ok(Constant {
+                value: *b as i64,
+                var_type: VarType::Bool,
+            }),
+            ExprKind::Var(name) => (self.lookup)(name).ok_or_else(|| {
+                let message = format!("{} is not a constant", name);
+                CompileError::new(ErrorKind::NotConstant, message).at(expr.span)
+            }),
+            ExprKind::Cast { expr, to } => {
+                let VarType::Int(int_type) = to else {
+                    let message = format!("Cannot cast to {}", to);
+                    return Err(CompileError::new(ErrorKind::InvalidType, message).at(expr.span));
+                };
+                let literal = if expr.is_untyped_int() {
+                    *int_type
+                } else {
+                    IntType::I32
+                };
+                let value = self.evaluate(expr, literal)?.value;
+                Ok(int(int_type.wrap(value), *int_type))
+            }
+            ExprKind::Unary { op, expr } => {
+                let Constant { value, var_type } = self.evaluate(expr, literal)?;
+                let value = match op {
+                    UnaryOp::Not => value ^ 1,
+                    UnaryOp::Neg => value.wrapping_neg(),
+                    UnaryOp::BitNot => !value,
+                };
+                Ok(Constant {
+                    value: wrap(value, &var_type),
+                    var_type,
+                })
+            }
+            ExprKind::Binary {
+                left,
+                op,
+                op_span,
+                right,
+            } => {
+                let lhs = self.evaluate(left, literal)?;
+                // The shift amount has its own type
+                let right_literal = match op {
+                    BinaryOp::Shl | BinaryOp::Shr | BinaryOp::Ushr => IntType::I32,
+                    _ => literal,
+                };
+                let rhs = self.evaluate(right, right_literal)?;
+                if matches!(op, BinaryOp::Div | BinaryOp::Mod) && rhs.value == 0 {
+                    let message = "Constant division by zero".to_string();
+                    return Err(CompileError::new(ErrorKind::DivisionByZero, message).at(*op_span));
+                }
+                Ok(self.binary(op, lhs, rhs.value))
+            }
+            _ => {
+                let message = "Expression is not constant".to_string();
+                Err(CompileError::new(ErrorKind::NotConstant, message).at(expr.span))
+            }
+        }
+    }
+
+    fn binary(&self, op: &BinaryOp, lhs: Constant, rhs: i64) -> Constant {
+        let Constant {
+            value: lhs,
+            var_type,
+        } = lhs;
+        let int_type = match var_type {
+            VarType::Int(int_type) => int_type,
+            _ => IntType::I32,
+        };
+        let (bits, signed) = (int_type.bits(), int_type.is_signed());
+        // The hardware only looks at the low bits of the shift amount
+        let shift_mask = if bits == 32 || self.target == Target::Rv32 {
+            31
+        } else {
+            63
+        };
+        let shift = (rhs & shift_mask) as u32;
+        let unsigned = |value: i64| value as u64 & (u64::MAX >> (64 - bits));
+        let less = if signed {
+            lhs < rhs
+        } else {
+            unsigned(lhs) < unsigned(rhs)
+        };
+        let value = match op {
+            BinaryOp::Add => lhs.wrapping_add(rhs),
+            BinaryOp::Sub => lhs.wrapping_sub(rhs),
+            BinaryOp::Mul => lhs.wrapping_mul(rhs),
+            BinaryOp::Div if signed => lhs.wrapping_div(rhs),
+            BinaryOp::Mod if signed => lhs.wrapping_rem(rhs),
+            BinaryOp::Div => (unsigned(lhs) / unsigned(rhs)) as i64,
+            BinaryOp::Mod => (unsigned(lhs) % unsigned(rhs)) as i64,
+            BinaryOp::BitAnd | BinaryOp::And => lhs & rhs,
+            BinaryOp::BitOr | BinaryOp::Or => lhs | rhs,
+            BinaryOp::BitXor => lhs ^ rhs,
+            BinaryOp::Shl => lhs.wrapping_shl(shift),
+            BinaryOp::Shr if signed => lhs >> shift,
+            BinaryOp::Shr => (lhs as u64 >> shift) as i64,
+            BinaryOp::Ushr => (unsigned(lhs) >> shift) as i64,
+            BinaryOp::Eq => return boolean(lhs == rhs),
+            BinaryOp::Neq => return boolean(lhs != rhs),
+            BinaryOp::Lt => return boolean(less),
+            BinaryOp::Ge => return boolean(!less),
+            BinaryOp::Gt => return boolean(!less && lhs != rhs),
+            BinaryOp::Le => return boolean(less || lhs == rhs),
+        };
+        Constant {
+            value: wrap(value, &var_type),
+            var_type,
+        }
+    }
+}
+
+fn int(value: i64, int_type: IntType) -> Constant {
+    Constant {
+        value,
+        var_type: VarType::Int(int_type),
+    }
+}
+
+fn boolean(value: bool) -> Constant {
+    Constant {
+        value: value as i64,
+        var_type: VarType::Bool,
+    }
+}
+
+fn wrap(value: i64, var_type: &VarType) -> i64 {
+    match var_type {
+        VarType::Int(int_type) => int_type.wrap(value),
+        _ => value,
+    }
+}
@@ -0,0 +1,257 @@
+use crate::ast::Span;
+use crate::lint::Lint;
+use crate::preprocessor::Source;
+use std::fmt;
+
+/// What went wrong, every kind has its own code. The first digits of the code
+/// are the compilation stage the error comes from
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ErrorKind {
+    Usage,
+    Io,
+    InvalidMacro,
+    UnknownDirective,
+    UnbalancedConditional,
+    MacroArguments,
+    InvalidNumber,
+    InvalidString,
+    UnknownLexem,
+    UnterminatedComment,
+    Syntax,
+    UnexpectedEof,
+    InvalidPlace,
+    Module,
+    MismatchedTypes,
+    UnknownName,
+    ImmutableAssign,
+    NonExhaustiveMatch,
+    DuplicateDefinition,
+    ArgumentCount,
+    DanglingReference,
+    OutOfScope,
+    LiteralOutOfRange,
+    InvalidOperand,
+    InvalidType,
+    Misplaced,
+    ReservedName,
+    InvalidPattern,
+    NotConstant,
+    IndexOutOfBounds,
+    InvalidStep,
+    MissingField,
+    DivisionByZero,
+    UnsupportedType,
+    EmptyRange,
+    TooManyArguments,
+    Codegen,
+    Lint(Lint),
+}
+
+impl ErrorKind {
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorKind::Usage => "E0001",
+            ErrorKind::Io => "E0002",
+            ErrorKind::InvalidMacro => "E0100",
+            ErrorKind::UnknownDirective => "E0101",
+            ErrorKind::UnbalancedConditional => "E0102",
+            ErrorKind::MacroArguments => "E0103",
+            ErrorKind::InvalidNumber => "E0200",
+            ErrorKind::InvalidString => "E0201",
+            ErrorKind::UnknownLexem => "E0202",
+            ErrorKind::UnterminatedComment => "E0203",
+            ErrorKind::Syntax => "E0300",
+            ErrorKind::UnexpectedEof => "E0301",
+            ErrorKind::InvalidPlace => "E0302",
+            ErrorKind::Module => "E0400",
+            ErrorKind::MismatchedTypes => "E0500",
+            ErrorKind::UnknownName => "E0501",
+            ErrorKind::ImmutableAssign => "E0502",
+            ErrorKind::NonExhaustiveMatch => "E0503",
+            ErrorKind::DuplicateDefinition => "E0504",
+            ErrorKind::ArgumentCount => "E0505",
+            ErrorKind::DanglingReference => "E0506",
+            ErrorKind::OutOfScope => "E0507",
+            ErrorKind::LiteralOutOfRange => "E0508",
+            ErrorKind::InvalidOperand => "E0509",
+            ErrorKind::InvalidType => "E0510",
+            ErrorKind::Misplaced => "E0511",
+            ErrorKind::ReservedName => "E0512",
+            ErrorKind::InvalidPattern => "E0513",
+            ErrorKind::NotConstant => "E0514",
+            ErrorKind::IndexOutOfBounds => "E0515",
+            ErrorKind::InvalidStep => "E0516",
+            ErrorKind::MissingField => "E0517",
+            ErrorKind::DivisionByZero => "E0518",
+            ErrorKind::UnsupportedType => "E0600",
+            ErrorKind::EmptyRange => "E0601",
+            ErrorKind::TooManyArguments => "E0602",
+            ErrorKind::Codegen => "E0603",
+            ErrorKind::Lint(lint) => lint.code(),
+        }
+    }
+}
+
+/// Only lints produce warnings
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Error with the source it refers to, notes and a hint on how to fix it.
+/// Errors found before the code is lexed have no span
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompileError {
+    pub kind: ErrorKind,
+    pub severity: Severity,
+    pub span: Option<Span>,
+    pub message: String,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+}
+
+impl CompileError {
+    pub fn new(kind: ErrorKind, message: String) -> Self {
+        CompileError {
+            kind,
+            severity: Severity::Error,
+            span: None,
+            message,
+            notes: vec![],
+            help: None,
+        }
+    }
+
+    pub fn at(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    pub fn help(mut self, help: String) -> Self {
+        self.help = Some(help);
+        self
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+}
+
+/// Passes that report several errors at once take the single errors of the others
+impl From<CompileError> for Vec<CompileError> {
+    fn from(error: CompileError) -> Self {
+        vec![error]
+    }
+}
+
+/// Only the header, the source is shown by `SourceMap::render`
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}[{}]: {}", severity, self.code(), self.message)
+    }
+}
+
+struct SourceFile {
+    name: String,
+    source: Source,
+    start: usize, // offset of the first byte in the source map
+}
+
+/// Preprocessed files of a program, placed one after another so that
+/// an offset identifies both the file and the position in it
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap { files: vec![] }
+    }
+
+    /// Returns the offset the spans of the file are shifted by
+    pub fn add(&mut self, name: String, source: Source) -> usize {
+        // One byte between files, so the end of a file isn't the start of the next one
+        let start = self
+            .files
+            .last()
+            .map_or(0, |file| file.start + file.source.code.len() + 1);
+        self.files.push(SourceFile {
+            name,
+            source,
+            start,
+        });
+        start
+    }
+
+    /// Rendered like rustc does: the message, `file:line:column`,
+    /// the source line with a caret under the span, then the notes and help
+    pub fn render(&self, error: &CompileError) -> String {
+        let mut lines = vec![error.to_string()];
+        let mut gutter = " ".to_string();
+        if let Some((name, line, column, text, width)) = self.locate(error.span) {
+            gutter = " ".repeat(line.to_string().len());
+            // Tabs are expanded, so the caret lines up whatever the tab width of the terminal
+            let before: String = text.chars().take(column - 1).collect();
+            let spanned: String = text.chars().skip(column - 1).take(width).collect();
+            let width = expand_tabs(&spanned).chars().count().max(1);
+            lines.extend([
+                format!("{}--> {}:{}:{}", gutter, name, line, column),
+                format!("{} |", gutter),
+                format!("{} | {}", line, expand_tabs(text)),
+                format!(
+                    "{} | {}{}",
+                    gutter,
+                    " ".repeat(expand_tabs(&before).chars().count()),
+                    "^".repeat(width)
+                ),
+            ]);
+        }
+        for note in &error.notes {
+            lines.push(format!("{} = note: {}", gutter, note));
+        }
+        if let Some(help) = &error.help {
+            lines.push(format!("{} = help: {}", gutter, help));
+        }
+        lines.join("\n")
+    }
+
+    /// Preprocessed text a span covers
+    pub fn snippet(&self, span: Span) -> Option<&str> {
+        let file = self
+            .files
+            .iter()
+            .rev()
+            .find(|file| file.start <= span.start)?;
+        file.source
+            .code
+            .get(span.start - file.start..span.end - file.start)
+    }
+
+    /// File name, line, column, text of the line and width of the caret
+    fn locate(&self, span: Option<Span>) -> Option<(&str, usize, usize, &str, usize)> {
+        let span = span?;
+        let file = self
+            .files
+            .iter()
+            .rev()
+            .find(|file| file.start <= span.start)?;
+        let (line, column, text, width) = file
+            .source
+            .locate(span.start - file.start, span.end - file.start);
+        Some((&file.name, line, column, text, width.max(1)))
+    }
+}
+
+fn expand_tabs(text: &str) -> String {
+    text.replace('\t', "    ")
+}
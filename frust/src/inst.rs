@@ -1,70 +1,132 @@
-use std::fmt::format;
+use std::fmt;
 
+#[allow(dead_code)] // Not every opcode of the ISA is emitted by codegen yet
 #[derive(Debug, Clone, Copy)]
 pub enum Opcode {
     Add,
     Sub,
     Mul,
     Div,
+    Divu,
     Rem,
+    Remu,
+    Addw,
+    Subw,
+    Mulw,
+    Divw,
+    Divuw,
+    Remw,
+    Remuw,
     And,
     Xor,
     Sll,
     Srl,
     Sra,
+    Sllw,
+    Srlw,
+    Sraw,
     Or,
     Slt,
-    Seq,
-    Sne,
-    Sge,
+    Sltu,
     Beq,
     Bne,
     Blt,
     Bge,
+    Bltu,
+    Bgeu,
+    Lb,
+    Lbu,
+    Lh,
+    Lhu,
     Lw,
+    Lwu,
+    Ld,
+    Sb,
+    Sh,
     Sw,
+    Sd,
     Lui,
     Addi,
+    Addiw,
+    Andi,
     Xori,
+    Sltiu,
+    Slli,
+    Srli,
+    Srai,
     Jal,
     Jalr,
+    La,
+    Ecall,
+    Ebreak,
 }
 
-impl ToString for Opcode {
-    fn to_string(&self) -> String {
-        match self {
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
             Opcode::Add => "add",
             Opcode::Sub => "sub",
             Opcode::Mul => "mul",
             Opcode::Div => "div",
+            Opcode::Divu => "divu",
             Opcode::Rem => "rem",
+            Opcode::Remu => "remu",
+            Opcode::Addw => "addw",
+            Opcode::Subw => "subw",
+            Opcode::Mulw => "mulw",
+            Opcode::Divw => "divw",
+            Opcode::Divuw => "divuw",
+            Opcode::Remw => "remw",
+            Opcode::Remuw => "remuw",
             Opcode::And => "and",
             Opcode::Or => "or",
             Opcode::Xor => "xor",
             Opcode::Sll => "sll",
             Opcode::Srl => "srl",
             Opcode::Sra => "sra",
+            Opcode::Sllw => "sllw",
+            Opcode::Srlw => "srlw",
+            Opcode::Sraw => "sraw",
             Opcode::Slt => "slt",
-            Opcode::Seq => "seq",
-            Opcode::Sne => "sne",
-            Opcode::Sge => "sge",
+            Opcode::Sltu => "sltu",
             Opcode::Beq => "beq",
             Opcode::Bne => "bne",
             Opcode::Blt => "blt",
             Opcode::Bge => "bge",
+            Opcode::Bltu => "bltu",
+            Opcode::Bgeu => "bgeu",
+            Opcode::Lb => "lb",
+            Opcode::Lbu => "lbu",
+            Opcode::Lh => "lh",
+            Opcode::Lhu => "lhu",
             Opcode::Lw => "lw",
+            Opcode::Lwu => "lwu",
+            Opcode::Ld => "ld",
+            Opcode::Sb => "sb",
+            Opcode::Sh => "sh",
             Opcode::Sw => "sw",
+            Opcode::Sd => "sd",
             Opcode::Lui => "lui",
             Opcode::Addi => "addi",
+            Opcode::Addiw => "addiw",
+            Opcode::Andi => "andi",
             Opcode::Xori => "xori",
+            Opcode::Sltiu => "sltiu",
+            Opcode::Slli => "slli",
+            Opcode::Srli => "srli",
+            Opcode::Srai => "srai",
             Opcode::Jal => "jal",
             Opcode::Jalr => "jalr",
-        }
-        .to_string()
+            Opcode::La => "la",
+            Opcode::Ecall => "ecall",
+            Opcode::Ebreak => "ebreak",
+        };
+        write!(f, "{}", name)
     }
 }
 
 // https://en.wikipedia.org/wiki/RISC-V
+#[allow(dead_code)]
 #[derive(Debug, Clone, Copy)]
 pub enum Reg {
     Zero,          // x0
@@ -72,37 +134,35 @@ pub enum Reg {
     StackPointer,  //x2
     GlobalPointer, //x3
     ThreadPointer, //x4
-    Temp(u8),      // x6-7 x28-31
-    Saved(u8),     // x8-9 x18-27
-    Arguments(u8), // x10-11 return val, x12-17 args
+    Temp(u8),      // x5-7 x28-31
+    Saved(u8),     // x8-9 x18-27, x8 is the frame pointer
+    Arguments(u8), // x10-11 return val, x10-17 args
 }
 
-impl ToString for Reg {
-    fn to_string(&self) -> String {
-        match self {
-            Reg::Zero => "x0",
-            Reg::ReturnAddress => "x1",
-            Reg::StackPointer => "x2",
-            Reg::GlobalPointer => "x3",
-            Reg::ThreadPointer => "x4",
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let index = match self {
+            Reg::Zero => 0,
+            Reg::ReturnAddress => 1,
+            Reg::StackPointer => 2,
+            Reg::GlobalPointer => 3,
+            Reg::ThreadPointer => 4,
             Reg::Temp(id) => match id {
-                0 => "x5",
-                1 => "x6",
-                2 => "x7",
-                3 => "x28",
-                4 => "x29",
-                5 => "x30",
-                6 => "x31",
+                0..=2 => 5 + id,
+                3..=6 => 25 + id,
                 _ => panic!("Wrong temp reg {}", id),
             },
             Reg::Saved(id) => match id {
-                0 => "x8",
-                1 => "x9",
-                _ => todo!("Not implemetned"),
+                0..=1 => 8 + id,
+                2..=11 => 16 + id,
+                _ => panic!("Wrong saved reg {}", id),
             },
-            Reg::Arguments(id) => todo!("Not implemented!"),
-        }
-        .to_string()
+            Reg::Arguments(id) => match id {
+                0..=7 => 10 + id,
+                _ => panic!("Wrong argument reg {}", id),
+            },
+        };
+        write!(f, "x{}", index)
     }
 }
 
@@ -119,148 +179,190 @@ pub enum Type {
 #[derive(Debug, Clone)]
 pub struct Instruction {
     notation: Type,
-    label: Option<String>,
+    labels: Vec<String>,
     opcode: Opcode,
     rd: Option<Reg>,
     rs1: Option<Reg>,
     rs2: Option<Reg>,
-    imm: Option<u32>,
+    imm: Option<i32>,
+    target: Option<String>,
 }
 
 impl Instruction {
+    fn new(notation: Type, opcode: Opcode) -> Self {
+        Instruction {
+            notation,
+            labels: vec![],
+            opcode,
+            rd: None,
+            rs1: None,
+            rs2: None,
+            imm: None,
+            target: None,
+        }
+    }
+
     /// R-Type: opcode rd, rs1, rs2
     pub fn new_rtype(opcode: Opcode, rd: Reg, rs1: Reg, rs2: Reg) -> Self {
         Instruction {
-            notation: Type::R,
-            label: None,
-            opcode,
             rd: Some(rd),
             rs1: Some(rs1),
             rs2: Some(rs2),
-            imm: None,
+            ..Instruction::new(Type::R, opcode)
         }
     }
 
     /// I-Type: opcode rd, rs1, imm
-    pub fn new_itype(opcode: Opcode, rd: Reg, rs1: Reg, imm: u32) -> Self {
+    ///
+    /// Loads and `jalr` are printed as `opcode rd, imm(rs1)`
+    pub fn new_itype(opcode: Opcode, rd: Reg, rs1: Reg, imm: i32) -> Self {
         Instruction {
-            notation: Type::I,
-            label: None,
-            opcode,
             rd: Some(rd),
             rs1: Some(rs1),
-            rs2: None,
             imm: Some(imm),
+            ..Instruction::new(Type::I, opcode)
         }
     }
 
-    /// S-Type: opcode rs1, rs2, imm
-    pub fn new_stype(opcode: Opcode, rs1: Reg, rs2: Reg, imm: u32) -> Self {
+    /// S-Type: opcode rs2, imm(rs1)
+    pub fn new_stype(opcode: Opcode, rs1: Reg, rs2: Reg, imm: i32) -> Self {
         Instruction {
-            notation: Type::S,
-            label: None,
-            opcode,
-            rd: None,
             rs1: Some(rs1),
             rs2: Some(rs2),
             imm: Some(imm),
+            ..Instruction::new(Type::S, opcode)
         }
     }
 
     /// U-Type: opcode rd, imm
-    pub fn new_utype(opcode: Opcode, rd: Reg, imm: u32) -> Self {
+    pub fn new_utype(opcode: Opcode, rd: Reg, imm: i32) -> Self {
         Instruction {
-            notation: Type::U,
-            label: None,
-            opcode,
             rd: Some(rd),
-            rs1: None,
-            rs2: None,
             imm: Some(imm),
+            ..Instruction::new(Type::U, opcode)
         }
     }
 
-    /// B-Type: opcode rs1, rs2, label(imm)
-    pub fn new_btype(opcode: Opcode, rs1: Reg, rs2: Reg, imm: u32) -> Self {
+    /// B-Type: opcode rs1, rs2, label
+    pub fn new_btype(opcode: Opcode, rs1: Reg, rs2: Reg, target: String) -> Self {
         Instruction {
-            notation: Type::B,
-            label: None,
-            opcode,
-            rd: None,
             rs1: Some(rs1),
             rs2: Some(rs2),
-            imm: Some(imm),
+            target: Some(target),
+            ..Instruction::new(Type::B, opcode)
         }
     }
 
-    /// J-Type: opcode label(imm)
-    pub fn new_jtype(opcode: Opcode, imm: u32) -> Self {
+    /// J-Type: opcode rd, label
+    pub fn new_jtype(opcode: Opcode, rd: Reg, target: String) -> Self {
         Instruction {
-            notation: Type::J,
-            label: None,
-            opcode,
-            rd: Some(Reg::Zero),
-            rs1: None,
-            rs2: None,
-            imm: Some(imm),
+            rd: Some(rd),
+            target: Some(target),
+            ..Instruction::new(Type::J, opcode)
+        }
+    }
+
+    /// Load address pseudo-instruction: la rd, label
+    pub fn new_la(rd: Reg, target: String) -> Self {
+        Instruction {
+            rd: Some(rd),
+            target: Some(target),
+            ..Instruction::new(Type::U, Opcode::La)
         }
     }
 
-    pub fn set_label(&mut self, label: String) {
-        self.label = Some(label);
+    /// Environment call, arguments are passed in the argument registers
+    pub fn new_ecall() -> Self {
+        Instruction::new(Type::I, Opcode::Ecall)
+    }
+
+    /// Breakpoint trap
+    pub fn new_ebreak() -> Self {
+        Instruction::new(Type::I, Opcode::Ebreak)
+    }
+
+    pub fn add_label(&mut self, label: String) {
+        self.labels.push(label);
     }
 
     pub fn set_offset(&mut self, offset: i32) {
-        self.imm = Some(offset as u32);
+        self.imm = Some(offset);
     }
 }
 
-impl ToString for Instruction {
-    fn to_string(&self) -> String {
-        let mut parts = if let Some(label) = self.label.clone() {
-            vec![label, String::from(": \n")]
-        } else {
-            vec![]
-        };
-        parts.push(match self.notation {
-            Type::R => format!(
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for label in &self.labels {
+            writeln!(f, "{}:", label)?;
+        }
+        match self.notation {
+            Type::R => write!(
+                f,
                 "{} {}, {}, {}",
-                self.opcode.to_string(),
-                self.rd.unwrap().to_string(),
-                self.rs1.unwrap().to_string(),
-                self.rs2.unwrap().to_string()
+                self.opcode,
+                self.rd.unwrap(),
+                self.rs1.unwrap(),
+                self.rs2.unwrap()
             ),
             Type::I => match self.opcode {
-                Opcode::Lui => format!(
-                    "{} {}, {}",
-                    self.opcode.to_string(),
-                    self.rd.unwrap().to_string(),
-                    self.imm.unwrap().to_string()
+                Opcode::Ecall | Opcode::Ebreak => write!(f, "{}", self.opcode),
+                Opcode::Lb
+                | Opcode::Lbu
+                | Opcode::Lh
+                | Opcode::Lhu
+                | Opcode::Lw
+                | Opcode::Lwu
+                | Opcode::Ld
+                | Opcode::Jalr => write!(
+                    f,
+                    "{} {}, {}({})",
+                    self.opcode,
+                    self.rd.unwrap(),
+                    self.imm.unwrap(),
+                    self.rs1.unwrap()
                 ),
-                _ => format!(
+                _ => write!(
+                    f,
                     "{} {}, {}, {}",
-                    self.opcode.to_string(),
-                    self.rd.unwrap().to_string(),
-                    self.rs1.unwrap().to_string(),
-                    self.imm.unwrap().to_string()
+                    self.opcode,
+                    self.rd.unwrap(),
+                    self.rs1.unwrap(),
+                    self.imm.unwrap()
+                ),
+            },
+            Type::S => write!(
+                f,
+                "{} {}, {}({})",
+                self.opcode,
+                self.rs2.unwrap(),
+                self.imm.unwrap(),
+                self.rs1.unwrap()
+            ),
+            Type::U => match &self.target {
+                Some(target) => write!(f, "{} {}, {}", self.opcode, self.rd.unwrap(), target),
+                None => write!(
+                    f,
+                    "{} {}, {}",
+                    self.opcode,
+                    self.rd.unwrap(),
+                    self.imm.unwrap()
                 ),
             },
-            Type::S => format!(
+            Type::B => write!(
+                f,
                 "{} {}, {}, {}",
-                self.opcode.to_string(),
-                self.rs1.unwrap().to_string(),
-                self.imm.unwrap().to_string(),
-                self.rs2.unwrap().to_string()
+                self.opcode,
+                self.rs1.unwrap(),
+                self.rs2.unwrap(),
+                self.target.as_ref().unwrap()
             ),
-            Type::U => todo!(),
-            Type::B => todo!(),
-            Type::J => format!(
+            Type::J => write!(
+                f,
                 "{} {}, {}",
-                self.opcode.to_string(),
-                self.rd.unwrap().to_string(),
-                (self.imm.unwrap() as i32).to_string()
+                self.opcode,
+                self.rd.unwrap(),
+                self.target.as_ref().unwrap()
             ),
-        });
-        parts.join("")
+        }
     }
 }
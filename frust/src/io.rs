@@ -1,23 +1,125 @@
+use crate::codegen::Target;
+use crate::diagnostic::{CompileError, ErrorKind};
+use crate::lint::{Level, LintLevels};
 use std::{
     fs::{self, File},
-    io::{IoSlice, LineWriter, Write},
+    io::Write,
 };
 
-pub fn parse_args(args: Vec<String>) -> Result<(String, String), String> {
-    match args.len() {
-        3 => Ok((args[1].clone(), args[2].clone())),
-        2 => Ok((args[1].clone(), "a.S".to_string())),
-        _ => Err("Wrong number of arguments: \n Usage: frustc input.fr output.S".to_string()),
+pub struct Options {
+    pub input: String,
+    pub output: String,
+    pub bounds_check: bool,
+    pub target: Target,
+    pub defines: Vec<(String, String)>,
+    pub lints: LintLevels,
+}
+
+const USAGE: &str = "Usage: frustc [--bounds-check] [--target=rv32|rv64] \
+                     [--define|-D NAME[=VALUE]]... [-W|-A|-D LINT]... input.fr [output.S]";
+
+/// Errors about the arguments carry the usage as help.
+/// `-D` denies a lint when given `warnings` or a lint name, otherwise it defines a macro
+/// like `--define`
+pub fn parse_args(args: Vec<String>) -> Result<Options, CompileError> {
+    let mut files = Vec::new();
+    let mut bounds_check = false;
+    let mut target = Target::Rv32;
+    let mut defines = Vec::new();
+    let mut lints = LintLevels::new();
+    let mut args = args.into_iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bounds-check" => bounds_check = true,
+            "--target=rv32" => target = Target::Rv32,
+            "--target=rv64" => target = Target::Rv64,
+            "--define" => match args.next() {
+                Some(define) => defines.push(parse_define(&define)),
+                None => {
+                    let message = "Expected NAME[=VALUE] after --define".to_string();
+                    return Err(usage_error(message));
+                }
+            },
+            flag if flag.starts_with("--define=") => {
+                defines.push(parse_define(&flag["--define=".len()..]))
+            }
+            flag if flag.len() >= 2 && ["-D", "-W", "-A"].contains(&&flag[..2]) => {
+                let value = match &flag[2..] {
+                    "" => match args.next() {
+                        Some(value) => value,
+                        None => {
+                            let expected = if flag == "-D" {
+                                "lint name or NAME[=VALUE]"
+                            } else {
+                                "lint name"
+                            };
+                            let message = format!("Expected {} after {}", expected, flag);
+                            return Err(usage_error(message));
+                        }
+                    },
+                    value => value.to_string(),
+                };
+                let level = match &flag[..2] {
+                    "-D" if !LintLevels::is_lint(&value) => {
+                        defines.push(parse_define(&value));
+                        continue;
+                    }
+                    "-D" => Level::Deny,
+                    "-W" => Level::Warn,
+                    _ => Level::Allow,
+                };
+                lints
+                    .set(&value, level)
+                    .map_err(|err| err.help(USAGE.to_string()))?;
+            }
+            flag if flag.starts_with('-') => {
+                return Err(usage_error(format!("Unknown option {}", flag)))
+            }
+            _ => files.push(arg),
+        }
+    }
+
+    let mut files = files.into_iter();
+    match (files.next(), files.next(), files.next()) {
+        (Some(input), output, None) => Ok(Options {
+            input,
+            output: output.unwrap_or("a.S".to_string()),
+            bounds_check,
+            target,
+            defines,
+            lints,
+        }),
+        _ => Err(usage_error("Wrong number of arguments".to_string())),
+    }
+}
+
+fn usage_error(message: String) -> CompileError {
+    CompileError::new(ErrorKind::Usage, message).help(USAGE.to_string())
+}
+
+/// `--define NAME` and `-D NAME` define the macro as 1
+fn parse_define(define: &str) -> (String, String) {
+    match define.split_once('=') {
+        Some((name, value)) => (name.to_string(), value.to_string()),
+        None => (define.to_string(), "1".to_string()),
     }
 }
 
-pub fn read_file(filename: String) -> Result<String, String> {
-    fs::read_to_string(filename).map_err(|err| err.to_string())
+pub fn read_file(filename: String) -> Result<String, CompileError> {
+    fs::read_to_string(&filename).map_err(|err| io_error("read", &filename, err))
 }
-pub fn write_line_file<T: ToString>(filename: String, data: &[T]) -> Result<(), String> {
-    let mut file = File::create(filename).map_err(|err| err.to_string())?;
+pub fn write_line_file<T: ToString>(filename: String, data: &[T]) -> Result<(), CompileError> {
+    let mut file = File::create(&filename).map_err(|err| io_error("create", &filename, err))?;
     for instruction in data {
-        writeln!(file, "{}", instruction.to_string()).map_err(|err| err.to_string())?;
+        writeln!(file, "{}", instruction.to_string())
+            .map_err(|err| io_error("write", &filename, err))?;
     }
     Ok(())
 }
+
+fn io_error(action: &str, filename: &str, err: std::io::Error) -> CompileError {
+    CompileError::new(
+        ErrorKind::Io,
+        format!("Cannot {} {}: {}", action, filename, err),
+    )
+}
@@ -0,0 +1,250 @@
+use crate::ast::*;
+use crate::diagnostic::{CompileError, ErrorKind};
+use std::collections::HashMap;
+
+/// Size of a bool, it takes a word in arrays and structs like on the stack
+pub const BOOL_SIZE: i32 = 4;
+/// Type of the variant index stored at the start of an enum value
+pub const TAG_TYPE: VarType = VarType::Int(IntType::U32);
+
+pub struct FieldLayout {
+    pub name: String,
+    pub var_type: VarType,
+    pub offset: i32,
+}
+
+pub struct StructLayout {
+    pub fields: Vec<FieldLayout>,
+    pub size: i32,
+    pub align: i32,
+}
+
+/// The fields of a variant are placed after the tag, variants share the same memory
+pub struct EnumLayout {
+    pub variants: Vec<VariantLayout>,
+    pub size: i32,
+    pub align: i32,
+}
+
+pub struct VariantLayout {
+    pub name: String,
+    pub fields: Vec<(i32, VarType)>, // offset, type
+}
+
+enum Declaration<'a> {
+    Struct(&'a [Param]),
+    Enum(&'a [EnumVariant]),
+}
+
+/// Memory layout of the types of a program: fields are placed in declaration order,
+/// each at an offset aligned to its type, and a struct is padded to its alignment
+pub struct TypeLayouts {
+    structs: HashMap<String, StructLayout>,
+    enums: HashMap<String, EnumLayout>,
+    pointer_size: i32, // references are as wide as a register
+}
+
+impl TypeLayouts {
+    pub fn new(pointer_size: i32) -> Self {
+        TypeLayouts {
+            structs: HashMap::new(),
+            enums: HashMap::new(),
+            pointer_size,
+        }
+    }
+
+    /// Lays out the structs and enums declared in a checked program
+    pub fn from_program(program: &[Stmt], pointer_size: i32) -> Result<Self, CompileError> {
+        let declarations: HashMap<&str, Declaration> = program
+            .iter()
+            .filter_map(|stmt| match &stmt.expr.kind {
+                ExprKind::Struct { name, fields } => {
+                    Some((name.as_str(), Declaration::Struct(fields)))
+                }
+                ExprKind::Enum { name, variants } => {
+                    Some((name.as_str(), Declaration::Enum(variants)))
+                }
+                _ => None,
+            })
+            .collect();
+        let mut layouts = TypeLayouts::new(pointer_size);
+        for name in declarations.keys() {
+            layouts.add_type(name, &declarations, &mut vec![])?;
+        }
+        Ok(layouts)
+    }
+
+    /// Types used by the fields are laid out first
+    fn add_type(
+        &mut self,
+        name: &str,
+        declarations: &HashMap<&str, Declaration>,
+        visiting: &mut Vec<String>,
+    ) -> Result<(), CompileError> {
+        if self.structs.contains_key(name) || self.enums.contains_key(name) {
+            return Ok(());
+        }
+        if visiting.iter().any(|visited| visited == name) {
+            let message = format!("Type {} contains itself", name);
+            return Err(CompileError::new(ErrorKind::InvalidType, message));
+        }
+        let declaration = declarations.get(name).ok_or_else(|| {
+            CompileError::new(ErrorKind::UnknownName, format!("Unknown type {}", name))
+        })?;
+
+        visiting.push(name.to_string());
+        let field_types: Vec<&VarType> = match declaration {
+            Declaration::Struct(fields) => fields.iter().map(|field| &field.var_type).collect(),
+            Declaration::Enum(variants) => variants.iter().flat_map(|v| &v.fields).collect(),
+        };
+        for var_type in field_types {
+            if let VarType::Struct(inner) | VarType::Enum(inner) = var_type {
+                self.add_type(inner, declarations, visiting)?;
+            }
+        }
+        visiting.pop();
+
+        match declaration {
+            Declaration::Struct(fields) => {
+                let (offsets, size, align) =
+                    self.place_fields(0, fields.iter().map(|field| &field.var_type));
+                let fields = fields
+                    .iter()
+                    .zip(offsets)
+                    .map(|(field, offset)| FieldLayout {
+                        name: field.name.clone(),
+                        var_type: field.var_type.clone(),
+                        offset,
+                    })
+                    .collect();
+                self.structs.insert(
+                    name.to_string(),
+                    StructLayout {
+                        fields,
+                        size,
+                        align,
+                    },
+                );
+            }
+            Declaration::Enum(variants) => {
+                let tag_size = self.size_of(&TAG_TYPE);
+                let mut layout = EnumLayout {
+                    variants: vec![],
+                    size: tag_size,
+                    align: tag_size,
+                };
+                for variant in variants.iter() {
+                    let (offsets, size, align) = self.place_fields(tag_size, variant.fields.iter());
+                    layout.size = layout.size.max(size);
+                    layout.align = layout.align.max(align);
+                    layout.variants.push(VariantLayout {
+                        name: variant.name.clone(),
+                        fields: offsets.into_iter().zip(variant.fields.clone()).collect(),
+                    });
+                }
+                layout.size = align_to(layout.size, layout.align);
+                self.enums.insert(name.to_string(), layout);
+            }
+        }
+        Ok(())
+    }
+
+    /// Offsets of consecutive fields placed from `start`, with the padded size and the alignment
+    fn place_fields<'a>(
+        &self,
+        start: i32,
+        types: impl Iterator<Item = &'a VarType>,
+    ) -> (Vec<i32>, i32, i32) {
+        let (mut offsets, mut size, mut align) = (vec![], start, 1);
+        for var_type in types {
+            let field_align = self.align_of(var_type);
+            let offset = align_to(size, field_align);
+            offsets.push(offset);
+            size = offset + self.size_of(var_type);
+            align = align.max(field_align);
+        }
+        (offsets, align_to(size, align), align)
+    }
+
+    pub fn size_of(&self, var_type: &VarType) -> i32 {
+        match var_type {
+            VarType::Int(int_type) => int_type.bits() as i32 / 8,
+            VarType::Bool => BOOL_SIZE,
+            VarType::Array(element, len) => self.size_of(element) * *len as i32,
+            VarType::Struct(name) => self.structs[name].size,
+            VarType::Enum(name) => self.enums[name].size,
+            VarType::Ref(..) => self.pointer_size,
+            VarType::Unit | VarType::Never => 0,
+        }
+    }
+
+    pub fn align_of(&self, var_type: &VarType) -> i32 {
+        match var_type {
+            VarType::Array(element, _) => self.align_of(element),
+            VarType::Struct(name) => self.structs[name].align,
+            VarType::Enum(name) => self.enums[name].align,
+            _ => self.size_of(var_type).max(1),
+        }
+    }
+
+    pub fn field(&self, name: &str, field: &str) -> Result<&FieldLayout, CompileError> {
+        self.structs
+            .get(name)
+            .and_then(|layout| layout.fields.iter().find(|f| f.name == field))
+            .ok_or_else(|| {
+                let message = format!("Struct {} has no field {}", name, field);
+                CompileError::new(ErrorKind::UnknownName, message)
+            })
+    }
+
+    /// The tag of a variant is its index in the declaration
+    pub fn variant(
+        &self,
+        name: &str,
+        variant: &str,
+    ) -> Result<(usize, &VariantLayout), CompileError> {
+        self.enums
+            .get(name)
+            .and_then(|layout| {
+                layout
+                    .variants
+                    .iter()
+                    .enumerate()
+                    .find(|(_, v)| v.name == variant)
+            })
+            .ok_or_else(|| {
+                let message = format!("Enum {} has no variant {}", name, variant);
+                CompileError::new(ErrorKind::UnknownName, message)
+            })
+    }
+
+    /// Integer and bool parts of a value with their offsets, a value is copied through them.
+    /// Enums are copied as whole words, their fields depend on the variant
+    pub fn scalar_fields(&self, var_type: &VarType) -> Vec<(i32, VarType)> {
+        match var_type {
+            VarType::Struct(name) => {
+                let mut scalars = vec![];
+                for field in &self.structs[name].fields {
+                    scalars.extend(
+                        self.scalar_fields(&field.var_type)
+                            .into_iter()
+                            .map(|(offset, var_type)| (field.offset + offset, var_type)),
+                    );
+                }
+                scalars
+            }
+            VarType::Enum(name) => {
+                let word = self.size_of(&TAG_TYPE);
+                (0..self.enums[name].size / word)
+                    .map(|i| (i * word, TAG_TYPE))
+                    .collect()
+            }
+            var_type => vec![(0, var_type.clone())],
+        }
+    }
+}
+
+/// Rounds `offset` up to a multiple of `align`
+pub fn align_to(offset: i32, align: i32) -> i32 {
+    (offset + align - 1) / align * align
+}
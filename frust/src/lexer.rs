@@ -1,24 +1,16 @@
-use std::{default, num::ParseIntError};
-
-use logos::Logos;
+use crate::ast::Span;
+use crate::diagnostic::{CompileError, ErrorKind};
+use logos::{Lexer, Logos};
 
 #[derive(Default, Debug, Clone, PartialEq)]
 pub enum LexingError {
     InvalidNumber(String),
+    InvalidString(String),
+    UnterminatedComment,
     #[default]
     UnknownLexem,
 }
 
-impl From<ParseIntError> for LexingError {
-    fn from(err: ParseIntError) -> Self {
-        use std::num::IntErrorKind::*;
-        match err.kind() {
-            PosOverflow | NegOverflow => LexingError::InvalidNumber("Overflow error".to_owned()),
-            _ => LexingError::InvalidNumber("Other error".to_owned()),
-        }
-    }
-}
-
 #[derive(Logos, Debug, PartialEq, Clone)]
 #[logos(error = LexingError)]
 #[logos(skip r"[ \t\n\f]+")]
@@ -26,12 +18,40 @@ pub enum Token {
     // Key words
     #[token("let")]
     KwLet,
+    #[token("mut")]
+    KwMut,
+    #[token("const")]
+    KwConst,
+    #[token("import")]
+    KwImport,
+    #[token("mod")]
+    KwMod,
     #[token("if")]
     KwIf,
     #[token("else")]
     KwElse,
+    #[token("match")]
+    KwMatch,
     #[token("while")]
     KwWhile,
+    #[token("for")]
+    KwFor,
+    #[token("in")]
+    KwIn,
+    #[token("break")]
+    KwBreak,
+    #[token("continue")]
+    KwContinue,
+    #[token("fn")]
+    KwFn,
+    #[token("struct")]
+    KwStruct,
+    #[token("enum")]
+    KwEnum,
+    #[token("return")]
+    KwReturn,
+    #[token("as")]
+    KwAs,
 
     // Types
     #[token("num")]
@@ -68,21 +88,76 @@ pub enum Token {
     OpGt,
     #[token("!")]
     OpNot,
+    #[token("&")]
+    OpBitAnd,
+    #[token("|")]
+    OpBitOr,
+    #[token("^")]
+    OpBitXor,
+    #[token("~")]
+    OpBitNot,
+    #[token("<<")]
+    OpShl,
+    #[token(">>")]
+    OpShr,
+    #[token(">>>")]
+    OpUshr,
+
+    // Compound assignment
+    #[token("+=")]
+    OpAddAssign,
+    #[token("-=")]
+    OpSubAssign,
+    #[token("*=")]
+    OpMulAssign,
+    #[token("/=")]
+    OpDivAssign,
+    #[token("%=")]
+    OpModAssign,
+    #[token("&=")]
+    OpBitAndAssign,
+    #[token("|=")]
+    OpBitOrAssign,
+    #[token("^=")]
+    OpBitXorAssign,
+    #[token("<<=")]
+    OpShlAssign,
+    #[token(">>=")]
+    OpShrAssign,
+    #[token(">>>=")]
+    OpUshrAssign,
 
     // Other
     #[token("=")]
     Assign,
     #[token(":")]
     Colon,
+    #[token("::")]
+    PathSep,
     #[token(";")]
     Semicolon,
     #[token(",")]
     Comma,
+    #[token("->")]
+    Arrow,
+    #[token("=>")]
+    FatArrow,
+    #[token(".")]
+    Dot,
+    #[token("..")]
+    DotDot,
+    #[token("..=")]
+    DotDotEq,
     #[token("(")]
     LParen,
     #[token(")")]
     RParen,
 
+    #[token("[")]
+    LBracket,
+    #[token("]")]
+    RBracket,
+
     #[token("{")]
     LBrace,
     #[token("}")]
@@ -96,19 +171,152 @@ pub enum Token {
     #[token("true", |_| true)]
     LitBool(bool),
 
-    #[regex(r"\d+", |lex| lex.slice().parse())]
-    LitNumber(i32),
+    // Letters are part of the match so that `0b12` or `10abc` are reported as a whole
+    #[regex(r"[0-9][0-9a-zA-Z_]*", |lex| parse_number(lex.slice()))]
+    LitNumber(u64),
+
+    #[regex(r#""([^"\\]|\\.)*""#, |lex| unescape(lex.slice()))]
+    LitString(String),
+
+    // Comments are trivia, the parser skips them. They hold the text without the delimiters
+    #[regex(r"///([^/\n][^\n]*)?", |lex| lex.slice()[3..].to_string())]
+    DocComment(String),
+    #[regex(r"//[^\n]*", |lex| lex.slice()[2..].to_string())]
+    LineComment(String),
+    #[token("/*", block_comment)]
+    BlockComment(String),
+}
+
+impl Token {
+    pub fn is_trivia(&self) -> bool {
+        matches!(
+            self,
+            Token::DocComment(_) | Token::LineComment(_) | Token::BlockComment(_)
+        )
+    }
+}
+
+/// Length of the block comment `text` starts with, `/* */` nest.
+/// None if the comment isn't closed
+pub fn block_comment_len(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut offset = 0;
+    while offset < text.len() {
+        match text.as_bytes()[offset..].get(..2) {
+            Some(b"/*") => depth += 1,
+            Some(b"*/") => depth -= 1,
+            _ => {
+                offset += 1;
+                continue;
+            }
+        }
+        offset += 2;
+        if depth == 0 {
+            return Some(offset);
+        }
+    }
+    None
+}
+
+fn block_comment(lex: &mut Lexer<Token>) -> Result<String, LexingError> {
+    let text = &lex.source()[lex.span().start..];
+    let len = block_comment_len(text).ok_or(LexingError::UnterminatedComment)?;
+    lex.bump(len - 2);
+    let comment = lex.slice();
+    Ok(comment[2..comment.len() - 2].to_string())
+}
+
+/// Parses decimal, `0x` hexadecimal, `0o` octal and `0b` binary literals with `_` separators,
+/// the range of the literal is checked against its type later
+fn parse_number(literal: &str) -> Result<u64, LexingError> {
+    let (radix, base, digits) = match literal.get(..2) {
+        Some("0x" | "0X") => (16, "hexadecimal", &literal[2..]),
+        Some("0o" | "0O") => (8, "octal", &literal[2..]),
+        Some("0b" | "0B") => (2, "binary", &literal[2..]),
+        _ => (10, "decimal", literal),
+    };
+    let digits: String = digits.chars().filter(|c| *c != '_').collect();
+    if digits.is_empty() {
+        return Err(LexingError::InvalidNumber(format!(
+            "No digits in {} literal {}",
+            base, literal
+        )));
+    }
+    if let Some(c) = digits.chars().find(|c| !c.is_digit(radix)) {
+        return Err(LexingError::InvalidNumber(format!(
+            "Invalid digit '{}' in {} literal {}",
+            c, base, literal
+        )));
+    }
+    u64::from_str_radix(&digits, radix).map_err(|_| {
+        LexingError::InvalidNumber(format!(
+            "Value of {} literal {} doesn't fit in 64 bits",
+            base, literal
+        ))
+    })
+}
+
+/// Strips the quotes of a string literal and resolves its escape sequences
+fn unescape(literal: &str) -> Result<String, LexingError> {
+    let mut result = String::new();
+    let mut chars = literal[1..literal.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some('0') => result.push('\0'),
+            Some('\\') => result.push('\\'),
+            Some('"') => result.push('"'),
+            Some(other) => {
+                return Err(LexingError::InvalidString(format!(
+                    "Unknown escape sequence \\{}",
+                    other
+                )))
+            }
+            None => unreachable!("String literal can't end with a backslash"),
+        }
+    }
+    Ok(result)
+}
+
+/// Tokens without their spans, for the tests
+#[cfg(test)]
+pub fn lexer(input: &str) -> Result<Vec<Token>, CompileError> {
+    lexer_with_spans(input).map(|tokens| tokens.into_iter().map(|(token, _)| token).collect())
 }
 
-pub fn lexer(input: &str) -> Result<Vec<Token>, String> {
+/// Tokens with the byte range of their lexem, errors point at the invalid lexem
+pub fn lexer_with_spans(input: &str) -> Result<Vec<(Token, Span)>, CompileError> {
     let mut tokens = Vec::new();
-    let mut lexer = Token::lexer(input);
+    let lexer = Token::lexer(input).spanned();
 
-    while let Some(token) = lexer.next() {
+    for (token, span) in lexer {
+        let span = Span::new(span.start, span.end);
         match token {
-            Ok(token) => tokens.push(token),
-            Err(LexingError::InvalidNumber(s)) => return Err(format!("Invalid number: {}", s)),
-            _ => return Err(format!("Unknown lexem")),
+            Ok(token) => tokens.push((token, span)),
+            Err(LexingError::InvalidNumber(s)) => {
+                let message = format!("Invalid number: {}", s);
+                return Err(CompileError::new(ErrorKind::InvalidNumber, message).at(span));
+            }
+            Err(LexingError::InvalidString(s)) => {
+                let message = format!("Invalid string: {}", s);
+                return Err(CompileError::new(ErrorKind::InvalidString, message)
+                    .at(span)
+                    .help(r#"escape sequences are \n, \t, \r, \0, \\ and \""#.to_string()));
+            }
+            Err(LexingError::UnterminatedComment) => {
+                let message = "Unterminated block comment".to_string();
+                return Err(CompileError::new(ErrorKind::UnterminatedComment, message).at(span));
+            }
+            _ => {
+                let message = "Unknown lexem".to_string();
+                return Err(CompileError::new(ErrorKind::UnknownLexem, message).at(span));
+            }
         }
     }
 
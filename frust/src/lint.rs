@@ -0,0 +1,829 @@
+use crate::ast::*;
+use crate::codegen::Target;
+use crate::consteval::{evaluate, is_constant};
+use crate::diagnostic::{CompileError, ErrorKind, Severity};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::mem;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Lint {
+    UnusedVariables,
+    UnusedAssignments,
+    UnreachableCode,
+    WhileFalse,
+    ConstantCondition,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 5] = [
+        Lint::UnusedVariables,
+        Lint::UnusedAssignments,
+        Lint::UnreachableCode,
+        Lint::WhileFalse,
+        Lint::ConstantCondition,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::UnusedVariables => "unused_variables",
+            Lint::UnusedAssignments => "unused_assignments",
+            Lint::UnreachableCode => "unreachable_code",
+            Lint::WhileFalse => "while_false",
+            Lint::ConstantCondition => "constant_condition",
+        }
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Lint::UnusedVariables => "W0001",
+            Lint::UnusedAssignments => "W0002",
+            Lint::UnreachableCode => "W0003",
+            Lint::WhileFalse => "W0004",
+            Lint::ConstantCondition => "W0005",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+impl Level {
+    fn flag(self) -> &'static str {
+        match self {
+            Level::Allow => "-A",
+            Level::Warn => "-W",
+            Level::Deny => "-D",
+        }
+    }
+}
+
+/// Levels set with `-W`, `-A` and `-D`, a later flag overrides an earlier one.
+/// Every lint warns by default, `warnings` stands for all of them
+#[derive(Debug, Clone, Default)]
+pub struct LintLevels {
+    levels: HashMap<Lint, (Level, &'static str)>, // level, name in the flag that set it
+}
+
+impl LintLevels {
+    pub fn new() -> Self {
+        LintLevels::default()
+    }
+
+    pub fn is_lint(name: &str) -> bool {
+        name == "warnings" || Lint::ALL.iter().any(|lint| lint.name() == name)
+    }
+
+    pub fn set(&mut self, name: &str, level: Level) -> Result<(), CompileError> {
+        if name == "warnings" {
+            for lint in Lint::ALL {
+                self.levels.insert(lint, (level, "warnings"));
+            }
+            return Ok(());
+        }
+        let lint = Lint::ALL
+            .into_iter()
+            .find(|lint| lint.name() == name)
+            .ok_or_else(|| CompileError::new(ErrorKind::Usage, format!("Unknown lint {}", name)))?;
+        self.levels.insert(lint, (level, lint.name()));
+        Ok(())
+    }
+
+    pub fn level(&self, lint: Lint) -> Level {
+        self.levels
+            .get(&lint)
+            .map_or(Level::Warn, |(level, _)| *level)
+    }
+
+    fn origin(&self, lint: Lint) -> String {
+        match self.levels.get(&lint) {
+            Some((level, name)) => format!("`{} {}` is set", level.flag(), name),
+            None => format!("`-W {}` is on by default", lint.name()),
+        }
+    }
+}
+
+/// Warnings about code that is valid but most likely not what was meant.
+/// Runs on a checked program, lints denied with `-D` are reported as errors
+pub fn lint(program: &[Stmt], levels: &LintLevels, target: Target) -> Vec<CompileError> {
+    let mut linter = Linter {
+        levels,
+        target,
+        diagnostics: vec![],
+        scopes: vec![vec![]],
+        borrowed: borrowed_variables(program.iter().map(|stmt| &stmt.expr)),
+        span: Span::default(),
+    };
+    linter.lint_statements(program, None);
+    linter.pop_scope();
+    for (name, span) in dead_assignments(program, target) {
+        linter.report(
+            Lint::UnusedAssignments,
+            span,
+            format!("Value assigned to {} is never read", name),
+            None,
+        );
+    }
+    let mut diagnostics = linter.diagnostics;
+    diagnostics.sort_by_key(|diagnostic| diagnostic.span.map(|span| span.start));
+    // Why a lint is reported is told once
+    let mut noted = HashSet::new();
+    for diagnostic in &mut diagnostics {
+        if let ErrorKind::Lint(lint) = diagnostic.kind {
+            if noted.insert(lint) {
+                diagnostic.notes.push(levels.origin(lint));
+            }
+        }
+    }
+    diagnostics
+}
+
+struct Local {
+    name: String,
+    span: Span,
+    read: bool,
+}
+
+struct Linter<'a> {
+    levels: &'a LintLevels,
+    target: Target,
+    diagnostics: Vec<CompileError>,
+    scopes: Vec<Vec<Local>>,
+    borrowed: HashSet<String>, // variables whose address is taken, they can be read through it
+    span: Span,                // statement being linted
+}
+
+impl Linter<'_> {
+    fn report(&mut self, lint: Lint, span: Span, message: String, help: Option<String>) {
+        let severity = match self.levels.level(lint) {
+            Level::Allow => return,
+            Level::Warn => Severity::Warning,
+            Level::Deny => Severity::Error,
+        };
+        self.diagnostics.push(CompileError {
+            severity,
+            help,
+            ..CompileError::new(ErrorKind::Lint(lint), message).at(span)
+        });
+    }
+
+    fn declare(&mut self, name: &str, span: Span) {
+        let binding = Local {
+            name: name.to_string(),
+            span,
+            read: false,
+        };
+        self.scopes.last_mut().unwrap().push(binding);
+    }
+
+    fn read(&mut self, name: &str) {
+        let binding = self
+            .scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.iter_mut().rev().find(|binding| binding.name == name));
+        if let Some(binding) = binding {
+            binding.read = true;
+        }
+    }
+
+    fn pop_scope(&mut self) {
+        for binding in self.scopes.pop().unwrap_or_default() {
+            if !binding.read && !binding.name.starts_with('_') {
+                self.report(
+                    Lint::UnusedVariables,
+                    binding.span,
+                    format!("Unused variable {}", binding.name),
+                    Some(format!(
+                        "if this is intentional, prefix it with an underscore: _{}",
+                        binding.name
+                    )),
+                );
+            }
+        }
+    }
+
+    fn lint_block(&mut self, block: &Block) {
+        self.scopes.push(vec![]);
+        self.lint_statements(&block.statements, block.value.as_deref());
+        self.pop_scope();
+    }
+
+    fn lint_statements(&mut self, statements: &[Stmt], value: Option<&Expr>) {
+        for stmt in statements {
+            let outer = mem::replace(&mut self.span, stmt.span);
+            self.lint_expr(&stmt.expr);
+            self.span = outer;
+        }
+        if let Some(value) = value {
+            self.lint_expr(value);
+        }
+
+        // Only the first statement that can't be reached is reported
+        let diverging = statements
+            .iter()
+            .position(|stmt| diverges(&stmt.expr, self.target));
+        if let Some(i) = diverging {
+            let span = match statements.get(i + 1) {
+                Some(next) => next.span,
+                None if value.is_some() => statements[i].span,
+                None => return,
+            };
+            let cause = match &statements[i].expr.kind {
+                ExprKind::Return(_) => "'return'",
+                ExprKind::Break => "'break'",
+                ExprKind::Continue => "'continue'",
+                _ => "a loop that never ends",
+            };
+            self.report(
+                Lint::UnreachableCode,
+                span,
+                format!("Unreachable code after {}", cause),
+                None,
+            );
+        }
+    }
+
+    fn lint_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Var(name) => self.read(name),
+            ExprKind::Let {
+                name,
+                name_span,
+                expr,
+                ..
+            } => {
+                self.lint_expr(expr);
+                self.declare(name, *name_span);
+            }
+            ExprKind::Assign { target, expr } => {
+                // Writing a variable isn't reading it, writing a part of it is
+                if !matches!(target.kind, ExprKind::Var(_)) {
+                    self.lint_expr(target);
+                }
+                self.lint_expr(expr);
+            }
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                if let Some(value) = constant(condition, self.target) {
+                    self.report(
+                        Lint::ConstantCondition,
+                        condition.span,
+                        format!("Condition of 'if' is always {}", value),
+                        None,
+                    );
+                }
+                self.lint_expr(condition);
+                self.lint_block(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.lint_block(else_branch);
+                }
+            }
+            ExprKind::While { condition, body } => {
+                if constant(condition, self.target) == Some(false) {
+                    self.report(
+                        Lint::WhileFalse,
+                        condition.span,
+                        "Body of 'while false' is never executed".to_string(),
+                        None,
+                    );
+                }
+                self.lint_expr(condition);
+                self.lint_block(body);
+            }
+            ExprKind::For {
+                var,
+                var_span,
+                start,
+                end,
+                step,
+                body,
+                ..
+            } => {
+                self.lint_expr(start);
+                self.lint_expr(end);
+                if let Some(step) = step {
+                    self.lint_expr(step);
+                }
+                self.scopes.push(vec![]);
+                self.declare(var, *var_span);
+                self.lint_block(body);
+                self.pop_scope();
+            }
+            ExprKind::Match { scrutinee, arms } => {
+                self.lint_expr(scrutinee);
+                for arm in arms {
+                    self.scopes.push(vec![]);
+                    for pattern in &arm.patterns {
+                        if let Pattern::Variant { bindings, .. } = pattern {
+                            for binding in bindings.iter().flatten() {
+                                self.declare(&binding.name, binding.span);
+                            }
+                        }
+                    }
+                    self.lint_block(&arm.body);
+                    self.pop_scope();
+                }
+            }
+            ExprKind::Block(block) => self.lint_block(block),
+            // Functions only see the constants of the top level
+            ExprKind::Function { params, body, .. } => {
+                let outer_scopes = mem::replace(&mut self.scopes, vec![vec![]]);
+                let outer_borrowed = mem::replace(
+                    &mut self.borrowed,
+                    borrowed_variables(body.statements.iter().map(|stmt| &stmt.expr)),
+                );
+                for param in params {
+                    self.declare(&param.name, param.span);
+                }
+                self.lint_block(body);
+                self.pop_scope();
+                self.scopes = outer_scopes;
+                self.borrowed = outer_borrowed;
+            }
+            _ => {
+                for child in children(expr) {
+                    self.lint_expr(child);
+                }
+            }
+        }
+    }
+}
+
+/// Assignments whose value no path of the program reads, with the name of their variable.
+/// Variables that are never read are left to the unused variables lint
+fn dead_assignments(program: &[Stmt], target: Target) -> Vec<(String, Span)> {
+    let mut assignments = Assignments {
+        target,
+        writes: vec![],
+        next: 0,
+        scopes: vec![vec![]],
+        read_variables: HashSet::new(),
+        borrowed: borrowed_variables(program.iter().map(|stmt| &stmt.expr)),
+        loops: vec![],
+    };
+    let mut values = Values::new();
+    for stmt in program {
+        assignments.flow_write(&stmt.expr, stmt.span, &mut values);
+    }
+    assignments
+        .writes
+        .into_iter()
+        .filter(|write| {
+            write.reported && !write.read && assignments.read_variables.contains(&write.binding)
+        })
+        .map(|write| (write.name, write.span))
+        .collect()
+}
+
+/// Writes whose value a variable may hold at a point of the code
+type Values = BTreeSet<usize>;
+
+/// Value given to a variable by its declaration or by an assignment
+struct Write {
+    name: String,
+    binding: usize, // the write declaring the variable
+    span: Span,
+    reported: bool, // parameters, loop variables, pattern bindings and borrowed variables aren't
+    read: bool,
+}
+
+/// Values that leave a loop with `break` or go back to its start with `continue`
+#[derive(Default)]
+struct Jumps {
+    breaks: Values,
+    continues: Values,
+}
+
+/// Follows the code in execution order with the writes each variable may hold,
+/// a read marks them as read and an assignment replaces them
+struct Assignments {
+    target: Target,
+    writes: Vec<Write>,
+    next: usize, // id of the next write, code visited again gets the same ids
+    scopes: Vec<Vec<(String, usize)>>, // variables with the writes declaring them
+    read_variables: HashSet<usize>, // variables read somewhere
+    borrowed: HashSet<String>,
+    loops: Vec<Jumps>,
+}
+
+impl Assignments {
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.iter().rev().find(|(local, _)| local == name))
+            .map(|(_, binding)| *binding)
+    }
+
+    /// A declaration when `binding` is `None`
+    fn write(
+        &mut self,
+        name: &str,
+        binding: Option<usize>,
+        span: Span,
+        reported: bool,
+        values: &mut Values,
+    ) {
+        let id = self.next;
+        self.next += 1;
+        let binding = binding.unwrap_or(id);
+        if id == self.writes.len() {
+            self.writes.push(Write {
+                name: name.to_string(),
+                binding,
+                span,
+                reported: reported && !name.starts_with('_') && !self.borrowed.contains(name),
+                read: false,
+            });
+        }
+        values.retain(|write| self.writes[*write].binding != binding);
+        values.insert(id);
+    }
+
+    fn declare(&mut self, name: &str, span: Span, reported: bool, values: &mut Values) {
+        let binding = self.next;
+        self.write(name, None, span, reported, values);
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .push((name.to_string(), binding));
+    }
+
+    fn read(&mut self, name: &str, values: &mut Values) {
+        let Some(binding) = self.lookup(name) else {
+            return;
+        };
+        self.read_variables.insert(binding);
+        let writes = &mut self.writes;
+        values.retain(|write| {
+            let held = writes[*write].binding == binding;
+            if held {
+                writes[*write].read = true;
+            }
+            !held
+        });
+    }
+
+    /// `span` is the one of the statement when the write is a statement
+    fn flow_write(&mut self, expr: &Expr, span: Span, values: &mut Values) {
+        match &expr.kind {
+            ExprKind::Let {
+                name, expr: value, ..
+            } => {
+                self.flow(value, values);
+                self.declare(name, span, true, values);
+            }
+            ExprKind::Assign {
+                target,
+                expr: value,
+            } => {
+                self.flow(value, values);
+                match &target.kind {
+                    ExprKind::Var(name) => {
+                        if let Some(binding) = self.lookup(name) {
+                            self.write(name, Some(binding), span, true, values);
+                        }
+                    }
+                    // Writing a part of a variable keeps the rest of its value
+                    _ => self.flow(target, values),
+                }
+            }
+            ExprKind::CompoundAssign {
+                target,
+                expr: value,
+                ..
+            } => {
+                self.flow(value, values);
+                self.flow(target, values);
+                if let ExprKind::Var(name) = &target.kind {
+                    if let Some(binding) = self.lookup(name) {
+                        self.write(name, Some(binding), span, true, values);
+                    }
+                }
+            }
+            _ => self.flow(expr, values),
+        }
+    }
+
+    fn flow(&mut self, expr: &Expr, values: &mut Values) {
+        match &expr.kind {
+            ExprKind::Var(name) => self.read(name, values),
+            ExprKind::Let { .. } | ExprKind::Assign { .. } | ExprKind::CompoundAssign { .. } => {
+                self.flow_write(expr, expr.span, values)
+            }
+            ExprKind::Binary {
+                left,
+                op: BinaryOp::And | BinaryOp::Or,
+                right,
+                ..
+            } => {
+                self.flow(left, values);
+                let skipped = values.clone();
+                self.flow(right, values);
+                values.extend(skipped);
+            }
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.flow(condition, values);
+                let mut other = values.clone();
+                self.flow_block(then_branch, values, &[]);
+                if let Some(else_branch) = else_branch {
+                    self.flow_block(else_branch, &mut other, &[]);
+                }
+                values.extend(other);
+            }
+            ExprKind::Match { scrutinee, arms } => {
+                self.flow(scrutinee, values);
+                let start = mem::take(values);
+                for arm in arms {
+                    let bindings: Vec<(&str, Span)> = arm
+                        .patterns
+                        .iter()
+                        .flat_map(|pattern| match pattern {
+                            Pattern::Variant { bindings, .. } => bindings.as_slice(),
+                            _ => &[],
+                        })
+                        .flatten()
+                        .map(|binding| (binding.name.as_str(), binding.span))
+                        .collect();
+                    let mut arm_values = start.clone();
+                    self.flow_block(&arm.body, &mut arm_values, &bindings);
+                    values.extend(arm_values);
+                }
+            }
+            ExprKind::While { condition, body } => {
+                self.flow_loop(Some(condition), &[], body, values)
+            }
+            ExprKind::For {
+                var,
+                var_span,
+                start,
+                end,
+                step,
+                body,
+                ..
+            } => {
+                self.flow(start, values);
+                self.flow(end, values);
+                if let Some(step) = step {
+                    self.flow(step, values);
+                }
+                self.flow_loop(None, &[(var, *var_span)], body, values);
+            }
+            ExprKind::Block(block) => self.flow_block(block, values, &[]),
+            // Functions only see the constants of the top level
+            ExprKind::Function { params, body, .. } => {
+                let outer_scopes = mem::take(&mut self.scopes);
+                let outer_loops = mem::take(&mut self.loops);
+                let outer_borrowed = mem::replace(
+                    &mut self.borrowed,
+                    borrowed_variables(body.statements.iter().map(|stmt| &stmt.expr)),
+                );
+                let params: Vec<(&str, Span)> = params
+                    .iter()
+                    .map(|param| (param.name.as_str(), param.span))
+                    .collect();
+                self.flow_block(body, &mut Values::new(), &params);
+                self.scopes = outer_scopes;
+                self.loops = outer_loops;
+                self.borrowed = outer_borrowed;
+            }
+            ExprKind::Return(value) => {
+                if let Some(value) = value {
+                    self.flow(value, values);
+                }
+                values.clear();
+            }
+            ExprKind::Break | ExprKind::Continue => {
+                if let Some(jumps) = self.loops.last_mut() {
+                    match expr.kind {
+                        ExprKind::Break => jumps.breaks.append(values),
+                        _ => jumps.continues.append(values),
+                    }
+                }
+                values.clear();
+            }
+            _ => {
+                for child in children(expr) {
+                    self.flow(child, values);
+                }
+            }
+        }
+    }
+
+    /// `declared` are the variables the block starts with, they end with it
+    fn flow_block(&mut self, block: &Block, values: &mut Values, declared: &[(&str, Span)]) {
+        self.scopes.push(vec![]);
+        for (name, span) in declared {
+            self.declare(name, *span, false, values);
+        }
+        for stmt in &block.statements {
+            self.flow_write(&stmt.expr, stmt.span, values);
+        }
+        if let Some(value) = &block.value {
+            self.flow(value, values);
+        }
+        let scope = self.scopes.pop().unwrap_or_default();
+        values.retain(|write| {
+            let binding = self.writes[*write].binding;
+            !scope.iter().any(|(_, local)| *local == binding)
+        });
+    }
+
+    /// The body runs again with the values it ends with, until they stay the same.
+    /// `for` loops have no condition, they can end before every run of the body
+    fn flow_loop(
+        &mut self,
+        condition: Option<&Expr>,
+        declared: &[(&str, Span)],
+        body: &Block,
+        values: &mut Values,
+    ) {
+        let first = self.next;
+        let mut start = values.clone();
+        loop {
+            self.next = first;
+            let mut iteration = start.clone();
+            if let Some(condition) = condition {
+                self.flow(condition, &mut iteration);
+            }
+            let mut exit = match condition {
+                Some(condition) if constant(condition, self.target) == Some(true) => Values::new(),
+                _ => iteration.clone(),
+            };
+            self.loops.push(Jumps::default());
+            self.flow_block(body, &mut iteration, declared);
+            let jumps = self.loops.pop().unwrap_or_default();
+            iteration.extend(jumps.continues);
+            let next: Values = start.union(&iteration).copied().collect();
+            if next == start {
+                exit.extend(jumps.breaks);
+                *values = exit;
+                return;
+            }
+            start = next;
+        }
+    }
+}
+
+/// Value of a condition made of literals only, conditions using constants aren't linted
+fn constant(condition: &Expr, target: Target) -> Option<bool> {
+    if !is_constant(condition, &|_| false) {
+        return None;
+    }
+    let constant = evaluate(condition, target, &|_| None).ok()?;
+    Some(constant.value != 0)
+}
+
+/// Whether the statement never lets the code after it run
+fn diverges(expr: &Expr, target: Target) -> bool {
+    match &expr.kind {
+        ExprKind::Return(_) | ExprKind::Break | ExprKind::Continue => true,
+        ExprKind::While { condition, body } => {
+            constant(condition, target) == Some(true) && !block_breaks(body)
+        }
+        _ => false,
+    }
+}
+
+/// Whether a `break` in the block ends the loop the block is the body of
+fn block_breaks(block: &Block) -> bool {
+    block
+        .statements
+        .iter()
+        .map(|stmt| &stmt.expr)
+        .chain(block.value.as_deref())
+        .any(breaks)
+}
+
+fn breaks(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Break => true,
+        // A break in a nested loop ends that loop
+        ExprKind::While { condition, .. } => breaks(condition),
+        ExprKind::For {
+            start, end, step, ..
+        } => breaks(start) || breaks(end) || step.as_deref().is_some_and(breaks),
+        _ => children(expr).into_iter().any(breaks),
+    }
+}
+
+/// Variables a reference is taken to, by name
+fn borrowed_variables<'a>(exprs: impl Iterator<Item = &'a Expr>) -> HashSet<String> {
+    fn collect(expr: &Expr, borrowed: &mut HashSet<String>) {
+        if let ExprKind::Ref { expr: place, .. } = &expr.kind {
+            let mut base = place.as_ref();
+            while let ExprKind::Index { array: inner, .. } | ExprKind::Field { base: inner, .. } =
+                &base.kind
+            {
+                base = inner;
+            }
+            if let ExprKind::Var(name) = &base.kind {
+                borrowed.insert(name.clone());
+            }
+        }
+        for child in children(expr) {
+            collect(child, borrowed);
+        }
+    }
+    let mut borrowed = HashSet::new();
+    for expr in exprs {
+        collect(expr, &mut borrowed);
+    }
+    borrowed
+}
+
+/// Expressions directly inside an expression, with the statements of its blocks
+fn children(expr: &Expr) -> Vec<&Expr> {
+    fn block(block: &Block) -> impl Iterator<Item = &Expr> {
+        block
+            .statements
+            .iter()
+            .map(|stmt| &stmt.expr)
+            .chain(block.value.as_deref())
+    }
+    match &expr.kind {
+        ExprKind::Number(_)
+        | ExprKind::Bool(_)
+        | ExprKind::Str(_)
+        | ExprKind::Var(_)
+        | ExprKind::Break
+        | ExprKind::Continue
+        | ExprKind::Import { .. }
+        | ExprKind::Struct { .. }
+        | ExprKind::Enum { .. }
+        | ExprKind::Error => vec![],
+        ExprKind::Unary { expr, .. }
+        | ExprKind::Cast { expr, .. }
+        | ExprKind::Let { expr, .. }
+        | ExprKind::Const { expr, .. }
+        | ExprKind::Field { base: expr, .. }
+        | ExprKind::Ref { expr, .. }
+        | ExprKind::Deref(expr)
+        | ExprKind::ArrayRepeat { value: expr, .. } => vec![expr],
+        ExprKind::Binary { left, right, .. } => vec![left, right],
+        ExprKind::Assign { target, expr } | ExprKind::CompoundAssign { target, expr, .. } => {
+            vec![target, expr]
+        }
+        ExprKind::Index { array, index } => vec![array, index],
+        ExprKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => {
+            let mut children = vec![condition.as_ref()];
+            children.extend(block(then_branch));
+            if let Some(else_branch) = else_branch {
+                children.extend(block(else_branch));
+            }
+            children
+        }
+        ExprKind::Match { scrutinee, arms } => {
+            let mut children = vec![scrutinee.as_ref()];
+            for arm in arms {
+                for pattern in &arm.patterns {
+                    match pattern {
+                        Pattern::Value(value) => children.push(value),
+                        Pattern::Range(start, end) => children.extend([start, end]),
+                        Pattern::Wildcard | Pattern::Variant { .. } => (),
+                    }
+                }
+                children.extend(block(&arm.body));
+            }
+            children
+        }
+        ExprKind::While { condition, body } => {
+            let mut children = vec![condition.as_ref()];
+            children.extend(block(body));
+            children
+        }
+        ExprKind::For {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            let mut children = vec![start.as_ref(), end.as_ref()];
+            children.extend(step.as_deref());
+            children.extend(block(body));
+            children
+        }
+        ExprKind::Array(exprs)
+        | ExprKind::Call { args: exprs, .. }
+        | ExprKind::Variant { args: exprs, .. } => exprs.iter().collect(),
+        ExprKind::StructLiteral { fields, .. } => fields.iter().map(|(_, expr)| expr).collect(),
+        ExprKind::Block(body) | ExprKind::Function { body, .. } => block(body).collect(),
+        ExprKind::Return(expr) => expr.as_deref().into_iter().collect(),
+    }
+}
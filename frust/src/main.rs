@@ -1,31 +1,80 @@
 use std::env;
+use std::process;
 mod ast;
 mod codegen;
+mod consteval;
+mod diagnostic;
 mod inst;
 mod io;
+mod layout;
 mod lexer;
+mod lint;
+mod modules;
 mod parser;
 mod preprocessor;
+mod typecheck;
 
+#[cfg(test)]
+mod sim;
+#[cfg(test)]
 mod test;
 
-use crate::preprocessor::*;
 use codegen::*;
+use diagnostic::*;
 use io::*;
-use lexer::*;
-use parser::*;
+use lint::*;
+use modules::*;
+use typecheck::*;
 
 fn main() {
-    let (input, output) = parse_args(env::args().collect()).unwrap();
-    let code = read_file(input).unwrap();
-    let preprocessed_code = remove_comments(&code);
+    let options = match parse_args(env::args().collect()) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", SourceMap::new().render(&err));
+            process::exit(1);
+        }
+    };
+    let mut sources = SourceMap::new();
+    let mut warnings = vec![];
+    let errors = compile(&options, &mut sources, &mut warnings)
+        .err()
+        .unwrap_or_default();
+    let rendered: Vec<String> = warnings
+        .iter()
+        .chain(&errors)
+        .map(|diagnostic| sources.render(diagnostic))
+        .collect();
+    if !rendered.is_empty() {
+        eprintln!("{}", rendered.join("\n\n"));
+    }
+    if !errors.is_empty() {
+        process::exit(1);
+    }
+}
 
-    let tokens = lexer(&preprocessed_code).unwrap();
-    let mut parser = Parser::new(tokens);
-    let expressions = parser.parse().unwrap();
-    let mut generator = CodeGenContext::new();
-    for expr in &expressions {
-        generator.generate(expr).unwrap();
+/// Denied lints fail the compilation together with the warnings found with them
+fn compile(
+    options: &Options,
+    sources: &mut SourceMap,
+    warnings: &mut Vec<CompileError>,
+) -> Result<(), Vec<CompileError>> {
+    let mut program = load_program(&options.input, &options.defines, sources)?;
+    let mut checker = TypeChecker::new();
+    checker.set_target(options.target);
+    checker.set_sources(sources);
+    checker.check(&mut program)?;
+    let diagnostics = lint(&program, &options.lints, options.target);
+    if diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error)
+    {
+        return Err(diagnostics);
     }
-    write_line_file(output, generator.instructions()).unwrap();
+    warnings.extend(diagnostics);
+    let mut generator = CodeGenContext::new();
+    generator.set_bounds_check(options.bounds_check);
+    generator.set_target(options.target);
+    generator.generate_program(&program)?;
+    write_line_file(options.output.clone(), &generator.assembly())?;
+    Ok(())
 }
@@ -0,0 +1,478 @@
+use crate::ast::*;
+use crate::diagnostic::*;
+use crate::io::*;
+use crate::lexer::*;
+use crate::parser::*;
+use crate::preprocessor::*;
+use crate::typecheck::is_builtin;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+/// Loads a program together with the modules it imports.
+/// Functions, constants, structs and enums of an imported module are renamed to `module::name`
+/// and placed before the statements of the main file
+/// Every file is preprocessed with the `-D` definitions and added to `sources`
+pub fn load_program(
+    path: &str,
+    defines: &[(String, String)],
+    sources: &mut SourceMap,
+) -> Result<Vec<Stmt>, Vec<CompileError>> {
+    let mut loader = ModuleLoader {
+        defines,
+        sources,
+        loaded: HashMap::new(),
+        loading: vec![],
+        names: HashSet::new(),
+        enums: HashSet::new(),
+        items: vec![],
+    };
+    let main = loader.load(Path::new(path), None, None)?;
+    let mut program = loader.items;
+    program.extend(main);
+    Ok(program)
+}
+
+struct ModuleLoader<'a> {
+    defines: &'a [(String, String)],
+    sources: &'a mut SourceMap,
+    loaded: HashMap<PathBuf, String>, // file -> module name
+    loading: Vec<(PathBuf, String)>,  // import chain, for cycle detection
+    names: HashSet<String>,
+    enums: HashSet<String>, // enums of the imported modules, by their full name
+    items: Vec<Stmt>,       // declarations of the imported modules
+}
+
+impl ModuleLoader<'_> {
+    /// The main file has no module name, its declarations keep their names.
+    /// Errors about opening a module point at its import
+    fn load(
+        &mut self,
+        path: &Path,
+        module: Option<&str>,
+        import: Option<Span>,
+    ) -> Result<Vec<Stmt>, Vec<CompileError>> {
+        let display = path.display().to_string();
+        let file = fs::canonicalize(path).map_err(|err| CompileError {
+            span: import,
+            ..CompileError::new(
+                ErrorKind::Module,
+                format!("Cannot open module {}: {}", display, err),
+            )
+        })?;
+        if let Some(start) = self
+            .loading
+            .iter()
+            .position(|(loading, _)| *loading == file)
+        {
+            let chain: Vec<&str> = self.loading[start..]
+                .iter()
+                .map(|(_, display)| display.as_str())
+                .collect();
+            return Err(vec![CompileError {
+                span: import,
+                ..CompileError::new(
+                    ErrorKind::Module,
+                    format!("Import cycle: {} -> {}", chain.join(" -> "), display),
+                )
+            }]);
+        }
+
+        let code = read_file(display.clone())?;
+        // Spans are shifted to the offsets of the file in the source map,
+        // errors of the preprocessor to the offsets of the original text
+        let source = match preprocess(&code, self.defines) {
+            Ok(source) => source,
+            Err(err) => {
+                let start = self
+                    .sources
+                    .add(display.clone(), Source::unprocessed(&code));
+                return Err(vec![shift(err, start)]);
+            }
+        };
+        let tokens = lexer_with_spans(&source.code);
+        let start = self.sources.add(display.clone(), source);
+        let tokens = tokens.map_err(|err| shift(err, start))?;
+        let tokens = tokens
+            .into_iter()
+            .map(|(token, span)| (token, span.shift(start)))
+            .collect();
+        let mut program = Parser::new(tokens).parse()?;
+
+        self.loading.push((file, display.clone()));
+        let mut imports = HashMap::new();
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for stmt in &program {
+            if let ExprKind::Import { name, path } = &stmt.expr.kind {
+                let module = self.import(&directory.join(path), name, stmt.span)?;
+                imports.insert(name.clone(), module);
+            }
+        }
+        self.loading.pop();
+
+        program.retain(|stmt| !matches!(stmt.expr.kind, ExprKind::Import { .. }));
+        let mut constants = HashSet::new();
+        let mut types = HashSet::new();
+        if let Some(module) = module {
+            for stmt in &program {
+                match &stmt.expr.kind {
+                    ExprKind::Function { .. } => (),
+                    ExprKind::Const { name, .. } => {
+                        constants.insert(name.clone());
+                    }
+                    ExprKind::Struct { name, .. } => {
+                        types.insert(name.clone());
+                    }
+                    ExprKind::Enum { name, .. } => {
+                        types.insert(name.clone());
+                        self.enums.insert(format!("{}::{}", module, name));
+                    }
+                    _ => {
+                        let message = format!(
+                            "Module {} can only contain functions, constants, structs, enums and imports",
+                            display
+                        );
+                        return Err(vec![
+                            CompileError::new(ErrorKind::Module, message).at(stmt.span)
+                        ]);
+                    }
+                }
+            }
+        }
+
+        let mut resolver = Resolver {
+            module,
+            imports: &imports,
+            constants: &constants,
+            types: &types,
+            enums: &self.enums,
+            locals: vec![],
+        };
+        for stmt in &mut program {
+            resolver.resolve(&mut stmt.expr)?;
+        }
+        Ok(program)
+    }
+
+    /// Every file is compiled once, however many modules import it
+    fn import(&mut self, path: &Path, name: &str, span: Span) -> Result<String, Vec<CompileError>> {
+        if let Ok(file) = fs::canonicalize(path) {
+            if let Some(module) = self.loaded.get(&file) {
+                return Ok(module.clone());
+            }
+        }
+        // Different files with the same name get distinct module names
+        let mut module = name.to_string();
+        let mut suffix = 1;
+        while self.names.contains(&module) {
+            suffix += 1;
+            module = format!("{}{}", name, suffix);
+        }
+
+        self.names.insert(module.clone());
+        let items = self.load(path, Some(&module), Some(span))?;
+        self.items.extend(items);
+        let file = fs::canonicalize(path)
+            .map_err(|err| CompileError::new(ErrorKind::Module, err.to_string()).at(span))?;
+        self.loaded.insert(file, module.clone());
+        Ok(module)
+    }
+}
+
+fn shift(error: CompileError, start: usize) -> CompileError {
+    CompileError {
+        span: error.span.map(|span| span.shift(start)),
+        ..error
+    }
+}
+
+/// Renames the declarations of a module and the names that refer to them
+struct Resolver<'a> {
+    module: Option<&'a str>,
+    imports: &'a HashMap<String, String>, // name used in the file -> module name
+    constants: &'a HashSet<String>,       // constants at the top level of the module
+    types: &'a HashSet<String>,           // structs and enums of the module
+    enums: &'a HashSet<String>,
+    locals: Vec<Vec<String>>, // variables in scope, they hide the constants of the module
+}
+
+impl Resolver<'_> {
+    /// `alias::name` refers to `name` in the module imported as `alias`,
+    /// `span` is the node using the name
+    fn imported_name(&self, name: &str, span: Span) -> Result<Option<String>, CompileError> {
+        let Some((alias, item)) = name.split_once("::") else {
+            return Ok(None);
+        };
+        match self.imports.get(alias) {
+            Some(module) => Ok(Some(format!("{}::{}", module, item))),
+            None => Err(CompileError::new(
+                ErrorKind::Module,
+                format!("Module {} is not imported", alias),
+            )
+            .at(span)
+            .help(format!("import it with `import \"{}.fr\";`", alias))),
+        }
+    }
+
+    /// Name of a declaration of the module, other names are left as they are
+    fn module_name(&self, name: &str, declared: bool) -> String {
+        match self.module {
+            Some(module) if declared => format!("{}::{}", module, name),
+            _ => name.to_string(),
+        }
+    }
+
+    /// Unqualified names refer to the functions of the same module
+    fn function_name(&self, name: &str, span: Span) -> Result<String, CompileError> {
+        match self.imported_name(name, span)? {
+            Some(name) => Ok(name),
+            None => Ok(self.module_name(name, !is_builtin(name))),
+        }
+    }
+
+    fn constant_name(&self, name: &str, span: Span) -> Result<String, CompileError> {
+        let local = self.locals.iter().flatten().any(|local| local == name);
+        match self.imported_name(name, span)? {
+            Some(name) => Ok(name),
+            None => Ok(self.module_name(name, self.constants.contains(name) && !local)),
+        }
+    }
+
+    fn type_name(&self, name: &str, span: Span) -> Result<String, CompileError> {
+        match self.imported_name(name, span)? {
+            Some(name) => Ok(name),
+            None => Ok(self.module_name(name, self.types.contains(name))),
+        }
+    }
+
+    /// The parser can't tell the structs and enums of other modules apart,
+    /// their types are parsed as structs
+    fn resolve_type(&self, var_type: &mut VarType, span: Span) -> Result<(), CompileError> {
+        match var_type {
+            VarType::Struct(name) | VarType::Enum(name) => {
+                let name = self.type_name(name, span)?;
+                let is_enum = matches!(var_type, VarType::Enum(_)) || self.enums.contains(&name);
+                *var_type = if is_enum {
+                    VarType::Enum(name)
+                } else {
+                    VarType::Struct(name)
+                };
+                Ok(())
+            }
+            VarType::Array(element, _) | VarType::Ref(element, _) => {
+                self.resolve_type(element, span)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Variables are only known inside the scope they are declared in
+    fn declare(&mut self, name: &str) {
+        if let Some(scope) = self.locals.last_mut() {
+            scope.push(name.to_string());
+        }
+    }
+
+    fn resolve(&mut self, expr: &mut Expr) -> Result<(), CompileError> {
+        let span = expr.span;
+        match &mut expr.kind {
+            ExprKind::Number(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Str(_)
+            | ExprKind::Break
+            | ExprKind::Error
+            | ExprKind::Continue
+            | ExprKind::Import { .. } => Ok(()),
+            ExprKind::Var(name) => {
+                *name = self.constant_name(name, span)?;
+                Ok(())
+            }
+            ExprKind::Unary { expr, .. }
+            | ExprKind::Field { base: expr, .. }
+            | ExprKind::Ref { expr, .. }
+            | ExprKind::Deref(expr) => self.resolve(expr),
+            ExprKind::Cast { expr, to } => {
+                self.resolve_type(to, span)?;
+                self.resolve(expr)
+            }
+            ExprKind::Let {
+                name,
+                var_type,
+                expr,
+                ..
+            } => {
+                self.resolve_type(var_type, span)?;
+                self.resolve(expr)?;
+                self.declare(name);
+                Ok(())
+            }
+            // Constants of a block are local like variables
+            ExprKind::Const {
+                name,
+                var_type,
+                expr,
+            } => {
+                self.resolve_type(var_type, span)?;
+                self.resolve(expr)?;
+                if self.locals.is_empty() {
+                    *name = self.module_name(name, true);
+                } else {
+                    self.declare(name);
+                }
+                Ok(())
+            }
+            ExprKind::Struct { name, fields } => {
+                *name = self.type_name(name, span)?;
+                fields
+                    .iter_mut()
+                    .try_for_each(|field| self.resolve_type(&mut field.var_type, span))
+            }
+            ExprKind::Enum { name, variants } => {
+                *name = self.type_name(name, span)?;
+                variants
+                    .iter_mut()
+                    .flat_map(|variant| variant.fields.iter_mut())
+                    .try_for_each(|field| self.resolve_type(field, span))
+            }
+            ExprKind::Binary { left, right, .. } => {
+                self.resolve(left)?;
+                self.resolve(right)
+            }
+            ExprKind::Assign { target, expr } | ExprKind::CompoundAssign { target, expr, .. } => {
+                self.resolve(target)?;
+                self.resolve(expr)
+            }
+            ExprKind::Index { array, index } => {
+                self.resolve(array)?;
+                self.resolve(index)
+            }
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.resolve(condition)?;
+                self.resolve_block(then_branch, vec![])?;
+                match else_branch {
+                    Some(else_branch) => self.resolve_block(else_branch, vec![]),
+                    None => Ok(()),
+                }
+            }
+            ExprKind::Match { scrutinee, arms } => {
+                self.resolve(scrutinee)?;
+                arms.iter_mut()
+                    .try_for_each(|arm| self.resolve_arm(arm, span))
+            }
+            ExprKind::While { condition, body } => {
+                self.resolve(condition)?;
+                self.resolve_block(body, vec![])
+            }
+            ExprKind::For {
+                var,
+                start,
+                end,
+                step,
+                body,
+                ..
+            } => {
+                self.resolve(start)?;
+                self.resolve(end)?;
+                if let Some(step) = step {
+                    self.resolve(step)?;
+                }
+                self.resolve_block(body, vec![var.clone()])
+            }
+            ExprKind::Array(elements) => elements.iter_mut().try_for_each(|e| self.resolve(e)),
+            ExprKind::ArrayRepeat { value, .. } => self.resolve(value),
+            ExprKind::StructLiteral { name, fields } => {
+                *name = self.type_name(name, span)?;
+                fields
+                    .iter_mut()
+                    .try_for_each(|(_, value)| self.resolve(value))
+            }
+            ExprKind::Block(block) => self.resolve_block(block, vec![]),
+            // The body only sees its parameters and the top level
+            ExprKind::Function {
+                name,
+                params,
+                ret_type,
+                body,
+            } => {
+                *name = self.function_name(name, span)?;
+                for param in params.iter_mut() {
+                    self.resolve_type(&mut param.var_type, span)?;
+                }
+                if let Some(ret_type) = ret_type {
+                    self.resolve_type(ret_type, span)?;
+                }
+                let names = params.iter().map(|param| param.name.clone()).collect();
+                let enclosing = mem::take(&mut self.locals);
+                let result = self.resolve_block(body, names);
+                self.locals = enclosing;
+                result
+            }
+            ExprKind::Variant {
+                enum_name, args, ..
+            } => {
+                *enum_name = self.type_name(enum_name, span)?;
+                args.iter_mut().try_for_each(|arg| self.resolve(arg))
+            }
+            ExprKind::Call { name, args } => {
+                *name = self.function_name(name, span)?;
+                args.iter_mut().try_for_each(|arg| self.resolve(arg))
+            }
+            ExprKind::Return(expr) => match expr {
+                Some(expr) => self.resolve(expr),
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// The variables bound by the patterns are in scope in the body of the arm
+    fn resolve_arm(&mut self, arm: &mut MatchArm, span: Span) -> Result<(), CompileError> {
+        let mut names = vec![];
+        for pattern in &mut arm.patterns {
+            match pattern {
+                Pattern::Wildcard => (),
+                Pattern::Value(value) => self.resolve(value)?,
+                Pattern::Range(start, end) => {
+                    self.resolve(start)?;
+                    self.resolve(end)?;
+                }
+                Pattern::Variant {
+                    enum_name,
+                    bindings,
+                    ..
+                } => {
+                    *enum_name = self.type_name(enum_name, span)?;
+                    names.extend(
+                        bindings
+                            .iter()
+                            .flatten()
+                            .map(|binding| binding.name.clone()),
+                    );
+                }
+            }
+        }
+        self.resolve_block(&mut arm.body, names)
+    }
+
+    /// `names` are declared in the scope of the block before its statements
+    fn resolve_block(&mut self, block: &mut Block, names: Vec<String>) -> Result<(), CompileError> {
+        self.locals.push(names);
+        let result = self.resolve_statements(block);
+        self.locals.pop();
+        result
+    }
+
+    fn resolve_statements(&mut self, block: &mut Block) -> Result<(), CompileError> {
+        for stmt in &mut block.statements {
+            self.resolve(&mut stmt.expr)?;
+        }
+        match &mut block.value {
+            Some(value) => self.resolve(value),
+            None => Ok(()),
+        }
+    }
+}
@@ -1,268 +1,967 @@
 use crate::ast::*;
+use crate::diagnostic::{CompileError, ErrorKind};
 use crate::lexer::Token;
-use std::iter::Peekable;
-use std::vec::IntoIter;
+use std::collections::HashSet;
+use std::mem;
+use std::path::Path;
 
-type TokenIter = Peekable<IntoIter<Token>>;
+/// Tokens with their spans, a syntax error points at the last token the parser looked at
+struct TokenStream {
+    tokens: Vec<(Token, Span)>,
+    position: usize,
+    last: usize, // index of the last token peeked or consumed
+}
+
+impl TokenStream {
+    fn peek(&mut self) -> Option<&Token> {
+        self.last = self.position;
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    /// The token `offset` positions after the next one
+    fn peek_nth(&self, offset: usize) -> Option<&Token> {
+        self.tokens
+            .get(self.position + offset)
+            .map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        self.last = self.position;
+        let token = self
+            .tokens
+            .get(self.position)
+            .map(|(token, _)| token.clone());
+        if token.is_some() {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn next_if_eq(&mut self, expected: &Token) -> Option<Token> {
+        if self.peek() == Some(expected) {
+            self.next()
+        } else {
+            None
+        }
+    }
+
+    /// Past the last token the span is the empty one at the end of the input
+    fn span(&self, index: usize) -> Span {
+        match self.tokens.get(index) {
+            Some((_, span)) => *span,
+            None => {
+                let end = self.tokens.last().map_or(0, |(_, span)| span.end);
+                Span::new(end, end)
+            }
+        }
+    }
+
+    /// From the token at `start` to the last consumed one
+    fn span_from(&self, start: usize) -> Span {
+        let end = self.position.max(start + 1) - 1;
+        Span::new(self.span(start).start, self.span(end).end)
+    }
+
+    /// The last consumed token
+    fn previous(&self) -> Span {
+        self.span_from(self.position.max(1) - 1)
+    }
+}
 
 pub struct Parser<'a> {
-    tokens: TokenIter,
+    tokens: TokenStream,
+    errors: Vec<CompileError>,
+    struct_literals: bool,  // false in the head of if, while, for and match
+    enums: HashSet<String>, // `Name::Variant` is a variant of these, not a module function
     _marker: std::marker::PhantomData<&'a ()>,
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(tokens: Vec<Token>) -> Self {
+    /// Comments are dropped. Enums can be used before their declaration,
+    /// their names are collected first
+    pub fn new(tokens: Vec<(Token, Span)>) -> Self {
+        let tokens: Vec<(Token, Span)> = tokens
+            .into_iter()
+            .filter(|(token, _)| !token.is_trivia())
+            .collect();
+        let enums = tokens
+            .windows(2)
+            .filter_map(|pair| match pair {
+                [(Token::KwEnum, _), (Token::Identifier(name), _)] => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
         Parser {
-            tokens: tokens.into_iter().peekable(),
+            tokens: TokenStream {
+                tokens,
+                position: 0,
+                last: 0,
+            },
+            errors: vec![],
+            struct_literals: true,
+            enums,
             _marker: std::marker::PhantomData,
         }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<Expr>, String> {
-        let mut expressions = Vec::new();
+    /// Node spanning from the token at `start` to the last consumed one
+    fn node(&self, start: usize, kind: ExprKind) -> Expr {
+        Expr::new(kind, self.tokens.span_from(start))
+    }
+
+    /// Error at the last token the parser looked at
+    fn error(&self, kind: ErrorKind, message: &str) -> CompileError {
+        CompileError::new(kind, message.to_string()).at(self.tokens.span(self.tokens.last))
+    }
+
+    fn syntax_error(&self, message: &str) -> CompileError {
+        self.error(ErrorKind::Syntax, message)
+    }
+
+    /// Reports every syntax error of the input
+    pub fn parse(&mut self) -> Result<Vec<Stmt>, Vec<CompileError>> {
+        let (statements, errors) = self.parse_partial();
+        if errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Statements with a syntax error become `ExprKind::Error`,
+    /// parsing goes on from the next statement
+    pub fn parse_partial(&mut self) -> (Vec<Stmt>, Vec<CompileError>) {
+        let mut statements = Vec::new();
         while self.tokens.peek().is_some() {
-            let expr = self.parse_expression()?;
-            expressions.push(expr);
+            let start = self.tokens.position;
+            let stmt = match self.parse_statement() {
+                Ok(expr) => Stmt {
+                    expr,
+                    span: self.tokens.span_from(start),
+                },
+                Err(err) => self.recover(err, start, false),
+            };
+            statements.push(stmt);
+        }
+        (statements, mem::take(&mut self.errors))
+    }
+
+    /// Records the error and skips to the start of the next statement:
+    /// past a `;` or a block closed at the level of the statement, or up to a statement keyword.
+    /// In a block the `}` closing it is left for the block
+    fn recover(&mut self, err: CompileError, start: usize, in_block: bool) -> Stmt {
+        self.errors.push(err);
+        // The token the error is about can start the next statement, it is looked at again.
+        // The statement has to consume something, or the same error is found again
+        if self.tokens.last + 1 == self.tokens.position && self.tokens.last > start {
+            self.tokens.position = self.tokens.last;
+        } else if self.tokens.position == start {
+            self.tokens.next();
+        }
+        let mut depth = 0;
+        while let Some(token) = self.tokens.peek() {
+            match token {
+                Token::Semicolon if depth == 0 => {
+                    self.tokens.next();
+                    break;
+                }
+                Token::RBrace if depth == 0 && in_block => break,
+                Token::RBrace if depth <= 1 => {
+                    self.tokens.next();
+                    break;
+                }
+                Token::RBrace => depth -= 1,
+                Token::LBrace => depth += 1,
+                token if depth == 0 && starts_statement(token) => break,
+                _ => (),
+            }
+            self.tokens.next();
+        }
+        let span = self.tokens.span_from(start);
+        Stmt {
+            expr: Expr::new(ExprKind::Error, span),
+            span,
+        }
+    }
+
+    fn parse_statement(&mut self) -> Result<Expr, CompileError> {
+        let expr = self.parse_statement_expr()?;
+        if !consumes_semicolon(&expr) {
+            match self.tokens.peek() {
+                Some(Token::Semicolon) => {
+                    self.tokens.next(); // Consume ;
+                }
+                _ if ends_with_block(&expr) => (),
+                _ => return Err(self.syntax_error("Expected ';' at the end of statement")),
+            }
+        }
+        Ok(expr)
+    }
+
+    /// Block-like expressions at the start of a statement end it, so `if c {} -x;` is two statements
+    fn parse_statement_expr(&mut self) -> Result<Expr, CompileError> {
+        match self.tokens.peek() {
+            Some(Token::KwIf | Token::KwMatch | Token::KwWhile | Token::KwFor | Token::LBrace) => {
+                self.parse_kw_or_expr()
+            }
+            _ => self.parse_expression(),
         }
-        Ok(expressions)
     }
 
-    fn parse_expression(&mut self) -> Result<Expr, String> {
+    fn parse_expression(&mut self) -> Result<Expr, CompileError> {
         self.parse_expr()
     }
 
-    fn parse_expr(&mut self) -> Result<Expr, String> {
+    fn parse_expr(&mut self) -> Result<Expr, CompileError> {
         self.parse_logical_or()
     }
 
-    fn parse_logical_or(&mut self) -> Result<Expr, String> {
+    /// A '{' after the head of if, while, for and match starts the body, not a struct literal
+    fn parse_head(&mut self) -> Result<Expr, CompileError> {
+        self.with_struct_literals(false, Self::parse_expr)
+    }
+
+    /// Parentheses and blocks allow struct literals again
+    fn with_struct_literals<T>(
+        &mut self,
+        allowed: bool,
+        parse: impl FnOnce(&mut Self) -> Result<T, CompileError>,
+    ) -> Result<T, CompileError> {
+        let outer = mem::replace(&mut self.struct_literals, allowed);
+        let result = parse(self);
+        self.struct_literals = outer;
+        result
+    }
+
+    /// Next token after the left operand of a binary operator, a statement that
+    /// consumed its ';' is not an operand, so `let a: num = 1; *p = a;` is two statements
+    fn peek_infix(&mut self, left: &Expr) -> Option<&Token> {
+        if consumes_semicolon(left) {
+            return None;
+        }
+        self.tokens.peek()
+    }
+
+    fn parse_logical_or(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
         let mut expr = self.parse_logical_and()?;
 
-        while let Some(Token::OpOr) = self.tokens.peek() {
+        while let Some(Token::OpOr) = self.peek_infix(&expr) {
+            let op_span = self.tokens.span(self.tokens.position);
             self.tokens.next(); // Consume ||
             let right = self.parse_logical_and()?;
-            expr = Expr::Binary {
-                left: Box::new(expr),
-                op: BinaryOp::Or,
-                right: Box::new(right),
-            };
+            expr = self.node(
+                start,
+                ExprKind::Binary {
+                    left: Box::new(expr),
+                    op: BinaryOp::Or,
+                    op_span,
+                    right: Box::new(right),
+                },
+            );
         }
 
         Ok(expr)
     }
 
-    fn parse_logical_and(&mut self) -> Result<Expr, String> {
+    fn parse_logical_and(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
         let mut expr = self.parse_equality()?;
 
-        while let Some(Token::OpAnd) = self.tokens.peek() {
+        while let Some(Token::OpAnd) = self.peek_infix(&expr) {
+            let op_span = self.tokens.span(self.tokens.position);
             self.tokens.next(); // Consume &&
             let right = self.parse_equality()?;
-            expr = Expr::Binary {
-                left: Box::new(expr),
-                op: BinaryOp::And,
-                right: Box::new(right),
-            };
+            expr = self.node(
+                start,
+                ExprKind::Binary {
+                    left: Box::new(expr),
+                    op: BinaryOp::And,
+                    op_span,
+                    right: Box::new(right),
+                },
+            );
         }
 
         Ok(expr)
     }
 
-    fn parse_equality(&mut self) -> Result<Expr, String> {
+    fn parse_equality(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
         let mut expr = self.parse_comparison()?;
 
         loop {
-            let op = match self.tokens.peek() {
+            let op = match self.peek_infix(&expr) {
                 Some(Token::OpEq) => BinaryOp::Eq,
                 Some(Token::OpNeq) => BinaryOp::Neq,
                 _ => break,
             };
+            let op_span = self.tokens.span(self.tokens.position);
             self.tokens.next(); // Consume operator == / !=
             let right = self.parse_comparison()?;
-            expr = Expr::Binary {
-                left: Box::new(expr),
-                op,
-                right: Box::new(right),
-            };
+            expr = self.node(
+                start,
+                ExprKind::Binary {
+                    left: Box::new(expr),
+                    op,
+                    op_span,
+                    right: Box::new(right),
+                },
+            );
         }
 
         Ok(expr)
     }
 
-    fn parse_comparison(&mut self) -> Result<Expr, String> {
-        let mut expr = self.parse_addition()?;
+    fn parse_comparison(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
+        let mut expr = self.parse_bitwise_or()?;
 
         loop {
-            let op = match self.tokens.peek() {
+            let op = match self.peek_infix(&expr) {
                 Some(Token::OpLt) => BinaryOp::Lt,
                 Some(Token::OpGt) => BinaryOp::Gt,
                 Some(Token::OpLe) => BinaryOp::Le,
                 Some(Token::OpGe) => BinaryOp::Ge,
                 _ => break,
             };
+            let op_span = self.tokens.span(self.tokens.position);
             self.tokens.next(); // Consume operator of comparison
-            let right = self.parse_addition()?;
-            expr = Expr::Binary {
-                left: Box::new(expr),
-                op,
-                right: Box::new(right),
+            let right = self.parse_bitwise_or()?;
+            expr = self.node(
+                start,
+                ExprKind::Binary {
+                    left: Box::new(expr),
+                    op,
+                    op_span,
+                    right: Box::new(right),
+                },
+            );
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_bitwise_or(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
+        let mut expr = self.parse_bitwise_xor()?;
+
+        while let Some(Token::OpBitOr) = self.peek_infix(&expr) {
+            let op_span = self.tokens.span(self.tokens.position);
+            self.tokens.next(); // Consume |
+            let right = self.parse_bitwise_xor()?;
+            expr = self.node(
+                start,
+                ExprKind::Binary {
+                    left: Box::new(expr),
+                    op: BinaryOp::BitOr,
+                    op_span,
+                    right: Box::new(right),
+                },
+            );
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_bitwise_xor(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
+        let mut expr = self.parse_bitwise_and()?;
+
+        while let Some(Token::OpBitXor) = self.peek_infix(&expr) {
+            let op_span = self.tokens.span(self.tokens.position);
+            self.tokens.next(); // Consume ^
+            let right = self.parse_bitwise_and()?;
+            expr = self.node(
+                start,
+                ExprKind::Binary {
+                    left: Box::new(expr),
+                    op: BinaryOp::BitXor,
+                    op_span,
+                    right: Box::new(right),
+                },
+            );
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_bitwise_and(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
+        let mut expr = self.parse_shift()?;
+
+        while let Some(Token::OpBitAnd) = self.peek_infix(&expr) {
+            let op_span = self.tokens.span(self.tokens.position);
+            self.tokens.next(); // Consume &
+            let right = self.parse_shift()?;
+            expr = self.node(
+                start,
+                ExprKind::Binary {
+                    left: Box::new(expr),
+                    op: BinaryOp::BitAnd,
+                    op_span,
+                    right: Box::new(right),
+                },
+            );
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_shift(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
+        let mut expr = self.parse_addition()?;
+
+        loop {
+            let op = match self.peek_infix(&expr) {
+                Some(Token::OpShl) => BinaryOp::Shl,
+                Some(Token::OpShr) => BinaryOp::Shr,
+                Some(Token::OpUshr) => BinaryOp::Ushr,
+                _ => break,
             };
+            let op_span = self.tokens.span(self.tokens.position);
+            self.tokens.next(); // Consume operator << / >> / >>>
+            let right = self.parse_addition()?;
+            expr = self.node(
+                start,
+                ExprKind::Binary {
+                    left: Box::new(expr),
+                    op,
+                    op_span,
+                    right: Box::new(right),
+                },
+            );
         }
 
         Ok(expr)
     }
 
-    fn parse_addition(&mut self) -> Result<Expr, String> {
+    fn parse_addition(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
         let mut expr = self.parse_multiplication()?;
 
         loop {
-            let op = match self.tokens.peek() {
+            let op = match self.peek_infix(&expr) {
                 Some(Token::OpAdd) => BinaryOp::Add,
                 Some(Token::OpSub) => BinaryOp::Sub,
                 _ => break,
             };
+            let op_span = self.tokens.span(self.tokens.position);
             self.tokens.next(); // Consume operator + / -
             let right = self.parse_multiplication()?;
-            expr = Expr::Binary {
-                left: Box::new(expr),
-                op,
-                right: Box::new(right),
-            };
+            expr = self.node(
+                start,
+                ExprKind::Binary {
+                    left: Box::new(expr),
+                    op,
+                    op_span,
+                    right: Box::new(right),
+                },
+            );
         }
 
         Ok(expr)
     }
 
-    fn parse_multiplication(&mut self) -> Result<Expr, String> {
-        let mut expr = self.parse_unary()?;
+    fn parse_multiplication(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
+        let mut expr = self.parse_cast()?;
 
         loop {
-            let op = match self.tokens.peek() {
+            let op = match self.peek_infix(&expr) {
                 Some(Token::OpMul) => BinaryOp::Mul,
                 Some(Token::OpDiv) => BinaryOp::Div,
                 Some(Token::OpMod) => BinaryOp::Mod,
                 _ => break,
             };
+            let op_span = self.tokens.span(self.tokens.position);
             self.tokens.next(); // Consume operator * / /
-            let right = self.parse_unary()?;
-            expr = Expr::Binary {
-                left: Box::new(expr),
-                op,
-                right: Box::new(right),
-            };
+            let right = self.parse_cast()?;
+            expr = self.node(
+                start,
+                ExprKind::Binary {
+                    left: Box::new(expr),
+                    op,
+                    op_span,
+                    right: Box::new(right),
+                },
+            );
+        }
+
+        Ok(expr)
+    }
+
+    /// `as` binds tighter than binary operators but looser than unary ones
+    fn parse_cast(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
+        let mut expr = self.parse_unary()?;
+
+        while let Some(Token::KwAs) = self.peek_infix(&expr) {
+            self.tokens.next(); // Consume as
+            let to = self.parse_type()?;
+            expr = self.node(
+                start,
+                ExprKind::Cast {
+                    expr: Box::new(expr),
+                    to,
+                },
+            );
         }
 
         Ok(expr)
     }
 
-    fn parse_unary(&mut self) -> Result<Expr, String> {
+    fn parse_unary(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
         if let Some(Token::OpNot) = self.tokens.peek() {
             self.tokens.next(); // Consume !
             let expr = self.parse_unary()?;
-            return Ok(Expr::Unary {
-                op: UnaryOp::Not,
-                expr: Box::new(expr),
-            });
+            return Ok(self.node(
+                start,
+                ExprKind::Unary {
+                    op: UnaryOp::Not,
+                    expr: Box::new(expr),
+                },
+            ));
         }
 
         if let Some(Token::OpSub) = self.tokens.peek() {
             self.tokens.next(); // Consume -
             let expr = self.parse_unary()?;
-            return Ok(Expr::Unary {
-                op: UnaryOp::Neg,
-                expr: Box::new(expr),
-            });
+            return Ok(self.node(
+                start,
+                ExprKind::Unary {
+                    op: UnaryOp::Neg,
+                    expr: Box::new(expr),
+                },
+            ));
+        }
+
+        if let Some(Token::OpBitNot) = self.tokens.peek() {
+            self.tokens.next(); // Consume ~
+            let expr = self.parse_unary()?;
+            return Ok(self.node(
+                start,
+                ExprKind::Unary {
+                    op: UnaryOp::BitNot,
+                    expr: Box::new(expr),
+                },
+            ));
+        }
+
+        if let Some(Token::OpBitAnd) = self.tokens.peek() {
+            self.tokens.next(); // Consume &
+            let mutable = self.tokens.next_if_eq(&Token::KwMut).is_some();
+            let expr = self.parse_unary()?;
+            return Ok(self.node(
+                start,
+                ExprKind::Ref {
+                    mutable,
+                    expr: Box::new(expr),
+                },
+            ));
+        }
+
+        // `*p = x` assigns through the reference, the assignment isn't part of the operand
+        if let Some(Token::OpMul) = self.tokens.peek() {
+            let expr = self.parse_deref()?;
+            return self.parse_assignment(start, expr);
         }
 
         self.parse_kw_or_expr()
     }
 
-    fn parse_kw_or_expr(&mut self) -> Result<Expr, String> {
-        match self.tokens.peek() {
-            Some(Token::KwLet) => self.parse_let(),
-            Some(Token::KwIf) => self.parse_if(),
-            Some(Token::KwWhile) => self.parse_while(),
-            _ => self.parse_simple_expr_or_literal(),
-        }
+    fn parse_kw_or_expr(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
+        let kind = match self.tokens.peek() {
+            Some(Token::KwLet) => self.parse_let()?,
+            Some(Token::KwConst) => self.parse_const()?,
+            Some(Token::KwImport) => self.parse_import()?,
+            Some(Token::KwMod) => self.parse_mod()?,
+            Some(Token::KwIf) => return self.parse_if(),
+            Some(Token::KwMatch) => self.parse_match()?,
+            Some(Token::KwWhile) => self.parse_while()?,
+            Some(Token::KwFor) => self.parse_for()?,
+            Some(Token::KwFn) => self.parse_fn()?,
+            Some(Token::KwStruct) => self.parse_struct()?,
+            Some(Token::KwEnum) => self.parse_enum()?,
+            Some(Token::KwReturn) => self.parse_return()?,
+            Some(Token::KwBreak) => self.parse_loop_jump(ExprKind::Break)?,
+            Some(Token::KwContinue) => self.parse_loop_jump(ExprKind::Continue)?,
+            Some(Token::LBrace) => {
+                self.tokens.next(); // Consume {
+                ExprKind::Block(self.parse_block()?)
+            }
+            _ => return self.parse_simple_expr_or_literal(),
+        };
+        Ok(self.node(start, kind))
+    }
+
+    fn parse_simple_expr_or_literal(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
+        let expr = self.parse_postfix()?;
+        self.parse_assignment(start, expr)
+    }
+
+    /// `*` followed by a postfix expression or another dereference
+    fn parse_deref(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
+        self.tokens.next(); // Consume *
+        let pointer = match self.tokens.peek() {
+            Some(Token::OpMul) => self.parse_deref()?,
+            _ => self.parse_postfix()?,
+        };
+        Ok(self.node(start, ExprKind::Deref(Box::new(pointer))))
     }
-    fn parse_simple_expr_or_literal(&mut self) -> Result<Expr, String> {
+
+    /// Parses the rest of an assignment if `expr`, which starts at the token `start`,
+    /// is followed by `=` or `op=`
+    fn parse_assignment(&mut self, start: usize, expr: Expr) -> Result<Expr, CompileError> {
+        let op = match self.tokens.peek() {
+            Some(Token::Assign) => None,
+            Some(token) if compound_operator(token).is_some() => compound_operator(token),
+            _ => return Ok(expr),
+        };
+        if !expr.is_place() {
+            return Err(self.error(
+                ErrorKind::InvalidPlace,
+                "Invalid left-hand side of assignment",
+            ));
+        }
+        self.tokens.next(); // Consume '=' or 'op='
+        let value = self.parse_expr()?;
+
         match self.tokens.next() {
+            Some(Token::Semicolon) => (),
+            _ => return Err(self.syntax_error("Expected ';' at the end of statement")),
+        }
+
+        let (target, expr) = (Box::new(expr), Box::new(value));
+        let kind = match op {
+            Some(op) => ExprKind::CompoundAssign { target, op, expr },
+            None => ExprKind::Assign { target, expr },
+        };
+        Ok(self.node(start, kind))
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
+        let mut expr = self.parse_primary()?;
+
+        loop {
+            match self.tokens.peek() {
+                Some(Token::LBracket) => {
+                    self.tokens.next(); // Consume [
+                    let index = self.with_struct_literals(true, Self::parse_expr)?;
+                    match self.tokens.next() {
+                        Some(Token::RBracket) => (),
+                        _ => return Err(self.syntax_error("Expected ']' after index")),
+                    }
+                    expr = self.node(
+                        start,
+                        ExprKind::Index {
+                            array: Box::new(expr),
+                            index: Box::new(index),
+                        },
+                    );
+                }
+                Some(Token::Dot) => {
+                    self.tokens.next(); // Consume .
+                    let Some(Token::Identifier(field)) = self.tokens.next() else {
+                        return Err(self.syntax_error("Expected field name after '.'"));
+                    };
+                    expr = self.node(
+                        start,
+                        ExprKind::Field {
+                            base: Box::new(expr),
+                            field,
+                        },
+                    );
+                }
+                _ => break,
+            }
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
+        let kind = match self.tokens.next() {
             Some(Token::LParen) => {
-                let expr = self.parse_expr()?;
-                match self.tokens.next() {
+                let expr = self.with_struct_literals(true, Self::parse_expr)?;
+                return match self.tokens.next() {
                     Some(Token::RParen) => Ok(expr),
-                    _ => Err("Expected ')'".to_string()),
+                    _ => Err(self.syntax_error("Expected ')'")),
+                };
+            }
+            Some(Token::LBracket) => self.parse_array_literal()?,
+            Some(Token::LitNumber(n)) => ExprKind::Number(n),
+            Some(Token::LitBool(v)) => ExprKind::Bool(v),
+            Some(Token::LitString(s)) => ExprKind::Str(s),
+            Some(Token::Identifier(name))
+                if self.enums.contains(&name) && self.tokens.peek() == Some(&Token::PathSep) =>
+            {
+                self.parse_variant(name)?
+            }
+            // Item of a module: `util::abs(x)`, `util::LIMIT`, `util::Point { .. }`
+            // or `util::Shape::Dot`
+            Some(Token::Identifier(module)) if self.tokens.peek() == Some(&Token::PathSep) => {
+                let name = self.parse_qualified_name(&module)?;
+                match self.tokens.peek() {
+                    Some(Token::LParen) => {
+                        self.tokens.next(); // Consume (
+                        let args = self.with_struct_literals(true, Self::parse_call_args)?;
+                        ExprKind::Call { name, args }
+                    }
+                    Some(Token::PathSep) => self.parse_variant(name)?,
+                    Some(Token::LBrace) if self.struct_literals => {
+                        self.parse_struct_literal(name)?
+                    }
+                    _ => ExprKind::Var(name),
                 }
             }
-            Some(Token::LitNumber(n)) => Ok(Expr::Number(n)),
-            Some(Token::LitBool(v)) => Ok(Expr::Bool(v)),
-            Some(Token::Identifier(name)) => {
-                if let Some(Token::Assign) = self.tokens.peek() {
-                    self.tokens.next(); // Consume '='
-                    let expr = self.parse_expr()?;
+            Some(Token::Identifier(name)) => match self.tokens.peek() {
+                Some(Token::LParen) => {
+                    self.tokens.next(); // Consume (
+                    let args = self.with_struct_literals(true, Self::parse_call_args)?;
+                    ExprKind::Call { name, args }
+                }
+                Some(Token::LBrace) if self.struct_literals => self.parse_struct_literal(name)?,
+                _ => ExprKind::Var(name),
+            },
+            Some(tok) => return Err(self.syntax_error(&format!("Unexpected token {:?}", tok))),
+            None => return Err(self.error(ErrorKind::UnexpectedEof, "Unexpected EOF")),
+        };
+        Ok(self.node(start, kind))
+    }
 
-                    match self.tokens.next() {
-                        Some(Token::Semicolon) => (),
-                        _ => return Err("Expected ';' at the end of statement".to_string()),
-                    }
+    /// Parses `:: Variant` and the arguments of the variant after the name of its enum
+    fn parse_variant(&mut self, enum_name: String) -> Result<ExprKind, CompileError> {
+        let variant = self.parse_variant_name(&enum_name)?;
+        let args = match self.tokens.peek() {
+            Some(Token::LParen) => {
+                self.tokens.next(); // Consume (
+                self.with_struct_literals(true, Self::parse_call_args)?
+            }
+            _ => vec![],
+        };
+        Ok(ExprKind::Variant {
+            enum_name,
+            variant,
+            args,
+        })
+    }
 
-                    Ok(Expr::Assign {
-                        name,
-                        expr: Box::new(expr),
-                    })
-                } else {
-                    Ok(Expr::Var(name))
+    /// Parses `:: name` after the name of a module
+    fn parse_qualified_name(&mut self, module: &str) -> Result<String, CompileError> {
+        self.tokens.next(); // Consume ::
+        match self.tokens.next() {
+            Some(Token::Identifier(name)) => Ok(format!("{}::{}", module, name)),
+            _ => Err(self.syntax_error(&format!("Expected a name after '{}::'", module))),
+        }
+    }
+
+    fn parse_array_literal(&mut self) -> Result<ExprKind, CompileError> {
+        let mut elements = Vec::new();
+        if let Some(Token::RBracket) = self.tokens.peek() {
+            self.tokens.next(); // Consume ]
+            return Ok(ExprKind::Array(elements));
+        }
+
+        let first = self.parse_expr()?;
+        if let Some(Token::Semicolon) = self.tokens.peek() {
+            self.tokens.next(); // Consume ;
+            let count = self.parse_array_length()?;
+            return Ok(ExprKind::ArrayRepeat {
+                value: Box::new(first),
+                count,
+            });
+        }
+
+        elements.push(first);
+        loop {
+            match self.tokens.next() {
+                Some(Token::Comma) => elements.push(self.parse_expr()?),
+                Some(Token::RBracket) => break,
+                _ => return Err(self.syntax_error("Expected ',' or ']' in array literal")),
+            }
+        }
+        Ok(ExprKind::Array(elements))
+    }
+
+    /// Parses `<number> "]"`
+    fn parse_array_length(&mut self) -> Result<usize, CompileError> {
+        let len = match self.tokens.next() {
+            Some(Token::LitNumber(n)) => n as usize,
+            _ => return Err(self.syntax_error("Expected array length")),
+        };
+        match self.tokens.next() {
+            Some(Token::RBracket) => Ok(len),
+            _ => Err(self.syntax_error("Expected ']' after array length")),
+        }
+    }
+
+    fn parse_call_args(&mut self) -> Result<Vec<Expr>, CompileError> {
+        let mut args = Vec::new();
+        if let Some(Token::RParen) = self.tokens.peek() {
+            self.tokens.next(); // Consume )
+            return Ok(args);
+        }
+        loop {
+            args.push(self.parse_expr()?);
+            match self.tokens.next() {
+                Some(Token::Comma) => (),
+                Some(Token::RParen) => break,
+                _ => return Err(self.syntax_error("Expected ',' or ')' in argument list")),
+            }
+        }
+        Ok(args)
+    }
+
+    fn parse_type(&mut self) -> Result<VarType, CompileError> {
+        match self.tokens.next() {
+            Some(Token::TypeNumber) => Ok(VarType::NUM),
+            Some(Token::OpBitAnd) => {
+                let mutable = self.tokens.next_if_eq(&Token::KwMut).is_some();
+                Ok(VarType::Ref(Box::new(self.parse_type()?), mutable))
+            }
+            // `&&T` is lexed as the logical and operator
+            Some(Token::OpAnd) => {
+                let mutable = self.tokens.next_if_eq(&Token::KwMut).is_some();
+                let inner = VarType::Ref(Box::new(self.parse_type()?), mutable);
+                Ok(VarType::Ref(Box::new(inner), false))
+            }
+            Some(Token::TypeBool) => Ok(VarType::Bool),
+            // The module loader tells the structs and enums of other modules apart
+            Some(Token::Identifier(module)) if self.tokens.peek() == Some(&Token::PathSep) => {
+                Ok(VarType::Struct(self.parse_qualified_name(&module)?))
+            }
+            // Integer type names are not keywords and stay valid identifiers
+            Some(Token::Identifier(name)) => match IntType::from_name(&name) {
+                Some(int_type) => Ok(VarType::Int(int_type)),
+                None if self.enums.contains(&name) => Ok(VarType::Enum(name)),
+                None => Ok(VarType::Struct(name)),
+            },
+            Some(Token::LBracket) => {
+                let element = self.parse_type()?;
+                match self.tokens.next() {
+                    Some(Token::Semicolon) => (),
+                    _ => return Err(self.syntax_error("Expected ';' in array type")),
                 }
+                let len = self.parse_array_length()?;
+                Ok(VarType::Array(Box::new(element), len))
             }
-            Some(tok) => Err(format!("Unexpected token {:?}", tok)),
-            None => Err("Unexpected EOF".to_string()),
+            _ => Err(self.syntax_error("Expected type")),
+        }
+    }
+
+    /// The module is named after the file: `import "lib/util.fr";` gives `util`
+    fn parse_import(&mut self) -> Result<ExprKind, CompileError> {
+        self.tokens.next(); // Consume import
+
+        let path = match self.tokens.next() {
+            Some(Token::LitString(path)) => path,
+            _ => return Err(self.syntax_error("Expected file name after 'import'")),
+        };
+        let name = Path::new(&path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .filter(|stem| is_identifier(stem))
+            .ok_or_else(|| {
+                let message = format!("Module name of \"{}\" is not an identifier", path);
+                self.error(ErrorKind::Module, &message)
+            })?
+            .to_string();
+        self.expect_semicolon()?;
+
+        Ok(ExprKind::Import { name, path })
+    }
+
+    fn parse_mod(&mut self) -> Result<ExprKind, CompileError> {
+        self.tokens.next(); // Consume mod
+
+        let name = match self.tokens.next() {
+            Some(Token::Identifier(name)) => name,
+            _ => return Err(self.syntax_error("Expected identifier after 'mod'")),
+        };
+        self.expect_semicolon()?;
+
+        Ok(ExprKind::Import {
+            path: format!("{}.fr", name),
+            name,
+        })
+    }
+
+    fn expect_semicolon(&mut self) -> Result<(), CompileError> {
+        match self.tokens.next() {
+            Some(Token::Semicolon) => Ok(()),
+            _ => Err(self.syntax_error("Expected ';' at the end of statement")),
         }
     }
 
-    fn parse_let(&mut self) -> Result<Expr, String> {
-        self.tokens.next(); // Consume let
+    fn parse_let(&mut self) -> Result<ExprKind, CompileError> {
+        let (name, name_span, var_type, expr) = self.parse_declaration("let")?;
+        Ok(ExprKind::Let {
+            name,
+            name_span,
+            var_type,
+            expr: Box::new(expr),
+        })
+    }
+
+    fn parse_const(&mut self) -> Result<ExprKind, CompileError> {
+        let (name, _, var_type, expr) = self.parse_declaration("const")?;
+        Ok(ExprKind::Const {
+            name,
+            var_type,
+            expr: Box::new(expr),
+        })
+    }
+
+    /// `keyword name: type = expr;`
+    fn parse_declaration(
+        &mut self,
+        keyword: &str,
+    ) -> Result<(String, Span, VarType, Expr), CompileError> {
+        self.tokens.next(); // Consume keyword
 
         let name = match self.tokens.next() {
             Some(Token::Identifier(name)) => name,
-            _ => return Err("Expected identifier after 'let'".to_string()),
+            _ => return Err(self.syntax_error(&format!("Expected identifier after '{}'", keyword))),
         };
+        let name_span = self.tokens.previous();
 
         match self.tokens.next() {
             Some(Token::Colon) => (),
-            _ => return Err("Expected ':' after identifier".to_string()),
+            _ => return Err(self.syntax_error("Expected ':' after identifier")),
         }
-        let var_type = match self.tokens.next() {
-            Some(Token::TypeNumber) => VarType::Number,
-            Some(Token::TypeBool) => VarType::Bool,
-            _ => return Err("Expected type ':'".to_string()),
-        };
+        let var_type = self.parse_type()?;
 
         match self.tokens.next() {
             Some(Token::Assign) => (),
-            _ => return Err("Expected operator '=' after type".to_string()),
+            _ => return Err(self.syntax_error("Expected operator '=' after type")),
         }
 
         let expr = self.parse_expr()?;
 
         match self.tokens.next() {
             Some(Token::Semicolon) => (),
-            _ => return Err("Expected ';' at the end of statement".to_string()),
+            _ => return Err(self.syntax_error("Expected ';' at the end of statement")),
         }
 
-        Ok(Expr::Let {
-            name,
-            var_type,
-            expr: Box::new(expr),
-        })
+        Ok((name, name_span, var_type, expr))
     }
 
-    fn parse_if(&mut self) -> Result<Expr, String> {
+    fn parse_if(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
         self.tokens.next(); // Consume if
 
-        let condition = self.parse_expr()?;
+        let condition = self.parse_head()?;
 
         match self.tokens.next() {
             Some(Token::LBrace) => (),
-            _ => return Err("Expected '{' after 'if'".to_string()),
+            _ => return Err(self.syntax_error("Expected '{' after 'if'")),
         }
 
         let then_branch = self.parse_block()?;
@@ -270,51 +969,621 @@ impl<'a> Parser<'a> {
         let else_branch = if let Some(Token::KwElse) = self.tokens.peek() {
             self.tokens.next(); // Consume else
 
-            match self.tokens.next() {
-                Some(Token::LBrace) => (),
-                _ => return Err("Expected '{' after 'else'".to_string()),
+            match self.tokens.peek() {
+                // `else if` is sugar for an else block holding just the nested if
+                Some(Token::KwIf) => Some(Block {
+                    statements: Vec::new(),
+                    value: Some(Box::new(self.parse_if()?)),
+                }),
+                Some(Token::LBrace) => {
+                    self.tokens.next(); // Consume {
+                    Some(self.parse_block()?)
+                }
+                _ => return Err(self.syntax_error("Expected '{' after 'else'")),
             }
-
-            Some(self.parse_block()?)
         } else {
             None
         };
 
-        Ok(Expr::If {
+        let kind = ExprKind::If {
             condition: Box::new(condition),
             then_branch,
             else_branch,
+        };
+        Ok(self.node(start, kind))
+    }
+
+    fn parse_match(&mut self) -> Result<ExprKind, CompileError> {
+        self.tokens.next(); // Consume match
+
+        let scrutinee = self.parse_head()?;
+
+        match self.tokens.next() {
+            Some(Token::LBrace) => (),
+            _ => return Err(self.syntax_error("Expected '{' after 'match' value")),
+        }
+
+        let mut arms = Vec::new();
+        loop {
+            if let Some(Token::RBrace) = self.tokens.peek() {
+                self.tokens.next(); // Consume }
+                break;
+            }
+
+            let mut patterns = vec![self.parse_pattern()?];
+            while let Some(Token::OpBitOr) = self.tokens.peek() {
+                self.tokens.next(); // Consume |
+                patterns.push(self.parse_pattern()?);
+            }
+
+            match self.tokens.next() {
+                Some(Token::FatArrow) => (),
+                _ => return Err(self.syntax_error("Expected '=>' after match pattern")),
+            }
+
+            // A block arm doesn't need a ',' after it
+            let (body, is_block) = match self.tokens.peek() {
+                Some(Token::LBrace) => {
+                    self.tokens.next(); // Consume {
+                    (self.parse_block()?, true)
+                }
+                _ => {
+                    let value = self.parse_expr()?;
+                    let body = Block {
+                        statements: Vec::new(),
+                        value: Some(Box::new(value)),
+                    };
+                    (body, false)
+                }
+            };
+            arms.push(MatchArm { patterns, body });
+
+            match self.tokens.peek() {
+                Some(Token::Comma) => {
+                    self.tokens.next(); // Consume ,
+                }
+                Some(Token::RBrace) => (),
+                _ if is_block => (),
+                _ => return Err(self.syntax_error("Expected ',' after match arm")),
+            }
+        }
+
+        Ok(ExprKind::Match {
+            scrutinee: Box::new(scrutinee),
+            arms,
+        })
+    }
+
+    fn parse_pattern(&mut self) -> Result<Pattern, CompileError> {
+        if let Some(Token::Identifier(name)) = self.tokens.peek().cloned() {
+            if name == "_" {
+                self.tokens.next(); // Consume _
+                return Ok(Pattern::Wildcard);
+            }
+            if self.enums.contains(&name) {
+                self.tokens.next(); // Consume the enum name
+                return self.parse_variant_pattern(name);
+            }
+            // `module::Enum::Variant`
+            if self.tokens.peek_nth(1) == Some(&Token::PathSep)
+                && self.tokens.peek_nth(3) == Some(&Token::PathSep)
+            {
+                self.tokens.next(); // Consume the module name
+                let enum_name = self.parse_qualified_name(&name)?;
+                return self.parse_variant_pattern(enum_name);
+            }
+        }
+
+        let value = self.parse_pattern_value()?;
+        match self.tokens.peek() {
+            Some(Token::DotDotEq) => {
+                self.tokens.next(); // Consume ..=
+                Ok(Pattern::Range(value, self.parse_pattern_value()?))
+            }
+            Some(Token::DotDot) => {
+                Err(self.syntax_error("Range patterns must include their end, use '..='"))
+            }
+            _ => Ok(Pattern::Value(value)),
+        }
+    }
+
+    fn parse_pattern_value(&mut self) -> Result<Expr, CompileError> {
+        let start = self.tokens.position;
+        let kind = match self.tokens.next() {
+            Some(Token::LitNumber(n)) => ExprKind::Number(n),
+            Some(Token::OpSub) => match self.tokens.next() {
+                Some(Token::LitNumber(n)) => ExprKind::Unary {
+                    op: UnaryOp::Neg,
+                    expr: Box::new(self.node(start + 1, ExprKind::Number(n))),
+                },
+                _ => return Err(self.syntax_error("Expected a number after '-' in pattern")),
+            },
+            Some(Token::LitBool(b)) => ExprKind::Bool(b),
+            Some(Token::Identifier(module)) if self.tokens.peek() == Some(&Token::PathSep) => {
+                ExprKind::Var(self.parse_qualified_name(&module)?)
+            }
+            Some(Token::Identifier(name)) => ExprKind::Var(name),
+            _ => return Err(self.syntax_error("Expected a literal, a constant or '_' in pattern")),
+        };
+        Ok(self.node(start, kind))
+    }
+
+    /// `::Variant` after the name of the enum, followed by the names bound to its fields
+    fn parse_variant_pattern(&mut self, enum_name: String) -> Result<Pattern, CompileError> {
+        let variant = self.parse_variant_name(&enum_name)?;
+        let mut bindings = Vec::new();
+        if let Some(Token::LParen) = self.tokens.peek() {
+            self.tokens.next(); // Consume (
+            loop {
+                match self.tokens.next() {
+                    Some(Token::Identifier(name)) if name == "_" => bindings.push(None),
+                    Some(Token::Identifier(name)) => bindings.push(Some(Binding {
+                        name,
+                        span: self.tokens.previous(),
+                    })),
+                    Some(Token::RParen) if bindings.is_empty() => break,
+                    _ => {
+                        return Err(self.syntax_error(&format!(
+                            "Expected a name or '_' in {}::{} pattern",
+                            enum_name, variant
+                        )))
+                    }
+                }
+                match self.tokens.next() {
+                    Some(Token::Comma) => (),
+                    Some(Token::RParen) => break,
+                    _ => {
+                        return Err(self.syntax_error(&format!(
+                            "Expected ',' or ')' in {}::{} pattern",
+                            enum_name, variant
+                        )))
+                    }
+                }
+            }
+        }
+        Ok(Pattern::Variant {
+            enum_name,
+            variant,
+            bindings,
         })
     }
 
-    fn parse_while(&mut self) -> Result<Expr, String> {
+    /// Parses `:: Variant` after the name of an enum
+    fn parse_variant_name(&mut self, enum_name: &str) -> Result<String, CompileError> {
+        match self.tokens.next() {
+            Some(Token::PathSep) => (),
+            _ => return Err(self.syntax_error(&format!("Expected '::' after enum {}", enum_name))),
+        }
+        match self.tokens.next() {
+            Some(Token::Identifier(variant)) => Ok(variant),
+            _ => Err(self.syntax_error(&format!("Expected variant name after '{}::'", enum_name))),
+        }
+    }
+
+    fn parse_enum(&mut self) -> Result<ExprKind, CompileError> {
+        self.tokens.next(); // Consume enum
+
+        let name = match self.tokens.next() {
+            Some(Token::Identifier(name)) => name,
+            _ => return Err(self.syntax_error("Expected enum name after 'enum'")),
+        };
+
+        match self.tokens.next() {
+            Some(Token::LBrace) => (),
+            _ => return Err(self.syntax_error("Expected '{' after enum name")),
+        }
+
+        let mut variants = Vec::new();
+        loop {
+            let variant = match self.tokens.next() {
+                Some(Token::RBrace) => break,
+                Some(Token::Identifier(variant)) => variant,
+                _ => return Err(self.syntax_error("Expected variant name in enum declaration")),
+            };
+            let mut fields = Vec::new();
+            if let Some(Token::LParen) = self.tokens.peek() {
+                self.tokens.next(); // Consume (
+                loop {
+                    fields.push(self.parse_type()?);
+                    match self.tokens.next() {
+                        Some(Token::Comma) => (),
+                        Some(Token::RParen) => break,
+                        _ => {
+                            return Err(self.syntax_error(&format!(
+                                "Expected ',' or ')' in fields of variant {}",
+                                variant
+                            )))
+                        }
+                    }
+                }
+            }
+            variants.push(EnumVariant {
+                name: variant,
+                fields,
+            });
+
+            match self.tokens.next() {
+                Some(Token::Comma) => (),
+                Some(Token::RBrace) => break,
+                _ => return Err(self.syntax_error("Expected ',' or '}' in enum declaration")),
+            }
+        }
+
+        Ok(ExprKind::Enum { name, variants })
+    }
+
+    fn parse_struct(&mut self) -> Result<ExprKind, CompileError> {
+        self.tokens.next(); // Consume struct
+
+        let name = match self.tokens.next() {
+            Some(Token::Identifier(name)) => name,
+            _ => return Err(self.syntax_error("Expected struct name after 'struct'")),
+        };
+
+        match self.tokens.next() {
+            Some(Token::LBrace) => (),
+            _ => return Err(self.syntax_error("Expected '{' after struct name")),
+        }
+
+        let mut fields = Vec::new();
+        loop {
+            let start = self.tokens.position;
+            let name = match self.tokens.next() {
+                Some(Token::RBrace) => break,
+                Some(Token::Identifier(name)) => name,
+                _ => return Err(self.syntax_error("Expected field name in struct declaration")),
+            };
+            match self.tokens.next() {
+                Some(Token::Colon) => (),
+                _ => return Err(self.syntax_error("Expected ':' after field name")),
+            }
+            let var_type = self.parse_type()?;
+            fields.push(Param {
+                name,
+                var_type,
+                span: self.tokens.span_from(start),
+            });
+
+            match self.tokens.next() {
+                Some(Token::Comma) => (),
+                Some(Token::RBrace) => break,
+                _ => return Err(self.syntax_error("Expected ',' or '}' in struct declaration")),
+            }
+        }
+
+        Ok(ExprKind::Struct { name, fields })
+    }
+
+    /// Parses the rest of `Name { field: value, ... }` after the name
+    fn parse_struct_literal(&mut self, name: String) -> Result<ExprKind, CompileError> {
+        self.tokens.next(); // Consume {
+
+        let mut fields = Vec::new();
+        loop {
+            let field = match self.tokens.next() {
+                Some(Token::RBrace) => break,
+                Some(Token::Identifier(field)) => field,
+                _ => {
+                    return Err(
+                        self.syntax_error(&format!("Expected field name in {} literal", name))
+                    )
+                }
+            };
+            match self.tokens.next() {
+                Some(Token::Colon) => (),
+                _ => return Err(self.syntax_error("Expected ':' after field name")),
+            }
+            fields.push((field, self.parse_expr()?));
+
+            match self.tokens.next() {
+                Some(Token::Comma) => (),
+                Some(Token::RBrace) => break,
+                _ => {
+                    return Err(
+                        self.syntax_error(&format!("Expected ',' or '}}' in {} literal", name))
+                    )
+                }
+            }
+        }
+
+        Ok(ExprKind::StructLiteral { name, fields })
+    }
+
+    fn parse_while(&mut self) -> Result<ExprKind, CompileError> {
         self.tokens.next(); // Consume while
 
-        let condition = self.parse_expr()?;
+        let condition = self.parse_head()?;
 
         match self.tokens.next() {
             Some(Token::LBrace) => (),
-            _ => return Err("Expected '{' after 'while'".to_string()),
+            _ => return Err(self.syntax_error("Expected '{' after 'while'")),
         }
 
         let body = self.parse_block()?;
 
-        Ok(Expr::While {
+        Ok(ExprKind::While {
             condition: Box::new(condition),
             body,
         })
     }
 
-    fn parse_block(&mut self) -> Result<Vec<Expr>, String> {
-        let mut expressions = Vec::new();
-        while let Some(token) = self.tokens.peek() {
-            if *token == Token::RBrace {
-                self.tokens.next(); // Consume }
-                break;
+    fn parse_for(&mut self) -> Result<ExprKind, CompileError> {
+        self.tokens.next(); // Consume for
+
+        let var = match self.tokens.next() {
+            Some(Token::Identifier(name)) => name,
+            _ => return Err(self.syntax_error("Expected identifier after 'for'")),
+        };
+        let var_span = self.tokens.previous();
+
+        match self.tokens.next() {
+            Some(Token::KwIn) => (),
+            _ => return Err(self.syntax_error("Expected 'in' after loop variable")),
+        }
+
+        let start = self.parse_head()?;
+
+        let inclusive = match self.tokens.next() {
+            Some(Token::DotDot) => false,
+            Some(Token::DotDotEq) => true,
+            _ => return Err(self.syntax_error("Expected '..' or '..=' in range")),
+        };
+
+        let end = self.parse_head()?;
+
+        // 'step' is a contextual keyword and stays a valid identifier elsewhere
+        let step = match self.tokens.peek() {
+            Some(Token::Identifier(name)) if name == "step" => {
+                self.tokens.next(); // Consume step
+                Some(Box::new(self.parse_head()?))
+            }
+            _ => None,
+        };
+
+        match self.tokens.next() {
+            Some(Token::LBrace) => (),
+            _ => return Err(self.syntax_error("Expected '{' after range")),
+        }
+
+        let body = self.parse_block()?;
+
+        Ok(ExprKind::For {
+            var,
+            var_span,
+            start: Box::new(start),
+            end: Box::new(end),
+            inclusive,
+            step,
+            body,
+        })
+    }
+
+    fn parse_fn(&mut self) -> Result<ExprKind, CompileError> {
+        self.tokens.next(); // Consume fn
+
+        let name = match self.tokens.next() {
+            Some(Token::Identifier(name)) => name,
+            _ => return Err(self.syntax_error("Expected function name after 'fn'")),
+        };
+
+        match self.tokens.next() {
+            Some(Token::LParen) => (),
+            _ => return Err(self.syntax_error("Expected '(' after function name")),
+        }
+
+        let mut params = Vec::new();
+        if let Some(Token::RParen) = self.tokens.peek() {
+            self.tokens.next(); // Consume )
+        } else {
+            loop {
+                let start = self.tokens.position;
+                let name = match self.tokens.next() {
+                    Some(Token::Identifier(name)) => name,
+                    _ => return Err(self.syntax_error("Expected parameter name")),
+                };
+                match self.tokens.next() {
+                    Some(Token::Colon) => (),
+                    _ => return Err(self.syntax_error("Expected ':' after parameter name")),
+                }
+                let var_type = self.parse_type()?;
+                params.push(Param {
+                    name,
+                    var_type,
+                    span: self.tokens.span_from(start),
+                });
+
+                match self.tokens.next() {
+                    Some(Token::Comma) => (),
+                    Some(Token::RParen) => break,
+                    _ => return Err(self.syntax_error("Expected ',' or ')' in parameter list")),
+                }
+            }
+        }
+
+        let ret_type = if let Some(Token::Arrow) = self.tokens.peek() {
+            self.tokens.next(); // Consume ->
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+
+        match self.tokens.next() {
+            Some(Token::LBrace) => (),
+            _ => return Err(self.syntax_error("Expected '{' before function body")),
+        }
+
+        let body = self.parse_block()?;
+
+        Ok(ExprKind::Function {
+            name,
+            params,
+            ret_type,
+            body,
+        })
+    }
+
+    fn parse_return(&mut self) -> Result<ExprKind, CompileError> {
+        self.tokens.next(); // Consume return
+
+        if let Some(Token::Semicolon) = self.tokens.peek() {
+            self.tokens.next(); // Consume ;
+            return Ok(ExprKind::Return(None));
+        }
+
+        let expr = self.parse_expr()?;
+
+        match self.tokens.next() {
+            Some(Token::Semicolon) => (),
+            _ => return Err(self.syntax_error("Expected ';' at the end of statement")),
+        }
+
+        Ok(ExprKind::Return(Some(Box::new(expr))))
+    }
+
+    /// Parses `break;` and `continue;`
+    fn parse_loop_jump(&mut self, jump: ExprKind) -> Result<ExprKind, CompileError> {
+        self.tokens.next(); // Consume break / continue
+
+        match self.tokens.next() {
+            Some(Token::Semicolon) => Ok(jump),
+            _ => Err(self.syntax_error("Expected ';' at the end of statement")),
+        }
+    }
+
+    /// Parses the rest of a block after `{`, a trailing expression without ';' becomes its value
+    fn parse_block(&mut self) -> Result<Block, CompileError> {
+        self.with_struct_literals(true, Self::parse_block_body)
+    }
+
+    fn parse_block_body(&mut self) -> Result<Block, CompileError> {
+        let mut statements = Vec::new();
+        loop {
+            match self.tokens.peek() {
+                Some(Token::RBrace) => {
+                    self.tokens.next(); // Consume }
+                    return Ok(Block {
+                        statements,
+                        value: None,
+                    });
+                }
+                None => {
+                    let message = "Expected '}' at the end of block";
+                    return Err(self.error(ErrorKind::UnexpectedEof, message));
+                }
+                _ => (),
+            }
+
+            let start = self.tokens.position;
+            let expr = match self.parse_statement_expr() {
+                Ok(expr) => expr,
+                Err(err) => {
+                    let stmt = self.recover(err, start, true);
+                    statements.push(stmt);
+                    continue;
+                }
+            };
+            if !consumes_semicolon(&expr) {
+                match self.tokens.peek() {
+                    Some(Token::Semicolon) => {
+                        self.tokens.next(); // Consume ;
+                    }
+                    Some(Token::RBrace) => {
+                        self.tokens.next(); // Consume }
+                        return Ok(Block {
+                            statements,
+                            value: Some(Box::new(expr)),
+                        });
+                    }
+                    _ if ends_with_block(&expr) => (),
+                    _ => {
+                        let err = self.syntax_error("Expected ';' at the end of statement");
+                        let stmt = self.recover(err, start, true);
+                        statements.push(stmt);
+                        continue;
+                    }
+                }
             }
-            let expr = self.parse_expression()?;
-            expressions.push(expr);
+            statements.push(Stmt {
+                expr,
+                span: self.tokens.span_from(start),
+            });
         }
-        Ok(expressions)
     }
 }
+
+/// Keywords a statement can start with, where parsing resumes after a syntax error
+fn starts_statement(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::KwLet
+            | Token::KwConst
+            | Token::KwImport
+            | Token::KwMod
+            | Token::KwIf
+            | Token::KwMatch
+            | Token::KwWhile
+            | Token::KwFor
+            | Token::KwBreak
+            | Token::KwContinue
+            | Token::KwFn
+            | Token::KwStruct
+            | Token::KwEnum
+            | Token::KwReturn
+    )
+}
+
+/// Statements that are parsed together with their own ';' or have no value at all
+fn consumes_semicolon(expr: &Expr) -> bool {
+    matches!(
+        expr.kind,
+        ExprKind::Let { .. }
+            | ExprKind::Const { .. }
+            | ExprKind::Assign { .. }
+            | ExprKind::CompoundAssign { .. }
+            | ExprKind::Import { .. }
+            | ExprKind::Return(_)
+            | ExprKind::Break
+            | ExprKind::Continue
+            | ExprKind::Function { .. }
+            | ExprKind::Struct { .. }
+            | ExprKind::Enum { .. }
+    )
+}
+
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Operator applied by a compound assignment token
+fn compound_operator(token: &Token) -> Option<BinaryOp> {
+    match token {
+        Token::OpAddAssign => Some(BinaryOp::Add),
+        Token::OpSubAssign => Some(BinaryOp::Sub),
+        Token::OpMulAssign => Some(BinaryOp::Mul),
+        Token::OpDivAssign => Some(BinaryOp::Div),
+        Token::OpModAssign => Some(BinaryOp::Mod),
+        Token::OpBitAndAssign => Some(BinaryOp::BitAnd),
+        Token::OpBitOrAssign => Some(BinaryOp::BitOr),
+        Token::OpBitXorAssign => Some(BinaryOp::BitXor),
+        Token::OpShlAssign => Some(BinaryOp::Shl),
+        Token::OpShrAssign => Some(BinaryOp::Shr),
+        Token::OpUshrAssign => Some(BinaryOp::Ushr),
+        _ => None,
+    }
+}
+
+/// Expressions ending with a block don't need a ';' to be used as statements
+fn ends_with_block(expr: &Expr) -> bool {
+    matches!(
+        expr.kind,
+        ExprKind::If { .. }
+            | ExprKind::Match { .. }
+            | ExprKind::While { .. }
+            | ExprKind::For { .. }
+            | ExprKind::Block(_)
+    )
+}
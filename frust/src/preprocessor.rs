@@ -1,10 +1,488 @@
-use lazy_static::lazy_static;
-use regex::Regex;
+use crate::ast::Span;
+use crate::diagnostic::{CompileError, ErrorKind};
+use crate::lexer::{block_comment_len, Token};
+use crate::parser::is_identifier;
+use logos::Logos;
+use std::collections::HashMap;
+use std::ops::Range;
 
-pub fn remove_comments(input: &str) -> String {
-    lazy_static! {
-        // Regex for comments
-        static ref RE: Regex = Regex::new(r"(/\*[\s\S]*?\*/)|(//.*)").unwrap();
+/// Preprocessed code, it has the lines of the original text at the same offsets
+/// except for the expanded macro calls
+pub struct Source {
+    pub code: String,
+    original: String,
+    expansions: Vec<Expansion>, // in the order of the code
+}
+
+/// A macro call replaced by its expansion
+struct Expansion {
+    code: Range<usize>,
+    call: Range<usize>,
+}
+
+impl Source {
+    /// Text that isn't preprocessed, errors of the preprocessor are located in it
+    pub fn unprocessed(text: &str) -> Self {
+        Source {
+            code: text.to_string(),
+            original: text.to_string(),
+            expansions: vec![],
+        }
+    }
+
+    /// Range of the original text a range of the preprocessed code comes from,
+    /// a part of an expansion comes from the whole macro call
+    fn original(&self, start: usize, end: usize) -> Range<usize> {
+        self.original_offset(start, false)..self.original_offset(end.max(start), true)
+    }
+
+    fn original_offset(&self, offset: usize, end: bool) -> usize {
+        let offset = offset.min(self.code.len());
+        let mut shifted = offset;
+        for expansion in &self.expansions {
+            let Range { start, end: stop } = expansion.code;
+            if offset < start || (offset == start && end) {
+                break;
+            }
+            if offset < stop || (offset == stop && end) {
+                return if end {
+                    expansion.call.end
+                } else {
+                    expansion.call.start
+                };
+            }
+            shifted = offset - stop + expansion.call.end;
+        }
+        shifted.min(self.original.len())
+    }
+
+    /// Line, column and original text of the line of a range of the preprocessed code,
+    /// with the width of the range on that line
+    pub fn locate(&self, start: usize, end: usize) -> (usize, usize, &str, usize) {
+        let range = self.original(start, end);
+        let text = &self.original;
+        let line_start = text[..range.start]
+            .rfind('\n')
+            .map_or(0, |newline| newline + 1);
+        let line_end = text[range.start..]
+            .find('\n')
+            .map_or(text.len(), |newline| range.start + newline);
+        let line = text[..line_start].matches('\n').count() + 1;
+        let column = text[line_start..range.start].chars().count() + 1;
+        // A range over several lines is measured up to the end of its first line
+        let width = text[range.start..range.end.min(line_end)].chars().count();
+        let line_text = text[line_start..line_end].trim_end_matches('\r');
+        (line, column, line_text, width)
+    }
+}
+
+/// Expands macros and blanks out the directives and the code disabled by conditional
+/// compilation, `defines` are the `NAME=VALUE` pairs given with `-D` or `--define`.
+/// Comments are kept for the lexer, lines without macros are copied as they are.
+/// Errors point at the line of the original text they are found on
+pub fn preprocess(input: &str, defines: &[(String, String)]) -> Result<Source, CompileError> {
+    let mut preprocessor = Preprocessor {
+        macros: HashMap::new(),
+        conditions: vec![],
+    };
+    for (name, value) in defines {
+        if !is_identifier(name) {
+            let message = format!("Invalid macro name {}", name);
+            return Err(CompileError::new(ErrorKind::InvalidMacro, message));
+        }
+        preprocessor.macros.insert(
+            name.clone(),
+            Macro {
+                params: None,
+                body: value.clone(),
+            },
+        );
+    }
+
+    let mut source = Source {
+        code: String::with_capacity(input.len()),
+        original: input.to_string(),
+        expansions: vec![],
+    };
+    let comments = comment_spans(input);
+    // Lines without their end, which is copied as it is
+    let mut lines = input.split_inclusive('\n').scan(0, |offset, line| {
+        let start = *offset;
+        *offset += line.len();
+        let text = line.trim_end_matches(['\n', '\r']);
+        Some((start, text, &line[text.len()..]))
+    });
+    while let Some((start, line, line_end)) = lines.next() {
+        // A line starting inside a block comment is code only after the comment
+        let commented = comments
+            .iter()
+            .find(|comment| comment.start < start && start < comment.end)
+            .map_or(0, |comment| (comment.end - start).min(line.len()));
+        let (comment, code) = line.split_at(commented);
+        let indent = code.len() - code.trim_start().len();
+        let span = Span::new(start + commented + indent, start + line.len());
+        let result = match code.trim_start().strip_prefix('#') {
+            Some(directive) if comment.is_empty() => {
+                // A directive continues on the next line after a trailing backslash
+                let mut directive = directive.to_string();
+                let mut directive_lines = vec![(line, line_end)];
+                while directive.ends_with('\\') {
+                    directive.pop();
+                    match lines.next() {
+                        Some((_, next, next_end)) => {
+                            directive.push_str(next);
+                            directive_lines.push((next, next_end));
+                        }
+                        None => break,
+                    }
+                }
+                let result = preprocessor.directive(&strip_comments(&directive), span);
+                // The comments of an active directive stay in their columns for the lexer
+                let active = preprocessor.active();
+                for (line, line_end) in directive_lines {
+                    match active {
+                        true => source.code.push_str(&blank_code(line)),
+                        false => source.code.push_str(&" ".repeat(line.len())),
+                    }
+                    source.code.push_str(line_end);
+                }
+                result
+            }
+            _ if preprocessor.active() => {
+                source.code.push_str(comment);
+                let mut calls = vec![];
+                preprocessor
+                    .expand(code, &mut vec![], &mut calls)
+                    .map(|expanded| {
+                        // Offsets of the calls in the original text and their expansions in the code
+                        let (call_start, code_start) = (start + comment.len(), source.code.len());
+                        source
+                            .expansions
+                            .extend(calls.into_iter().map(|(call, code)| Expansion {
+                                code: code_start + code.start..code_start + code.end,
+                                call: call_start + call.start..call_start + call.end,
+                            }));
+                        source.code.push_str(&expanded);
+                        source.code.push_str(line_end);
+                    })
+            }
+            _ => {
+                source.code.push_str(&" ".repeat(line.len()));
+                source.code.push_str(line_end);
+                Ok(())
+            }
+        };
+        result.map_err(|err| err.at(span))?;
+    }
+
+    match preprocessor.conditions.last() {
+        Some(condition) => {
+            let message = format!("Unterminated #{}", condition.directive);
+            Err(CompileError::new(ErrorKind::UnbalancedConditional, message).at(condition.span))
+        }
+        None => Ok(source),
+    }
+}
+
+struct Macro {
+    params: Option<Vec<String>>, // None for object-like macros
+    body: String,
+}
+
+/// An `#ifdef` or `#ifndef` waiting for its `#endif`
+struct Condition {
+    directive: String,
+    span: Span,
+    enclosing: bool, // whether the code around the condition is compiled
+    taken: bool,
+    has_else: bool,
+}
+
+struct Preprocessor {
+    macros: HashMap<String, Macro>,
+    conditions: Vec<Condition>,
+}
+
+impl Preprocessor {
+    fn active(&self) -> bool {
+        self.conditions
+            .last()
+            .is_none_or(|condition| condition.enclosing && condition.taken)
+    }
+
+    fn directive(&mut self, directive: &str, span: Span) -> Result<(), CompileError> {
+        let directive = directive.trim();
+        let (name, rest) = directive
+            .split_once(|c: char| c.is_whitespace())
+            .unwrap_or((directive, ""));
+        let rest = rest.trim();
+        match name {
+            "ifdef" | "ifndef" => {
+                if !is_identifier(rest) {
+                    let message = format!("Expected macro name after #{}", name);
+                    return Err(CompileError::new(ErrorKind::InvalidMacro, message));
+                }
+                self.conditions.push(Condition {
+                    directive: name.to_string(),
+                    span,
+                    enclosing: self.active(),
+                    taken: self.macros.contains_key(rest) == (name == "ifdef"),
+                    has_else: false,
+                });
+            }
+            "else" => match self.conditions.last_mut() {
+                Some(condition) if condition.has_else => {
+                    let message = format!("Duplicate #else for #{}", condition.directive);
+                    return Err(CompileError::new(ErrorKind::UnbalancedConditional, message));
+                }
+                Some(condition) => {
+                    condition.taken = !condition.taken;
+                    condition.has_else = true;
+                }
+                None => {
+                    let message = "#else without #ifdef".to_string();
+                    return Err(CompileError::new(ErrorKind::UnbalancedConditional, message));
+                }
+            },
+            "endif" => {
+                if self.conditions.pop().is_none() {
+                    let message = "#endif without #ifdef".to_string();
+                    return Err(CompileError::new(ErrorKind::UnbalancedConditional, message));
+                }
+            }
+            _ if !self.active() => {}
+            "define" => self.define(rest)?,
+            "undef" => {
+                if !is_identifier(rest) {
+                    let message = "Expected macro name after #undef".to_string();
+                    return Err(CompileError::new(ErrorKind::InvalidMacro, message));
+                }
+                self.macros.remove(rest);
+            }
+            _ => {
+                let message = format!("Unknown directive #{}", name);
+                return Err(CompileError::new(ErrorKind::UnknownDirective, message));
+            }
+        }
+        Ok(())
+    }
+
+    /// `NAME body` or `NAME(params) body`, the parameter list must follow the name immediately
+    fn define(&mut self, definition: &str) -> Result<(), CompileError> {
+        let end = definition
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(definition.len());
+        let (name, rest) = definition.split_at(end);
+        if !is_identifier(name) {
+            let message = "Expected macro name after #define".to_string();
+            return Err(CompileError::new(ErrorKind::InvalidMacro, message));
+        }
+        let (params, body) = match rest.strip_prefix('(') {
+            Some(rest) => {
+                let (params, body) = rest.split_once(')').ok_or_else(|| {
+                    let message = format!("Expected ')' after parameters of macro {}", name);
+                    CompileError::new(ErrorKind::InvalidMacro, message)
+                })?;
+                let params: Vec<String> = match params.trim() {
+                    "" => vec![],
+                    params => params.split(',').map(|p| p.trim().to_string()).collect(),
+                };
+                if let Some(param) = params.iter().find(|param| !is_identifier(param)) {
+                    let message = format!("Invalid parameter {} of macro {}", param, name);
+                    return Err(CompileError::new(ErrorKind::InvalidMacro, message));
+                }
+                (Some(params), body)
+            }
+            None => (None, rest),
+        };
+        self.macros.insert(
+            name.to_string(),
+            Macro {
+                params,
+                body: body.trim().to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Replaces macros in a line, a macro is not expanded again inside its own expansion.
+    /// `calls` gets the range of each outermost call in the text and of its expansion in the result
+    fn expand(
+        &self,
+        text: &str,
+        expanding: &mut Vec<String>,
+        calls: &mut Vec<(Range<usize>, Range<usize>)>,
+    ) -> Result<String, CompileError> {
+        let mut result = String::new();
+        let mut rest = text;
+        while !rest.is_empty() {
+            let call_start = text.len() - rest.len();
+            let (piece, after) = split_piece(rest);
+            rest = after;
+            let Some(definition) = self.macros.get(piece) else {
+                result.push_str(piece);
+                continue;
+            };
+            if expanding.iter().any(|name| name == piece) {
+                result.push_str(piece);
+                continue;
+            }
+            let body = match &definition.params {
+                None => definition.body.clone(),
+                Some(params) => {
+                    // A function-like macro without arguments is left as is
+                    let Some(call) = rest.trim_start().strip_prefix('(') else {
+                        result.push_str(piece);
+                        continue;
+                    };
+                    let (args, after) = split_args(call).ok_or_else(|| {
+                        let message = format!("Unterminated call of macro {}", piece);
+                        CompileError::new(ErrorKind::MacroArguments, message)
+                    })?;
+                    rest = after;
+                    if args.len() != params.len() {
+                        let message = format!(
+                            "Macro {} expects {} arguments, got {}",
+                            piece,
+                            params.len(),
+                            args.len()
+                        );
+                        return Err(CompileError::new(ErrorKind::MacroArguments, message));
+                    }
+                    let mut values = HashMap::new();
+                    for (param, arg) in params.iter().zip(args) {
+                        let value = self.expand(arg.trim(), expanding, &mut vec![])?;
+                        values.insert(param.as_str(), value);
+                    }
+                    substitute(&definition.body, &values)
+                }
+            };
+            expanding.push(piece.to_string());
+            let expanded = self.expand(&body, expanding, &mut vec![]);
+            expanding.pop();
+            let expansion_start = result.len();
+            result.push_str(&expanded?);
+            if expanding.is_empty() {
+                calls.push((
+                    call_start..text.len() - rest.len(),
+                    expansion_start..result.len(),
+                ));
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Byte ranges of the comments, lexing errors are left for the lexer
+fn comment_spans(input: &str) -> Vec<Range<usize>> {
+    Token::lexer(input)
+        .spanned()
+        .filter(|(token, _)| token.as_ref().is_ok_and(Token::is_trivia))
+        .map(|(_, span)| span)
+        .collect()
+}
+
+fn is_comment(piece: &str) -> bool {
+    piece.starts_with("//") || piece.starts_with("/*")
+}
+
+/// Comments of a directive separate words like spaces do
+fn strip_comments(text: &str) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (piece, after) = split_piece(rest);
+        result.push_str(if is_comment(piece) { " " } else { piece });
+        rest = after;
+    }
+    result
+}
+
+/// The comments of a line with everything else blanked out
+fn blank_code(line: &str) -> String {
+    let mut result = String::new();
+    let mut rest = line;
+    while !rest.is_empty() {
+        let (piece, after) = split_piece(rest);
+        if is_comment(piece) {
+            result.push_str(piece);
+        } else {
+            result.push_str(&" ".repeat(piece.len()));
+        }
+        rest = after;
+    }
+    result
+}
+
+/// Splits off a comment, a string literal, a word or a single character.
+/// A comment left open goes to the end of the text
+fn split_piece(text: &str) -> (&str, &str) {
+    if text.starts_with("//") {
+        return (text, "");
+    }
+    if text.starts_with("/*") {
+        return text.split_at(block_comment_len(text).unwrap_or(text.len()));
+    }
+    let mut chars = text.char_indices();
+    let end = match chars.next() {
+        Some((_, '"')) => {
+            let mut escaped = false;
+            chars
+                .find(|&(_, c)| {
+                    let end = c == '"' && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    end
+                })
+                .map_or(text.len(), |(i, _)| i + 1)
+        }
+        Some((_, c)) if c.is_ascii_alphanumeric() || c == '_' => text
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(text.len()),
+        Some((_, c)) => c.len_utf8(),
+        None => 0,
+    };
+    text.split_at(end)
+}
+
+/// Splits the arguments of a macro call at the top-level commas,
+/// `text` starts after the opening parenthesis
+fn split_args(text: &str) -> Option<(Vec<&str>, &str)> {
+    let mut args = vec![];
+    let mut depth = 0;
+    let (mut start, mut offset) = (0, 0);
+    while offset < text.len() {
+        let (piece, _) = split_piece(&text[offset..]);
+        match piece {
+            "(" => depth += 1,
+            ")" if depth > 0 => depth -= 1,
+            ")" => {
+                args.push(&text[start..offset]);
+                if args.len() == 1 && args[0].trim().is_empty() {
+                    args.clear();
+                }
+                return Some((args, &text[offset + 1..]));
+            }
+            "," if depth == 0 => {
+                args.push(&text[start..offset]);
+                start = offset + 1;
+            }
+            _ => {}
+        }
+        offset += piece.len();
+    }
+    None
+}
+
+fn substitute(body: &str, values: &HashMap<&str, String>) -> String {
+    let mut result = String::new();
+    let mut rest = body;
+    while !rest.is_empty() {
+        let (piece, after) = split_piece(rest);
+        match values.get(piece) {
+            Some(value) => result.push_str(value),
+            None => result.push_str(piece),
+        }
+        rest = after;
     }
-    RE.replace_all(input, "").to_string()
+    result
 }
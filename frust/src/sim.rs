@@ -0,0 +1,398 @@
+use crate::codegen::Target;
+use std::collections::HashMap;
+
+const TEXT_BASE: u64 = 0x1000;
+const DATA_BASE: u64 = 0x1000_0000;
+const STACK_TOP: u64 = 0x7fff_0000;
+const STEP_LIMIT: usize = 10_000_000;
+
+/// How a simulated program ended
+#[derive(Debug, PartialEq)]
+pub enum Exit {
+    Code(i64), // exit system call
+    Trap,      // ebreak
+}
+
+pub struct Execution {
+    pub output: String,
+    pub exit: Exit,
+}
+
+#[derive(Debug)]
+enum Operand {
+    Reg(usize),
+    Imm(i64),
+    Mem(i64, usize), // offset(base)
+    Label(String),
+}
+
+struct Inst {
+    op: String,
+    operands: Vec<Operand>,
+}
+
+/// Runs the assembly produced by codegen the way a RISC-V machine with
+/// the `write` and `exit` system calls would, and collects what it prints
+pub fn run(assembly: &[String], target: Target) -> Execution {
+    let mut machine = Machine::new(assembly, target);
+    let exit = machine.execute();
+    Execution {
+        output: String::from_utf8_lossy(&machine.output).into_owned(),
+        exit,
+    }
+}
+
+struct Machine {
+    xlen: u32,
+    code: Vec<Inst>,
+    labels: HashMap<String, u64>,
+    memory: HashMap<u64, u8>,
+    regs: [u64; 32],
+    pc: u64,
+    output: Vec<u8>,
+}
+
+impl Machine {
+    fn new(assembly: &[String], target: Target) -> Self {
+        let mut machine = Machine {
+            xlen: match target {
+                Target::Rv32 => 32,
+                Target::Rv64 => 64,
+            },
+            code: Vec::new(),
+            labels: HashMap::new(),
+            memory: HashMap::new(),
+            regs: [0; 32],
+            pc: 0,
+            output: Vec::new(),
+        };
+        let mut in_text = true;
+        let mut data = DATA_BASE;
+        // Data words holding labels are filled in once every label is known
+        let mut words = Vec::new();
+        for line in assembly.iter().flat_map(|line| line.lines()) {
+            let line = line.trim();
+            if let Some(label) = line.strip_suffix(':') {
+                let address = if in_text {
+                    TEXT_BASE + 4 * machine.code.len() as u64
+                } else {
+                    data
+                };
+                machine.labels.insert(label.to_string(), address);
+                continue;
+            }
+            let (op, args) = line.split_once(' ').unwrap_or((line, ""));
+            match op {
+                ".globl" => {}
+                ".text" => in_text = true,
+                ".section" => in_text = false,
+                ".balign" => {
+                    let align: u64 = args.parse().expect("Invalid alignment");
+                    data = data.div_ceil(align) * align;
+                }
+                ".word" | ".dword" => {
+                    let size = if op == ".word" { 4 } else { 8 };
+                    words.push((data, size, args.to_string()));
+                    data += size;
+                }
+                ".string" => {
+                    let quoted = args.strip_prefix('"').and_then(|s| s.strip_suffix('"'));
+                    for byte in unescape(quoted.expect("Unquoted string")) {
+                        machine.memory.insert(data, byte);
+                        data += 1;
+                    }
+                    machine.memory.insert(data, 0);
+                    data += 1;
+                }
+                _ => {
+                    let operands = args
+                        .split(", ")
+                        .filter(|arg| !arg.is_empty())
+                        .map(operand)
+                        .collect();
+                    machine.code.push(Inst {
+                        op: op.to_string(),
+                        operands,
+                    });
+                }
+            }
+        }
+        for (address, size, label) in words {
+            let value = machine.label(&label);
+            machine.store(address, value, size);
+        }
+        machine.pc = machine.label("_start");
+        machine.regs[2] = STACK_TOP;
+        machine
+    }
+
+    fn label(&self, label: &str) -> u64 {
+        *self
+            .labels
+            .get(label)
+            .unwrap_or_else(|| panic!("Unknown label {}", label))
+    }
+
+    fn mask(&self, value: u64) -> u64 {
+        if self.xlen == 32 {
+            value & 0xffff_ffff
+        } else {
+            value
+        }
+    }
+
+    fn signed(&self, value: u64) -> i64 {
+        if self.xlen == 32 {
+            value as u32 as i32 as i64
+        } else {
+            value as i64
+        }
+    }
+
+    fn reg(&self, operand: &Operand) -> u64 {
+        match operand {
+            Operand::Reg(reg) => self.regs[*reg],
+            other => panic!("Expected a register, found {:?}", other),
+        }
+    }
+
+    fn imm(&self, operand: &Operand) -> i64 {
+        match operand {
+            Operand::Imm(imm) => *imm,
+            other => panic!("Expected an immediate, found {:?}", other),
+        }
+    }
+
+    fn target(&self, operand: &Operand) -> u64 {
+        match operand {
+            Operand::Label(label) => self.label(label),
+            other => panic!("Expected a label, found {:?}", other),
+        }
+    }
+
+    fn address(&self, operand: &Operand) -> u64 {
+        match operand {
+            Operand::Mem(offset, base) => self.mask(self.regs[*base].wrapping_add(*offset as u64)),
+            other => panic!("Expected a memory operand, found {:?}", other),
+        }
+    }
+
+    fn set(&mut self, operand: &Operand, value: u64) {
+        match operand {
+            Operand::Reg(0) => {}
+            Operand::Reg(reg) => self.regs[*reg] = self.mask(value),
+            other => panic!("Expected a register, found {:?}", other),
+        }
+    }
+
+    fn load(&self, address: u64, size: u64) -> u64 {
+        (0..size).fold(0, |value, i| {
+            let byte = self.memory.get(&(address + i)).copied().unwrap_or(0);
+            value | (byte as u64) << (8 * i)
+        })
+    }
+
+    fn store(&mut self, address: u64, value: u64, size: u64) {
+        for i in 0..size {
+            self.memory.insert(address + i, (value >> (8 * i)) as u8);
+        }
+    }
+
+    fn execute(&mut self) -> Exit {
+        let code = std::mem::take(&mut self.code);
+        for _ in 0..STEP_LIMIT {
+            let index = (self.pc - TEXT_BASE) / 4;
+            let inst = code
+                .get(index as usize)
+                .unwrap_or_else(|| panic!("Jump outside of the code to {:#x}", self.pc));
+            let Inst { op, operands: ops } = inst;
+            let mut next = self.pc + 4;
+            let shift_mask = self.xlen as u64 - 1;
+            match op.as_str() {
+                "add" | "sub" | "mul" | "div" | "divu" | "rem" | "remu" | "and" | "or" | "xor"
+                | "sll" | "srl" | "sra" | "slt" | "sltu" => {
+                    let (x, y) = (self.reg(&ops[1]), self.reg(&ops[2]));
+                    let (sx, sy) = (self.signed(x), self.signed(y));
+                    let value = match op.as_str() {
+                        "add" => x.wrapping_add(y),
+                        "sub" => x.wrapping_sub(y),
+                        "mul" => x.wrapping_mul(y),
+                        "div" if sy == 0 => u64::MAX,
+                        "div" => sx.wrapping_div(sy) as u64,
+                        "divu" if y == 0 => u64::MAX,
+                        "divu" => x / y,
+                        "rem" if sy == 0 => x,
+                        "rem" => sx.wrapping_rem(sy) as u64,
+                        "remu" if y == 0 => x,
+                        "remu" => x % y,
+                        "and" => x & y,
+                        "or" => x | y,
+                        "xor" => x ^ y,
+                        "sll" => x << (y & shift_mask),
+                        "srl" => x >> (y & shift_mask),
+                        "sra" => (sx >> (y & shift_mask)) as u64,
+                        "slt" => (sx < sy) as u64,
+                        _ => (x < y) as u64,
+                    };
+                    self.set(&ops[0], value);
+                }
+                "addw" | "subw" | "mulw" | "divw" | "divuw" | "remw" | "remuw" | "sllw"
+                | "srlw" | "sraw" => {
+                    let (x, y) = (self.reg(&ops[1]) as u32, self.reg(&ops[2]) as u32);
+                    let (sx, sy) = (x as i32, y as i32);
+                    let value = match op.as_str() {
+                        "addw" => x.wrapping_add(y),
+                        "subw" => x.wrapping_sub(y),
+                        "mulw" => x.wrapping_mul(y),
+                        "divw" if sy == 0 => u32::MAX,
+                        "divw" => sx.wrapping_div(sy) as u32,
+                        "divuw" if y == 0 => u32::MAX,
+                        "divuw" => x / y,
+                        "remw" if sy == 0 => x,
+                        "remw" => sx.wrapping_rem(sy) as u32,
+                        "remuw" if y == 0 => x,
+                        "remuw" => x % y,
+                        "sllw" => x << (y & 31),
+                        "srlw" => x >> (y & 31),
+                        _ => (sx >> (y & 31)) as u32,
+                    };
+                    self.set(&ops[0], value as i32 as u64);
+                }
+                "addi" | "andi" | "xori" | "sltiu" | "slli" | "srli" | "srai" => {
+                    let x = self.reg(&ops[1]);
+                    let imm = self.imm(&ops[2]);
+                    let value = match op.as_str() {
+                        "addi" => x.wrapping_add(imm as u64),
+                        "andi" => x & imm as u64,
+                        "xori" => x ^ imm as u64,
+                        "sltiu" => (x < self.mask(imm as u64)) as u64,
+                        "slli" => x << (imm as u64 & shift_mask),
+                        "srli" => x >> (imm as u64 & shift_mask),
+                        _ => (self.signed(x) >> (imm as u64 & shift_mask)) as u64,
+                    };
+                    self.set(&ops[0], value);
+                }
+                "addiw" => {
+                    let value = (self.reg(&ops[1]) as i32).wrapping_add(self.imm(&ops[2]) as i32);
+                    self.set(&ops[0], value as i64 as u64);
+                }
+                "lui" => {
+                    let value = ((self.imm(&ops[1]) as i32) << 12) as i64;
+                    self.set(&ops[0], value as u64);
+                }
+                "la" => {
+                    let address = self.target(&ops[1]);
+                    self.set(&ops[0], address);
+                }
+                "lb" | "lbu" | "lh" | "lhu" | "lw" | "lwu" | "ld" => {
+                    let size = match op.as_str() {
+                        "lb" | "lbu" => 1,
+                        "lh" | "lhu" => 2,
+                        "lw" | "lwu" => 4,
+                        _ => 8,
+                    };
+                    let value = self.load(self.address(&ops[1]), size);
+                    let value = if op.ends_with('u') || size == 8 {
+                        value
+                    } else {
+                        let unused = 64 - 8 * size;
+                        (((value << unused) as i64) >> unused) as u64
+                    };
+                    self.set(&ops[0], value);
+                }
+                "sb" | "sh" | "sw" | "sd" => {
+                    let size = match op.as_str() {
+                        "sb" => 1,
+                        "sh" => 2,
+                        "sw" => 4,
+                        _ => 8,
+                    };
+                    self.store(self.address(&ops[1]), self.reg(&ops[0]), size);
+                }
+                "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => {
+                    let (x, y) = (self.reg(&ops[0]), self.reg(&ops[1]));
+                    let (sx, sy) = (self.signed(x), self.signed(y));
+                    let taken = match op.as_str() {
+                        "beq" => x == y,
+                        "bne" => x != y,
+                        "blt" => sx < sy,
+                        "bge" => sx >= sy,
+                        "bltu" => x < y,
+                        _ => x >= y,
+                    };
+                    if taken {
+                        next = self.target(&ops[2]);
+                    }
+                }
+                "jal" => {
+                    next = self.target(&ops[1]);
+                    self.set(&ops[0], self.pc + 4);
+                }
+                "jalr" => {
+                    next = self.address(&ops[1]) & !1;
+                    self.set(&ops[0], self.pc + 4);
+                }
+                "ecall" => match self.regs[17] {
+                    64 => {
+                        let (buffer, length) = (self.regs[11], self.regs[12]);
+                        let bytes: Vec<u8> = (0..length)
+                            .map(|i| self.load(buffer + i, 1) as u8)
+                            .collect();
+                        self.output.extend(bytes);
+                        self.regs[10] = length;
+                    }
+                    93 => return Exit::Code(self.signed(self.regs[10])),
+                    other => panic!("Unknown system call {}", other),
+                },
+                "ebreak" => return Exit::Trap,
+                other => panic!("Unknown instruction {}", other),
+            }
+            self.pc = next;
+        }
+        panic!("Program didn't exit after {} steps", STEP_LIMIT)
+    }
+}
+
+fn operand(text: &str) -> Operand {
+    if let Some(reg) = register(text) {
+        return Operand::Reg(reg);
+    }
+    if let Ok(imm) = text.parse() {
+        return Operand::Imm(imm);
+    }
+    if let Some((offset, base)) = text.strip_suffix(')').and_then(|s| s.split_once('(')) {
+        if let (Ok(offset), Some(base)) = (offset.parse(), register(base)) {
+            return Operand::Mem(offset, base);
+        }
+    }
+    Operand::Label(text.to_string())
+}
+
+fn register(text: &str) -> Option<usize> {
+    let index = text.strip_prefix('x')?.parse().ok()?;
+    (index < 32).then_some(index)
+}
+
+/// Reverses the escapes of codegen's `.string` directives
+fn unescape(string: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut rest = string.bytes();
+    while let Some(byte) = rest.next() {
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        match rest.next() {
+            Some(b'n') => bytes.push(b'\n'),
+            Some(b't') => bytes.push(b'\t'),
+            Some(digit @ b'0'..=b'7') => {
+                let octal = [digit, rest.next().unwrap(), rest.next().unwrap()];
+                let octal = std::str::from_utf8(&octal).unwrap();
+                bytes.push(u8::from_str_radix(octal, 8).expect("Invalid octal escape"));
+            }
+            Some(other) => bytes.push(other),
+            None => panic!("String ends with a backslash"),
+        }
+    }
+    bytes
+}
@@ -1,26 +1,131 @@
-use std::f32::consts::E;
-
-use crate::ast::Expr;
-use crate::codegen::*;
+use crate::ast::*;
 use crate::codegen::*;
-use crate::inst::*;
+use crate::diagnostic::*;
 use crate::io::*;
+use crate::layout::*;
 use crate::lexer::*;
+use crate::lint::*;
+use crate::modules::*;
 use crate::parser::*;
 use crate::preprocessor::*;
+use crate::sim::*;
+use crate::typecheck::*;
+use std::collections::HashSet;
+
+fn compile(code: &str) -> Result<Vec<String>, CompileError> {
+    compile_with(code, Target::Rv32, |_| ())
+}
+
+fn compile_with(
+    code: &str,
+    target: Target,
+    configure: impl FnOnce(&mut CodeGenContext),
+) -> Result<Vec<String>, CompileError> {
+    let tokens = lexer_with_spans(code)?;
+    let mut parser = Parser::new(tokens);
+    let mut program = parser.parse().map_err(|mut errors| errors.remove(0))?;
+    let mut sources = SourceMap::new();
+    sources.add("test.fr".to_string(), Source::unprocessed(code));
+    let mut checker = TypeChecker::new();
+    checker.set_target(target);
+    checker.set_sources(&sources);
+    checker.check(&mut program)?;
+    let mut generator = CodeGenContext::new();
+    generator.set_target(target);
+    configure(&mut generator);
+    generator.generate_program(&program)?;
+    Ok(generator.assembly())
+}
+
+/// The kind of error a program fails to compile with
+fn error_kind(code: &str) -> ErrorKind {
+    compile(code).unwrap_err().kind
+}
+
+/// What a program prints on the target
+fn output_on(code: &str, target: Target) -> String {
+    let asm = compile_with(code, target, |_| ()).unwrap();
+    let execution = run(&asm, target);
+    assert_eq!(execution.exit, Exit::Code(0));
+    execution.output
+}
+
+/// What a program prints, it has to behave the same on both targets
+fn output(code: &str) -> String {
+    let output = output_on(code, Target::Rv32);
+    assert_eq!(output_on(code, Target::Rv64), output);
+    output
+}
+
+/// Whether the lines occur one after the other, `*` in them matches any text
+/// so that frame offsets and label numbers don't matter
+fn contains_sequence(asm: &[String], sequence: &[&str]) -> bool {
+    let lines: Vec<&str> = asm.iter().flat_map(|line| line.lines()).collect();
+    lines.windows(sequence.len()).any(|window| {
+        window
+            .iter()
+            .zip(sequence)
+            .all(|(line, pattern)| wildcard_match(line, pattern))
+    })
+}
+
+fn wildcard_match(text: &str, pattern: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = text.strip_prefix(parts.next().unwrap()) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        let Some(start) = rest.find(part) else {
+            return false;
+        };
+        rest = &rest[start + part.len()..];
+    }
+    rest.ends_with(last)
+}
+
+/// Number of distinct stack slots addressed through the frame pointer
+fn frame_slots(asm: &[String]) -> usize {
+    asm.iter()
+        .flat_map(|line| line.lines())
+        .filter_map(|line| line.rsplit(", ").next()?.strip_suffix("(x8)"))
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// Statements of a program without their spans
+fn parse(code: &str) -> Vec<ExprKind> {
+    let mut parser = Parser::new(lexer_with_spans(code).unwrap());
+    let program = parser.parse().unwrap();
+    program.into_iter().map(|stmt| stmt.expr.kind).collect()
+}
+
+/// Messages of the lints of a program, at their default levels
+fn lint_messages(code: &str) -> Vec<String> {
+    let mut program = Parser::new(lexer_with_spans(code).unwrap())
+        .parse()
+        .unwrap();
+    TypeChecker::new().check(&mut program).unwrap();
+    lint(&program, &LintLevels::new(), Target::Rv32)
+        .into_iter()
+        .map(|warning| warning.message)
+        .collect()
+}
 
 #[test]
 pub fn test_preprocessing() {
     let code = read_file("tests/example.fr".to_string()).unwrap();
-    let preprocessed_code = remove_comments(&code);
-    println!("{}", preprocessed_code);
+    let source = preprocess(&code, &[]).unwrap();
+    println!("{}", source.code);
 }
 
 #[test]
 pub fn test_lexer() {
     let code = read_file("tests/example.fr".to_string()).unwrap();
-    let preprocessed_code = remove_comments(&code);
-    let tokens = lexer(&preprocessed_code).unwrap();
+    let tokens = lexer(&code).unwrap();
 
     for token in &tokens {
         println!("{:?}", token);
@@ -30,8 +135,7 @@ pub fn test_lexer() {
 #[test]
 pub fn test_parser() {
     let code = read_file("tests/example.fr".to_string()).unwrap();
-    let preprocessed_code = remove_comments(&code);
-    let tokens = lexer(&preprocessed_code).unwrap();
+    let tokens = lexer_with_spans(&code).unwrap();
 
     let mut parser = Parser::new(tokens);
     let expressions = parser.parse().unwrap();
@@ -43,17 +147,1566 @@ pub fn test_parser() {
 #[test]
 pub fn test_codegen() {
     let code = read_file("tests/example.fr".to_string()).unwrap();
-    let preprocessed_code = remove_comments(&code);
-    let tokens = lexer(&preprocessed_code).unwrap();
+    let tokens = lexer_with_spans(&code).unwrap();
 
     let mut parser = Parser::new(tokens);
-    let expressions = parser.parse().unwrap();
+    let mut expressions = parser.parse().unwrap();
+    TypeChecker::new().check(&mut expressions).unwrap();
 
     let mut generator = CodeGenContext::new();
-    for expr in &expressions {
-        generator.generate(expr).unwrap();
-    }
-    for instr in generator.instructions() {
-        println!("{}", instr.to_string())
+    generator.generate_program(&expressions).unwrap();
+    for line in generator.assembly() {
+        println!("{}", line)
     }
 }
+
+#[test]
+pub fn test_functions() {
+    let code = read_file("tests/functions.fr".to_string()).unwrap();
+    let asm = compile(&code).unwrap();
+
+    assert!(asm.contains(&"jal x1, add".to_string()));
+    assert!(asm.contains(&"jalr x0, 0(x1)".to_string()));
+    // Arguments are passed in a0, a1 and the result comes back in a0
+    assert!(contains_sequence(&asm, &["sw x10, *(x8)", "sw x11, *(x8)"]));
+    assert!(asm.contains(&"addi x5, x10, 0".to_string()));
+    assert_eq!(output(&code), "5 120\n");
+
+    assert!(compile("return 1;").is_err());
+    assert_eq!(error_kind("fn _start() { }"), ErrorKind::ReservedName);
+}
+
+#[test]
+pub fn test_typecheck() {
+    let code = read_file("tests/functions.fr".to_string()).unwrap();
+    assert!(compile(&code).is_ok());
+
+    assert_eq!(
+        error_kind("let x: bool = 5 + 1;"),
+        ErrorKind::MismatchedTypes
+    );
+    assert_eq!(error_kind("let x: bool = !5;"), ErrorKind::InvalidOperand);
+    assert_eq!(error_kind("if 3 { }"), ErrorKind::MismatchedTypes);
+    assert_eq!(
+        error_kind("let x: num = 1 + true;"),
+        ErrorKind::InvalidOperand
+    );
+    assert_eq!(
+        error_kind("fn f(a: num) -> bool { return a; }"),
+        ErrorKind::MismatchedTypes
+    );
+    assert_eq!(
+        error_kind("fn f(a: num) -> num { if a > 0 { return a; } }"),
+        ErrorKind::MismatchedTypes
+    );
+    assert_eq!(
+        error_kind("fn f(a: num) { } let x: num = f(1, 2);"),
+        ErrorKind::ArgumentCount
+    );
+    let nine = "fn f(a: num, b: num, c: num, d: num, e: num, f: num, g: num, h: num, i: num) { }";
+    assert_eq!(error_kind(nine), ErrorKind::TooManyArguments);
+}
+
+#[test]
+pub fn test_scopes() {
+    let code = read_file("tests/scopes.fr".to_string()).unwrap();
+    let asm = compile(&code).unwrap();
+    // Slots of the block variables are reused after their scope ends
+    assert_eq!(frame_slots(&asm), 5);
+    assert_eq!(output(&code), "12 2\n");
+
+    assert_eq!(
+        error_kind("if true { let y: num = 1; } let z: num = y;"),
+        ErrorKind::OutOfScope
+    );
+    assert_eq!(
+        compile("let y: num = 1; while y < 3 { let y: bool = true; } y = y + 1;"),
+        Ok(compile("let y: num = 1; while y < 3 { let w: bool = true; } y = y + 1;").unwrap())
+    );
+}
+
+#[test]
+pub fn test_arrays() {
+    let code = read_file("tests/arrays.fr".to_string()).unwrap();
+    let asm = compile(&code).unwrap();
+    assert!(asm.contains(&"slli x5, x5, 2".to_string()));
+    assert!(!asm.contains(&"ebreak".to_string()));
+
+    let asm = compile_with(&code, Target::Rv32, |generator| {
+        generator.set_bounds_check(true)
+    })
+    .unwrap();
+    assert!(asm.contains(&"ebreak".to_string()));
+
+    assert_eq!(
+        error_kind("let a: [num; 2] = [1, 2, 3];"),
+        ErrorKind::MismatchedTypes
+    );
+    assert_eq!(
+        error_kind("let a: [num; 2] = [1, 2]; let b: num = a[2];"),
+        ErrorKind::IndexOutOfBounds
+    );
+    assert_eq!(
+        error_kind("let a: [num; 2] = [1, 2]; let b: num = a[-1];"),
+        ErrorKind::IndexOutOfBounds
+    );
+    assert_eq!(
+        error_kind("const LEN: num = 2; let a: [num; 2] = [1, 2]; a[LEN] = 3;"),
+        ErrorKind::IndexOutOfBounds
+    );
+    assert_eq!(
+        output("const LAST: num = 1; let a: [num; 2] = [1, 2]; println(a[LAST - 1] + a[LAST]);"),
+        "3\n"
+    );
+    assert_eq!(
+        error_kind("let a: [num; 2] = [1, 2]; a[0] = true;"),
+        ErrorKind::MismatchedTypes
+    );
+
+    // Blocks, ifs and matches can give an array, it is copied out of their scope
+    assert_eq!(
+        output(
+            "let a: [num; 3] = [1, 2, 3]; let c: bool = a[0] == 1; \
+             let b: [num; 3] = if c { a } else { a }; \
+             let d: [num; 3] = { a }; \
+             let e: [num; 3] = { let t: [num; 3] = [4, 5, 6]; t }; \
+             b = match a[1] { 2 => [7; 3], _ => a }; \
+             println(b[0], d[2], e[0], e[2]);"
+        ),
+        "7346\n"
+    );
+}
+
+#[test]
+pub fn test_print() {
+    let code = read_file("tests/print.fr".to_string()).unwrap();
+    let asm = compile(&code).unwrap();
+    assert!(asm.contains(&".section .rodata".to_string()));
+    assert!(asm.contains(&".string \"\\t\\\"quoted\\\"\\\\\"".to_string()));
+    assert!(asm.contains(&"jal x1, __frust_print_num".to_string()));
+    // write(1, buf, len)
+    assert!(asm.contains(&"addi x17, x0, 64".to_string()));
+
+    assert_eq!(
+        output(&code),
+        "0 1 1 2 3 5 8 13 21 34 \nnegative: -42, zero: 0, min: -2147483648\nflags: true false\t\"quoted\"\\\n"
+    );
+
+    assert_eq!(
+        error_kind("let a: [num; 1] = [1]; print(a);"),
+        ErrorKind::InvalidOperand
+    );
+    assert_eq!(error_kind("fn print() { }"), ErrorKind::ReservedName);
+    assert_eq!(
+        error_kind("fn __frust_print_num(x: num) { }"),
+        ErrorKind::ReservedName
+    );
+}
+
+#[test]
+pub fn test_break_continue() {
+    let code = read_file("tests/loops.fr".to_string()).unwrap();
+    // Inner loop jumps don't leave the outer loop
+    assert_eq!(
+        output(&code),
+        "1 1\n12 3\n1245 5\n12457 7\n124578 9\ndone 11\n"
+    );
+
+    assert_eq!(error_kind("if true { break; }"), ErrorKind::Misplaced);
+    assert_eq!(
+        error_kind("while true { fn f() { continue; } }"),
+        ErrorKind::Misplaced
+    );
+}
+
+#[test]
+pub fn test_for_range() {
+    let asm = compile("let n: num = 0; for i in 0..=10 step 2 { n = n + i; }").unwrap();
+    // Bounds are stored once and the loop steps by the distance to the end
+    assert!(contains_sequence(&asm, &["blt x6, x5, .Lendfor_*"]));
+    assert!(contains_sequence(&asm, &["bltu x6, x7, .Lendfor_*"]));
+
+    assert_eq!(
+        error_kind("for i in 0..10 { } let x: num = i;"),
+        ErrorKind::OutOfScope
+    );
+    assert_eq!(
+        error_kind("for i in 0..10 step 0 { }"),
+        ErrorKind::InvalidStep
+    );
+    assert_eq!(
+        error_kind("const Z: num = 2; for i in 0..10 step Z - 3 { }"),
+        ErrorKind::InvalidStep
+    );
+    // A step known only at runtime ends the loop before the body if it isn't positive
+    let asm = compile("let s: num = 0; for i in 0..3 step s { }").unwrap();
+    assert!(contains_sequence(&asm, &["bge x0, x7, .Lendfor_*"]));
+    assert_eq!(
+        error_kind("for i in 0..true { }"),
+        ErrorKind::MismatchedTypes
+    );
+}
+
+#[test]
+pub fn test_block_expressions() {
+    let asm = compile("let c: bool = true; let x: num = if c { 1 } else { 2 };").unwrap();
+    // Both branches leave their value in Temp(0) before the store
+    assert!(asm.contains(&"addi x5, x0, 1".to_string()));
+    assert!(contains_sequence(&asm, &[".Lelse_*:", "addi x5, x0, 2"]));
+    assert!(contains_sequence(&asm, &[".Lendif_*:", "sw x5, *(x8)"]));
+    assert_eq!(
+        output("let c: bool = false; let x: num = if c { 1 } else { 2 }; println(x);"),
+        "2\n"
+    );
+
+    let asm = compile("fn sq(a: num) -> num { let t: num = a; t * t }").unwrap();
+    assert!(asm.contains(&"mul x5, x6, x5".to_string()));
+    assert!(asm.contains(&"addi x10, x5, 0".to_string()));
+
+    assert!(compile("fn f(a: num) -> num { if a > 0 { return 1; } else { 2 } }").is_ok());
+    assert!(compile("let x: num = { let y: num = 2; y * y }; println({ x });").is_ok());
+    let code = read_file("tests/blocks.fr".to_string()).unwrap();
+    assert_eq!(output(&code), "1 43\n-1 0 1\n25\n20 -1\n88 true\n");
+
+    assert_eq!(
+        error_kind("let x: num = if true { 1 } else { false };"),
+        ErrorKind::MismatchedTypes
+    );
+    assert_eq!(
+        error_kind("let x: num = if true { 1 };"),
+        ErrorKind::MismatchedTypes
+    );
+    assert_eq!(
+        error_kind("let x: num = { let y: num = 2; y * y; };"),
+        ErrorKind::MismatchedTypes
+    );
+    assert_eq!(
+        error_kind("fn f() -> num { true }"),
+        ErrorKind::MismatchedTypes
+    );
+    assert_eq!(error_kind("let x: num = { 1 2 };"), ErrorKind::Syntax);
+}
+
+#[test]
+pub fn test_bitwise() {
+    let tokens = lexer("a >>> 1 >> 2 << 3 & ~b | c ^ d && e").unwrap();
+    assert_eq!(
+        tokens[1..],
+        [
+            Token::OpUshr,
+            Token::LitNumber(1),
+            Token::OpShr,
+            Token::LitNumber(2),
+            Token::OpShl,
+            Token::LitNumber(3),
+            Token::OpBitAnd,
+            Token::OpBitNot,
+            Token::Identifier("b".to_string()),
+            Token::OpBitOr,
+            Token::Identifier("c".to_string()),
+            Token::OpBitXor,
+            Token::Identifier("d".to_string()),
+            Token::OpAnd,
+            Token::Identifier("e".to_string()),
+        ]
+    );
+
+    // Bitwise operators bind tighter than comparisons, shifts looser than '+'
+    let code = "1 + 2 << 3 & 4 == 0;";
+    let operator = |op: &str| {
+        let start = code.find(op).unwrap();
+        Span::new(start, start + op.len())
+    };
+    let binary = |left: Expr, op: BinaryOp, symbol: &str, right: Expr| ExprKind::Binary {
+        left: Box::new(left),
+        op,
+        op_span: operator(symbol),
+        right: Box::new(right),
+    };
+    let number = |n: u64| Expr::from(ExprKind::Number(n));
+    let sum = binary(number(1), BinaryOp::Add, "+", number(2));
+    let shift = binary(sum.into(), BinaryOp::Shl, "<<", number(3));
+    let and = binary(shift.into(), BinaryOp::BitAnd, "&", number(4));
+    assert_eq!(
+        parse(code),
+        vec![binary(and.into(), BinaryOp::Eq, "==", number(0))]
+    );
+
+    let asm = compile("let x: num = ~(-8 >> 1) >>> 2;").unwrap();
+    assert!(asm.contains(&"sra x5, x6, x5".to_string()));
+    assert!(asm.contains(&"xori x5, x5, -1".to_string()));
+    assert!(asm.contains(&"srl x5, x6, x5".to_string()));
+    let asm = compile_with("let x: num = 1 << 31;", Target::Rv64, |_| ()).unwrap();
+    assert!(asm.contains(&"sllw x5, x6, x5".to_string()));
+    let code = read_file("tests/bitwise.fr".to_string()).unwrap();
+    assert_eq!(
+        output(&code),
+        "8 14 6 -13\n16 -4 15 2147483647\n24 true 3\ntrue true\n85 -2147483648\n"
+    );
+
+    assert_eq!(
+        error_kind("let x: num = 1 & true;"),
+        ErrorKind::InvalidOperand
+    );
+    assert_eq!(
+        error_kind("let x: bool = true << 1;"),
+        ErrorKind::InvalidOperand
+    );
+    assert_eq!(
+        error_kind("let x: bool = ~true;"),
+        ErrorKind::InvalidOperand
+    );
+}
+
+#[test]
+pub fn test_short_circuit() {
+    let asm = compile("let a: bool = true; let b: bool = a && false || a;").unwrap();
+    // The right operand is skipped by a branch and the result is normalized
+    assert!(contains_sequence(&asm, &["beq x5, x0, .Lendand_*"]));
+    assert!(contains_sequence(&asm, &[".Lendand_*:", "sltu x5, x0, x5"]));
+    assert!(contains_sequence(&asm, &["bne x5, x0, .Lendor_*"]));
+    assert!(contains_sequence(&asm, &[".Lendor_*:", "sltu x5, x0, x5"]));
+    assert!(!asm
+        .iter()
+        .any(|line| line.starts_with("and ") || line.starts_with("or ")));
+
+    let asm = compile("let a: bool = true & false;").unwrap();
+    assert!(asm.contains(&"and x5, x6, x5".to_string()));
+    // Skipped operands print nothing
+    let code = read_file("tests/logic.fr".to_string()).unwrap();
+    assert_eq!(
+        output(&code),
+        "2\n[1]false\n[1]true\n[1][2][3]true\n[1][2] false\n"
+    );
+}
+
+#[test]
+pub fn test_number_literals() {
+    assert_eq!(
+        lexer("0xFF 0b1010 0o17 1_000_000 0x_dead_BEEF").unwrap(),
+        vec![
+            Token::LitNumber(255),
+            Token::LitNumber(10),
+            Token::LitNumber(15),
+            Token::LitNumber(1_000_000),
+            Token::LitNumber(0xdead_beef),
+        ]
+    );
+    assert_eq!(
+        lexer("0x1_0000_0000_0000_0000").map_err(|err| err.kind),
+        Err(ErrorKind::InvalidNumber)
+    );
+    assert_eq!(
+        lexer("0b102").map_err(|err| err.kind),
+        Err(ErrorKind::InvalidNumber)
+    );
+    assert_eq!(
+        lexer("0o").map_err(|err| err.kind),
+        Err(ErrorKind::InvalidNumber)
+    );
+    assert_eq!(
+        lexer("12abc").map_err(|err| err.kind),
+        Err(ErrorKind::InvalidNumber)
+    );
+
+    let asm = compile("let x: num = -0x8000_0000;").unwrap();
+    assert!(asm.contains(&"lui x5, 524288".to_string()));
+    let code = read_file("tests/literals.fr".to_string()).unwrap();
+    assert_eq!(
+        output(&code),
+        "65535 170 493 1000000\n2147483647 -2147483648 -2147483648\n240 16 16\n"
+    );
+    assert_eq!(
+        error_kind("let x: num = 0xFFFF_FFFF;"),
+        ErrorKind::LiteralOutOfRange
+    );
+    assert_eq!(
+        error_kind("let x: num = -2147483649;"),
+        ErrorKind::LiteralOutOfRange
+    );
+    // The literal is quoted as it was written
+    let message = |code: &str| compile(code).unwrap_err().message;
+    assert_eq!(
+        message("let x: num = 0x1_0000_0000;"),
+        "Literal 0x1_0000_0000 is out of range for num"
+    );
+    assert_eq!(
+        message("let x: u8 = 0b1_0000_0000;"),
+        "Literal 0b1_0000_0000 is out of range for u8"
+    );
+    assert_eq!(
+        message("let x: u8 = -0b1;"),
+        "Literal -0b1 is out of range for u8"
+    );
+}
+
+#[test]
+pub fn test_int_types() {
+    let code = read_file("tests/ints.fr".to_string()).unwrap();
+    let asm = compile(&code).unwrap();
+    assert!(contains_sequence(&asm, &["lbu x5, *(x8)"]));
+    assert!(contains_sequence(&asm, &["sb x5, *(x8)"]));
+    assert!(asm.contains(&"andi x5, x5, 255".to_string()));
+    assert!(asm.contains(&"divu x5, x6, x5".to_string()));
+    assert!(asm.contains(&"sltu x5, x5, x6".to_string()));
+    assert!(asm.contains(&"sh x5, 0(x6)".to_string()));
+
+    let asm = compile_with(&code, Target::Rv64, |_| ()).unwrap();
+    assert!(contains_sequence(&asm, &["lwu x5, *(x8)"]));
+    assert!(asm.contains(&"divuw x5, x6, x5".to_string()));
+    assert_eq!(
+        output(&code),
+        "44 -128 -128 300\n4000000000 2000000000 3 true 3\n-3 -1 4294967295 -1\n\
+         -1 -25536 -32768 0\n10964 15 255 true\n"
+    );
+
+    let asm = compile_with(
+        "let x: i64 = 0x1_0000_0000; let y: i64 = x * x;",
+        Target::Rv64,
+        |_| (),
+    )
+    .unwrap();
+    assert!(asm.contains(&"slli x5, x5, 12".to_string()));
+    assert!(contains_sequence(&asm, &["sd x5, *(x8)"]));
+    assert!(asm.contains(&"mul x5, x6, x5".to_string()));
+    let code = "let x: i64 = 0x1_0000_0001; println(x * x, \" \", x as i32);";
+    assert_eq!(output_on(code, Target::Rv64), "8589934593 1\n");
+    assert_eq!(error_kind("let x: i64 = 1;"), ErrorKind::UnsupportedType);
+    assert_eq!(error_kind("const C: i64 = 1;"), ErrorKind::UnsupportedType);
+    assert_eq!(
+        error_kind("fn f() -> i64 { 1 }"),
+        ErrorKind::UnsupportedType
+    );
+    assert_eq!(
+        error_kind("let x: num = (1 as i64) as num;"),
+        ErrorKind::UnsupportedType
+    );
+
+    assert_eq!(error_kind("let x: u8 = 256;"), ErrorKind::LiteralOutOfRange);
+    assert_eq!(error_kind("let x: u16 = -1;"), ErrorKind::LiteralOutOfRange);
+    assert_eq!(
+        error_kind("let x: u8 = 1; let y: num = x + 1;"),
+        ErrorKind::MismatchedTypes
+    );
+    assert_eq!(
+        error_kind("let x: u8 = 1; let y: i8 = 2; let z: bool = x < y;"),
+        ErrorKind::InvalidOperand
+    );
+    assert_eq!(
+        error_kind("let x: u32 = 1; let y: u32 = -x;"),
+        ErrorKind::InvalidOperand
+    );
+    assert_eq!(
+        error_kind("let x: bool = true as bool;"),
+        ErrorKind::InvalidType
+    );
+}
+
+#[test]
+pub fn test_consts() {
+    let code = read_file("tests/consts.fr".to_string()).unwrap();
+    let asm = compile(&code).unwrap();
+    // Constants are loaded as immediates, never from the stack
+    assert!(asm.contains(&"addi x5, x0, 15".to_string()));
+    assert!(asm.contains(&"lui x5, 732423".to_string()));
+    assert!(!asm.iter().any(|line| line.starts_with("lbu")));
+
+    let code = "const A: num = 6; const B: num = A * 7; let x: num = B; println(x);";
+    let asm = compile(code).unwrap();
+    assert!(contains_sequence(
+        &asm,
+        &["addi x5, x0, 42", "sw x5, *(x8)"]
+    ));
+    assert_eq!(output(code), "42\n");
+
+    assert_eq!(
+        error_kind("const A: num = 1; A = 2;"),
+        ErrorKind::ImmutableAssign
+    );
+    assert_eq!(
+        error_kind("let x: num = 1; const A: num = x + 1;"),
+        ErrorKind::NotConstant
+    );
+    assert_eq!(
+        error_kind("fn f() -> num { 1 } const A: num = f();"),
+        ErrorKind::NotConstant
+    );
+    let error = compile("const A: num = 0; const B: num = 7 / A;").unwrap_err();
+    assert_eq!(
+        (error.kind, error.span),
+        (ErrorKind::DivisionByZero, Some(Span::new(35, 36)))
+    );
+    assert_eq!(
+        error_kind("const A: u8 = 0; const B: u8 = 5 % A;"),
+        ErrorKind::DivisionByZero
+    );
+    assert_eq!(
+        error_kind("{ const A: num = 1; } let x: num = A;"),
+        ErrorKind::OutOfScope
+    );
+    assert_eq!(
+        error_kind("const A: u8 = 256;"),
+        ErrorKind::LiteralOutOfRange
+    );
+}
+
+#[test]
+pub fn test_compound_assignment() {
+    assert_eq!(
+        lexer("+= -= *= /= %= &= |= ^= <<= >>= >>>=").unwrap(),
+        vec![
+            Token::OpAddAssign,
+            Token::OpSubAssign,
+            Token::OpMulAssign,
+            Token::OpDivAssign,
+            Token::OpModAssign,
+            Token::OpBitAndAssign,
+            Token::OpBitOrAssign,
+            Token::OpBitXorAssign,
+            Token::OpShlAssign,
+            Token::OpShrAssign,
+            Token::OpUshrAssign,
+        ]
+    );
+    assert_eq!(
+        parse("a[1] <<= 2;"),
+        vec![ExprKind::CompoundAssign {
+            target: Box::new(
+                ExprKind::Index {
+                    array: Box::new(ExprKind::Var("a".to_string()).into()),
+                    index: Box::new(ExprKind::Number(1).into()),
+                }
+                .into()
+            ),
+            op: BinaryOp::Shl,
+            expr: Box::new(ExprKind::Number(2).into()),
+        }]
+    );
+
+    // The variable is loaded and stored once, without going through the stack
+    let code = "let a: num = 1; a += 2; println(a);";
+    assert!(contains_sequence(
+        &compile(code).unwrap(),
+        &[
+            "addi x5, x0, 2",
+            "lw x6, *(x8)",
+            "add x5, x6, x5",
+            "sw x5, *(x8)"
+        ]
+    ));
+    assert_eq!(output(code), "3\n");
+    let code = read_file("tests/compound.fr".to_string()).unwrap();
+    let asm = compile(&code).unwrap();
+    assert_eq!(asm.iter().filter(|line| line.starts_with("lh ")).count(), 5);
+    assert_eq!(output(&code), "30\n60\n10000 20000 30000\ntrue\n");
+
+    assert_eq!(
+        error_kind("const A: num = 1; A += 1;"),
+        ErrorKind::ImmutableAssign
+    );
+    assert_eq!(
+        error_kind("let b: bool = true; b += true;"),
+        ErrorKind::InvalidOperand
+    );
+    assert_eq!(
+        error_kind("let x: u8 = 1; let y: num = 2; x *= y;"),
+        ErrorKind::InvalidOperand
+    );
+    assert_eq!(error_kind("1 += 2;"), ErrorKind::InvalidPlace);
+}
+
+#[test]
+pub fn test_modules() {
+    assert_eq!(
+        parse("import \"lib/util.fr\"; mod math; util::abs(1);"),
+        vec![
+            ExprKind::Import {
+                name: "util".to_string(),
+                path: "lib/util.fr".to_string(),
+            },
+            ExprKind::Import {
+                name: "math".to_string(),
+                path: "math.fr".to_string(),
+            },
+            ExprKind::Call {
+                name: "util::abs".to_string(),
+                args: vec![ExprKind::Number(1).into()],
+            },
+        ]
+    );
+
+    let mut sources = SourceMap::new();
+    let mut program = load_program("tests/modules/main.fr", &[], &mut sources).unwrap();
+    let names: Vec<&str> = program
+        .iter()
+        .filter_map(|stmt| match &stmt.expr.kind {
+            ExprKind::Function { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    // math is imported twice but compiled once
+    assert_eq!(
+        names,
+        [
+            "math::square",
+            "math::cube",
+            "util::abs",
+            "util::clamp",
+            "abs"
+        ]
+    );
+    TypeChecker::new().check(&mut program).unwrap();
+    let mut generator = CodeGenContext::new();
+    generator.generate_program(&program).unwrap();
+    let asm = generator.assembly().join("\n");
+    assert!(asm.contains("\nutil.abs:\n"));
+    assert!(asm.contains("\nabs:\n"));
+    assert!(asm.contains("jal x1, util.abs\n"));
+
+    // Constants, structs and enums of a module are namespaced like its functions
+    let mut program = load_program("tests/modules/items.fr", &[], &mut sources).unwrap();
+    TypeChecker::new().check(&mut program).unwrap();
+    let mut generator = CodeGenContext::new();
+    generator.generate_program(&program).unwrap();
+    assert_eq!(
+        run(&generator.assembly(), Target::Rv32).output,
+        "9 11 7 4\n3\narea\n"
+    );
+    assert_eq!(
+        parse("let p: util::Point = util::Point { x: util::K }; match p { util::E::A(x) => 1 };")
+            [0],
+        ExprKind::Let {
+            name: "p".to_string(),
+            name_span: Span::new(4, 5),
+            var_type: VarType::Struct("util::Point".to_string()),
+            expr: Box::new(
+                ExprKind::StructLiteral {
+                    name: "util::Point".to_string(),
+                    fields: vec![("x".to_string(), ExprKind::Var("util::K".to_string()).into())],
+                }
+                .into()
+            ),
+        }
+    );
+
+    let load = |path: &str| {
+        load_program(path, &[], &mut SourceMap::new()).map_err(|errors| errors[0].kind)
+    };
+    assert_eq!(load("tests/modules/cycle_a.fr"), Err(ErrorKind::Module));
+    assert_eq!(load("tests/modules/unimported.fr"), Err(ErrorKind::Module));
+    assert_eq!(error_kind("{ mod util; }"), ErrorKind::Misplaced);
+    assert_eq!(load("tests/modules/missing.fr"), Err(ErrorKind::Module));
+}
+
+#[test]
+pub fn test_macros() {
+    let args = ["frustc", "--define", "DEBUG", "-DLIMIT=20", "main.fr"];
+    let options = parse_args(args.iter().map(|arg| arg.to_string()).collect()).unwrap();
+    let defines = options.defines;
+    assert_eq!(defines[1], ("LIMIT".to_string(), "20".to_string()));
+    let source = preprocess("#define TWICE(x) (x) * 2\nTWICE(TWICE(3))", &[]).unwrap();
+    assert_eq!(source.code.trim(), "((3) * 2) * 2");
+    let source = preprocess("#define A B\n#define B A\nA B", &[]).unwrap();
+    assert_eq!(source.code.trim(), "A B");
+    let source = preprocess("#define N 1\nprintln(\"N\", N);", &[]).unwrap();
+    assert_eq!(source.code.trim(), "println(\"N\", 1);");
+
+    let code = "#ifdef DEBUG\na\n#ifndef DEBUG\nb\n#else\nc\n#endif\n#else\nd\n#endif\ne";
+    let source = preprocess(code, &defines).unwrap();
+    assert_eq!(
+        source.code.split_whitespace().collect::<Vec<_>>(),
+        ["a", "c", "e"]
+    );
+    let source = preprocess(code, &[]).unwrap();
+    assert_eq!(
+        source.code.split_whitespace().collect::<Vec<_>>(),
+        ["d", "e"]
+    );
+    // Directives and disabled lines are blanked, the code keeps the offsets of the original
+    assert_eq!(source.code.len(), code.len());
+    let d = code.find("\nd\n").unwrap() + 1;
+    assert_eq!(source.locate(d, d + 1), (9, 1, "d", 1));
+    assert_eq!(source.locate(code.len() - 1, code.len()), (11, 1, "e", 1));
+    // Expansions come from the whole macro call
+    let code = "#define N 3\n#define SQ(x) ((x) * (x))\nlet t: num = SQ(N) + SQ (2);";
+    let source = preprocess(code, &[]).unwrap();
+    let (square, two) = (
+        source.code.find("((3)").unwrap(),
+        source.code.find("((2)").unwrap(),
+    );
+    let text = "let t: num = SQ(N) + SQ (2);";
+    assert_eq!(source.locate(square + 1, square + 3), (3, 14, text, 5));
+    assert_eq!(source.locate(two, source.code.len()), (3, 22, text, 7));
+
+    let code = read_file("tests/macros.fr".to_string()).unwrap();
+    let source = preprocess(&code, &defines).unwrap();
+    let tokens = lexer(&source.code).unwrap();
+    assert!(tokens.contains(&Token::LitString("debug build".to_string())));
+    let source = preprocess("/* one\ntwo */\n#define X $\nlet a = X;", &[]).unwrap();
+    let err = lexer_with_spans(&source.code).unwrap_err();
+    let span = err.span.unwrap();
+    let (line, column, _, _) = source.locate(span.start, span.end);
+    assert_eq!(
+        (err.message.as_str(), line, column),
+        ("Unknown lexem", 4, 9)
+    );
+
+    assert_eq!(
+        preprocess("#define F(a, b) a + b\nF(1)", &[])
+            .err()
+            .map(|err| err.kind),
+        Some(ErrorKind::MacroArguments)
+    );
+    assert_eq!(
+        preprocess("#ifdef A\n#ifdef B\n#endif", &[])
+            .err()
+            .map(|err| err.kind),
+        Some(ErrorKind::UnbalancedConditional)
+    );
+    assert_eq!(
+        preprocess("\n#endif", &[]).err().map(|err| err.kind),
+        Some(ErrorKind::UnbalancedConditional)
+    );
+    assert_eq!(
+        preprocess("#include \"a.fr\"", &[])
+            .err()
+            .map(|err| err.kind),
+        Some(ErrorKind::UnknownDirective)
+    );
+    assert_eq!(
+        preprocess("#define F(x) x\nF(1", &[])
+            .err()
+            .map(|err| err.kind),
+        Some(ErrorKind::MacroArguments)
+    );
+}
+
+#[test]
+pub fn test_match() {
+    let value = |n: u64| Block {
+        statements: vec![],
+        value: Some(Box::new(ExprKind::Number(n).into())),
+    };
+    assert_eq!(
+        parse("match x { 0 | -1 => 1, 2..=9 => { 2 } _ => 3 }"),
+        vec![ExprKind::Match {
+            scrutinee: Box::new(ExprKind::Var("x".to_string()).into()),
+            arms: vec![
+                MatchArm {
+                    patterns: vec![
+                        Pattern::Value(ExprKind::Number(0).into()),
+                        Pattern::Value(
+                            ExprKind::Unary {
+                                op: UnaryOp::Neg,
+                                expr: Box::new(ExprKind::Number(1).into()),
+                            }
+                            .into()
+                        ),
+                    ],
+                    body: value(1),
+                },
+                MatchArm {
+                    patterns: vec![Pattern::Range(
+                        ExprKind::Number(2).into(),
+                        ExprKind::Number(9).into()
+                    )],
+                    body: value(2),
+                },
+                MatchArm {
+                    patterns: vec![Pattern::Wildcard],
+                    body: value(3),
+                },
+            ],
+        }]
+    );
+
+    let code = read_file("tests/match.fr".to_string()).unwrap();
+    let asm = compile(&code).unwrap();
+    // Only the dense match on u8 gets a jump table
+    assert_eq!(
+        asm.iter()
+            .filter(|line| line.ends_with("jalr x0, 0(x6)"))
+            .count(),
+        1
+    );
+    assert_eq!(
+        asm.iter()
+            .filter(|line| line.starts_with(".word .Larm"))
+            .count(),
+        7
+    );
+    assert_eq!(
+        output(&code),
+        "0 50 50 50 50 50 10 20 20 30 30 30 30 30 30 30 0 0 40 0\n\
+         7 1 2 3 4 5 5 0 0 \n1 2 3 4 5 6 0 0 \ntrue\nbig\n"
+    );
+
+    let code = "let b: bool = true; match b { false => println(0), true => println(1) }";
+    assert!(contains_sequence(
+        &compile(code).unwrap(),
+        &[
+            "beq x5, x0, .Larm_*",
+            "addi x6, x0, 1",
+            "beq x5, x6, .Larm_*",
+            "jal x0, .Lendmatch_*"
+        ]
+    ));
+    assert_eq!(output(code), "1\n");
+
+    assert_eq!(
+        error_kind("let x: num = 1; match x { 0 => 1, 1..=5 => 2 };"),
+        ErrorKind::NonExhaustiveMatch
+    );
+    assert_eq!(
+        error_kind("match true { true => 1 };"),
+        ErrorKind::NonExhaustiveMatch
+    );
+    // Constant patterns count by their value
+    assert!(
+        compile("const T: bool = true; const F: bool = !T; match T { T => 1, F => 2 };").is_ok()
+    );
+    assert_eq!(
+        error_kind("const T: bool = 1 < 2; match false { T => 1, true => 2 };"),
+        ErrorKind::NonExhaustiveMatch
+    );
+    assert_eq!(
+        error_kind("match true { false..=true => 1, _ => 0 };"),
+        ErrorKind::InvalidPattern
+    );
+    assert_eq!(
+        error_kind("let x: u8 = 1; match x { 256 => 1, _ => 0 };"),
+        ErrorKind::LiteralOutOfRange
+    );
+    assert_eq!(
+        error_kind("let x: num = 1; let y: num = 2; match x { y => 1, _ => 0 };"),
+        ErrorKind::InvalidPattern
+    );
+    assert_eq!(
+        error_kind("let x: num = 1; let y: num = match x { 0 => 1, _ => true };"),
+        ErrorKind::MismatchedTypes
+    );
+    assert_eq!(
+        error_kind("let x: num = 1; match x { 5..=1 => 1, _ => 0 };"),
+        ErrorKind::EmptyRange
+    );
+    let code = "const LOW: u8 = 9; let x: u8 = 1; match x { LOW..=3 => 1, _ => 0 };";
+    let mut program = Parser::new(lexer_with_spans(code).unwrap())
+        .parse()
+        .unwrap();
+    let error = TypeChecker::new().check(&mut program).unwrap_err();
+    assert_eq!(
+        (error.kind, error.message.as_str()),
+        (ErrorKind::EmptyRange, "Range pattern 9..=3 is empty")
+    );
+}
+
+#[test]
+pub fn test_structs() {
+    let field = |base: Expr, field: &str| -> Expr {
+        ExprKind::Field {
+            base: Box::new(base),
+            field: field.to_string(),
+        }
+        .into()
+    };
+    assert_eq!(
+        parse("p.corner.x = P { x: 1 }.x;"),
+        vec![ExprKind::Assign {
+            target: Box::new(field(
+                field(ExprKind::Var("p".to_string()).into(), "corner"),
+                "x"
+            )),
+            expr: Box::new(field(
+                ExprKind::StructLiteral {
+                    name: "P".to_string(),
+                    fields: vec![("x".to_string(), ExprKind::Number(1).into())],
+                }
+                .into(),
+                "x"
+            )),
+        }]
+    );
+
+    let code = read_file("tests/structs.fr".to_string()).unwrap();
+    let program = Parser::new(lexer_with_spans(&code).unwrap())
+        .parse()
+        .unwrap();
+    let layouts = TypeLayouts::from_program(&program, 4).unwrap();
+    let rect = VarType::Struct("Rect".to_string());
+    assert_eq!((layouts.size_of(&rect), layouts.align_of(&rect)), (24, 4));
+    assert_eq!(layouts.field("Rect", "size").unwrap().offset, 12);
+    assert_eq!(layouts.field("Rect", "tag").unwrap().offset, 20);
+    assert_eq!(layouts.scalar_fields(&rect)[4], (16, VarType::NUM));
+    assert!(compile(&code).is_ok());
+
+    // Fields are copied one by one through Temp(2)
+    let asm =
+        compile("struct P { x: num, b: u8 } let p: P = P { x: 1, b: 2 }; let q: P = p;").unwrap();
+    assert!(asm.contains(&"addi x6, x8, -16".to_string()));
+    assert!(asm.contains(&"lbu x7, 4(x5)".to_string()));
+    assert!(asm.contains(&"sb x7, 4(x6)".to_string()));
+
+    assert_eq!(
+        error_kind("struct P { x: num } struct P { y: num }"),
+        ErrorKind::DuplicateDefinition
+    );
+    assert_eq!(
+        error_kind("struct A { b: B } struct B { a: A }"),
+        ErrorKind::InvalidType
+    );
+    assert_eq!(
+        error_kind("struct P { x: num, y: num } let p: P = P { x: 1 };"),
+        ErrorKind::MissingField
+    );
+    assert_eq!(
+        error_kind("struct P { x: num } let p: P = P { x: true };"),
+        ErrorKind::MismatchedTypes
+    );
+    assert_eq!(
+        error_kind("struct P { x: num } let p: P = P { x: 1 }; let y: num = p.z;"),
+        ErrorKind::UnknownName
+    );
+    assert_eq!(
+        error_kind("let a: num = 1; let y: num = a.x;"),
+        ErrorKind::InvalidOperand
+    );
+    assert_eq!(
+        error_kind("fn f() { struct P { x: num } }"),
+        ErrorKind::Misplaced
+    );
+}
+
+#[test]
+pub fn test_enums() {
+    assert_eq!(
+        parse("match s { E::A(x, _) => x, E::B => 0 }; enum E { }")[0],
+        ExprKind::Match {
+            scrutinee: Box::new(ExprKind::Var("s".to_string()).into()),
+            arms: vec![
+                MatchArm {
+                    patterns: vec![Pattern::Variant {
+                        enum_name: "E".to_string(),
+                        variant: "A".to_string(),
+                        bindings: vec![
+                            Some(Binding {
+                                name: "x".to_string(),
+                                span: Span::default(),
+                            }),
+                            None,
+                        ],
+                    }],
+                    body: Block {
+                        statements: vec![],
+                        value: Some(Box::new(ExprKind::Var("x".to_string()).into())),
+                    },
+                },
+                MatchArm {
+                    patterns: vec![Pattern::Variant {
+                        enum_name: "E".to_string(),
+                        variant: "B".to_string(),
+                        bindings: vec![],
+                    }],
+                    body: Block {
+                        statements: vec![],
+                        value: Some(Box::new(ExprKind::Number(0).into())),
+                    },
+                },
+            ],
+        }
+    );
+
+    let code = read_file("tests/enums.fr".to_string()).unwrap();
+    let program = Parser::new(lexer_with_spans(&code).unwrap())
+        .parse()
+        .unwrap();
+    let layouts = TypeLayouts::from_program(&program, 4).unwrap();
+    let shape = VarType::Enum("Shape".to_string());
+    assert_eq!((layouts.size_of(&shape), layouts.align_of(&shape)), (20, 4));
+    let (tag, rect) = layouts.variant("Shape", "Rect").unwrap();
+    assert_eq!((tag, rect.fields[1].0), (1, 12));
+    assert_eq!(
+        layouts.variant("Shape", "Segment").unwrap().1.fields[1].0,
+        8
+    );
+    // The matches on all four variants dispatch through jump tables of tags
+    let asm = compile(&code).unwrap();
+    assert_eq!(
+        asm.iter()
+            .filter(|line| line.ends_with("jalr x0, 0(x6)"))
+            .count(),
+        3
+    );
+
+    // A variant is built in place: fields first, then the tag
+    let asm = compile("enum E { A(num), B } let e: E = E::A(7);").unwrap();
+    assert!(contains_sequence(
+        &asm,
+        &[
+            "addi x5, x0, 7",
+            "sw x5, *(x8)",
+            "addi x5, x0, 0",
+            "sw x5, *(x8)"
+        ]
+    ));
+
+    assert_eq!(
+        error_kind("enum E { A, B } let e: E = E::A; match e { E::A => 1 };"),
+        ErrorKind::NonExhaustiveMatch
+    );
+    assert_eq!(
+        error_kind("enum E { A(num) } let e: E = E::A;"),
+        ErrorKind::ArgumentCount
+    );
+    assert_eq!(
+        error_kind("enum E { A(num, num) } let e: E = E::A(1, 2); match e { E::A(x) => 1 };"),
+        ErrorKind::ArgumentCount
+    );
+    assert_eq!(
+        error_kind(
+            "enum E { A(num), B(num) } let e: E = E::A(1); match e { E::A(x) | E::B(x) => x };"
+        ),
+        ErrorKind::InvalidPattern
+    );
+    assert_eq!(
+        error_kind("enum E { A } let x: num = 1; match x { E::A => 1, _ => 0 };"),
+        ErrorKind::MismatchedTypes
+    );
+    assert_eq!(
+        error_kind("struct S { e: E } enum E { A(S) }"),
+        ErrorKind::InvalidType
+    );
+    assert_eq!(
+        error_kind("enum E { A(i64) } let e: E = E::A(1);"),
+        ErrorKind::UnsupportedType
+    );
+}
+
+#[test]
+pub fn test_pointers() {
+    let program = parse("let a: num = 1; *p += a; x = &mut *q;");
+    assert_eq!(
+        program[1],
+        ExprKind::CompoundAssign {
+            target: Box::new(
+                ExprKind::Deref(Box::new(ExprKind::Var("p".to_string()).into())).into()
+            ),
+            op: BinaryOp::Add,
+            expr: Box::new(ExprKind::Var("a".to_string()).into()),
+        }
+    );
+    assert_eq!(
+        program[2],
+        ExprKind::Assign {
+            target: Box::new(ExprKind::Var("x".to_string()).into()),
+            expr: Box::new(
+                ExprKind::Ref {
+                    mutable: true,
+                    expr: Box::new(
+                        ExprKind::Deref(Box::new(ExprKind::Var("q".to_string()).into())).into()
+                    ),
+                }
+                .into()
+            ),
+        }
+    );
+    let ExprKind::Function { params, .. } = &parse("fn f(r: &&mut [u8; 2]) {}")[0] else {
+        panic!("Expected a function");
+    };
+    assert_eq!(params[0].var_type.to_string(), "&&mut [u8; 2]");
+
+    let code = read_file("tests/pointers.fr".to_string()).unwrap();
+    assert!(compile(&code).is_ok());
+
+    // A reference is the frame address of the variable, it takes a whole register
+    let code = "let a: num = 1; let r: &mut num = &mut a; *r = 5; println(a);";
+    let asm = compile_with(code, Target::Rv64, |_| ()).unwrap();
+    assert!(contains_sequence(&asm, &["addi x5, x8, *", "sd x5, *(x8)"]));
+    assert!(contains_sequence(&asm, &["ld x5, *(x8)"]));
+    assert_eq!(output(code), "5\n");
+
+    assert_eq!(
+        error_kind("let a: num = 1; let r: &num = &a; *r = 2;"),
+        ErrorKind::ImmutableAssign
+    );
+    assert_eq!(
+        error_kind("struct P { x: num } fn f(p: &P) { p.x += 1; }"),
+        ErrorKind::ImmutableAssign
+    );
+    assert_eq!(
+        error_kind("let a: [num; 2] = [1, 2]; let r: &[num; 2] = &a; let m: &mut num = &mut r[0];"),
+        ErrorKind::ImmutableAssign
+    );
+    assert_eq!(
+        error_kind("let a: num = 1; let b: num = *a;"),
+        ErrorKind::InvalidOperand
+    );
+    assert_eq!(
+        error_kind("let a: num = 1; let r: &mut num = &a;"),
+        ErrorKind::MismatchedTypes
+    );
+    assert_eq!(
+        error_kind("const A: num = 1; let r: &num = &A;"),
+        ErrorKind::InvalidPlace
+    );
+    assert_eq!(
+        error_kind("let r: &num = &(1 + 2);"),
+        ErrorKind::InvalidPlace
+    );
+    assert_eq!(
+        error_kind("fn f(a: num) -> &num { &a }"),
+        ErrorKind::InvalidType
+    );
+    // References can't outlive the variables they point to
+    assert_eq!(
+        error_kind("let x: num = 1; let q: &num = &x; { let inner: num = 55; q = &inner; }"),
+        ErrorKind::DanglingReference
+    );
+    assert_eq!(error_kind("let x: num = 1; let q: &num = &x; { let p: &num = &x; let y: num = 2; p = &y; q = p; }"), ErrorKind::DanglingReference);
+    assert_eq!(
+        error_kind("let r: &num = { let z: num = 2; &z };"),
+        ErrorKind::DanglingReference
+    );
+    assert_eq!(
+        error_kind("fn f(out: &mut &num) { let local: num = 1; *out = &local; }"),
+        ErrorKind::DanglingReference
+    );
+    assert!(compile(
+        "let x: num = 1; let q: &num = &x; { let y: num = 2; let p: &num = &y; p = q; q = &x; }"
+    )
+    .is_ok());
+    assert!(compile("fn f(out: &mut &num, v: &num) { *out = v; }").is_ok());
+}
+
+#[test]
+pub fn test_diagnostics() {
+    let render = |path: &str| {
+        let mut sources = SourceMap::new();
+        let error = load_program(path, &[], &mut sources).and_then(|mut program| {
+            TypeChecker::new().check(&mut program)?;
+            Ok(CodeGenContext::new().generate_program(&program)?)
+        });
+        let errors = error.unwrap_err();
+        let rendered: Vec<String> = errors.iter().map(|err| sources.render(err)).collect();
+        rendered.join("\n\n")
+    };
+    assert_eq!(
+        render("tests/diagnostics/syntax.fr"),
+        [
+            "error[E0300]: Expected ';' at the end of statement",
+            " --> tests/diagnostics/syntax.fr:4:1",
+            "  |",
+            "4 | println(a, b);",
+            "  | ^^^^^^^",
+        ]
+        .join("\n")
+    );
+    // Errors point at the innermost expression
+    assert_eq!(
+        render("tests/diagnostics/types.fr"),
+        [
+            "error[E0509]: Operator '+=' cannot be applied to num and bool",
+            " --> tests/diagnostics/types.fr:4:9",
+            "  |",
+            "4 |         i += true;",
+            "  |         ^^^^^^^^^^",
+            "  = note: in function 'check'",
+        ]
+        .join("\n")
+    );
+    // Operand errors point at the operator, snippets show the original line
+    assert_eq!(
+        render("tests/diagnostics/macros.fr"),
+        [
+            "error[E0509]: Operator '+' expects num operands, found num and bool",
+            " --> tests/diagnostics/macros.fr:4:20",
+            "  |",
+            "4 | let t: num = SQ(N) + true;",
+            "  |                    ^^^^^^",
+        ]
+        .join("\n")
+    );
+    // Spans of imported files are located in their own file
+    assert_eq!(
+        render("tests/modules/cycle_a.fr"),
+        [
+            "error[E0400]: Import cycle: tests/modules/cycle_a.fr -> tests/modules/cycle_b.fr -> tests/modules/cycle_a.fr",
+            " --> tests/modules/cycle_b.fr:1:1",
+            "  |",
+            "1 | import \"cycle_a.fr\";",
+            "  | ^^^^^^^^^^^^^^^^^^^^",
+        ]
+        .join("\n")
+    );
+    // Errors of the preprocessor point at the line of the directive or of the macro call
+    assert_eq!(
+        render("tests/diagnostics/macro_call.fr"),
+        [
+            "error[E0103]: Macro SQ expects 1 arguments, got 2",
+            " --> tests/diagnostics/macro_call.fr:3:1",
+            "  |",
+            "3 | let a: num = SQ(1, 2);",
+            "  | ^^^^^^^^^^^^^^^^^^^^^^",
+        ]
+        .join("\n")
+    );
+    // Tabs are expanded in the line and under it alike
+    assert_eq!(
+        render("tests/diagnostics/tabs.fr"),
+        [
+            "error[E0500]: Mismatched types in 'let a': expected num, found bool",
+            " --> tests/diagnostics/tabs.fr:3:16",
+            "  |",
+            "3 |         let a: num = true;",
+            "  |                      ^^^^",
+            "  = note: in function 'main'",
+        ]
+        .join("\n")
+    );
+    let error = preprocess("let a: num = 1;\n  #ifdef A\n", &[])
+        .err()
+        .unwrap();
+    assert_eq!(
+        (error.kind, error.span),
+        (ErrorKind::UnbalancedConditional, Some(Span::new(18, 26)))
+    );
+    // Files that can't be opened have no location
+    assert!(render("tests/modules/missing.fr")
+        .starts_with("error[E0400]: Cannot open module tests/modules/missing.fr: "));
+}
+
+#[test]
+pub fn test_errors() {
+    let lex = |code: &str| lexer_with_spans(code).unwrap_err();
+    let error = lex("let a = 0b102;");
+    assert_eq!(
+        (error.kind, error.code(), error.span),
+        (ErrorKind::InvalidNumber, "E0200", Some(Span::new(8, 13)))
+    );
+    let error = lex("print(\"\\q\");");
+    assert_eq!(error.kind, ErrorKind::InvalidString);
+    assert!(error.help.is_some());
+    assert_eq!(lex("let a = $;").kind, ErrorKind::UnknownLexem);
+
+    let mut parser = Parser::new(lexer_with_spans("let a = ;").unwrap());
+    assert_eq!(parser.parse().unwrap_err()[0].kind, ErrorKind::Syntax);
+
+    let mut program = Parser::new(lexer_with_spans("let a: num = true;").unwrap())
+        .parse()
+        .unwrap();
+    let error = TypeChecker::new().check(&mut program).unwrap_err();
+    assert_eq!(
+        (error.kind, error.notes.len()),
+        (ErrorKind::MismatchedTypes, 0)
+    );
+
+    let mut program = Parser::new(lexer_with_spans("let a: i64 = 1;").unwrap())
+        .parse()
+        .unwrap();
+    let error = TypeChecker::new().check(&mut program).unwrap_err();
+    assert_eq!(
+        (error.kind, error.span),
+        (ErrorKind::UnsupportedType, Some(Span::new(0, 15)))
+    );
+
+    // Usage errors have no location, the usage is the help
+    let args = |args: &[&str]| parse_args(args.iter().map(|arg| arg.to_string()).collect());
+    let error = args(&["frustc", "--bogus", "a.fr"]).err().unwrap();
+    assert!(SourceMap::new()
+        .render(&error)
+        .starts_with("error[E0001]: Unknown option --bogus\n  = help: Usage: frustc "));
+    assert_eq!(args(&["frustc"]).err().unwrap().kind, ErrorKind::Usage);
+    assert_eq!(
+        read_file("tests/missing.fr".to_string()).unwrap_err().kind,
+        ErrorKind::Io
+    );
+}
+
+#[test]
+pub fn test_recovery() {
+    let code = read_file("tests/diagnostics/recovery.fr".to_string()).unwrap();
+    let source = preprocess(&code, &[]).unwrap();
+    let mut parser = Parser::new(lexer_with_spans(&source.code).unwrap());
+    let (program, errors) = parser.parse_partial();
+    let errors: Vec<(&str, usize)> = errors
+        .iter()
+        .map(|err| {
+            (
+                err.message.as_str(),
+                source.locate(err.span.unwrap().start, 0).0,
+            )
+        })
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            ("Unexpected token Semicolon", 2),
+            ("Expected ':' after parameter name", 4),
+            ("Expected ')'", 8),
+            ("Unexpected token Semicolon", 10),
+            ("Expected ',' or ']' in array literal", 14),
+        ]
+    );
+    // Statements with an error are kept as error nodes, blocks recover inside them
+    let program: Vec<ExprKind> = program.into_iter().map(|stmt| stmt.expr.kind).collect();
+    assert_eq!(program.len(), 6);
+    assert_eq!(program[0], ExprKind::Error);
+    assert!(matches!(program[1], ExprKind::Let { .. }));
+    assert_eq!(program[2], ExprKind::Error);
+    let ExprKind::Function { body, .. } = &program[3] else {
+        panic!("Expected function, got {:?}", program[3]);
+    };
+    let body: Vec<&ExprKind> = body.statements.iter().map(|stmt| &stmt.expr.kind).collect();
+    assert_eq!(body.len(), 3);
+    assert_eq!(*body[0], ExprKind::Error);
+    assert!(matches!(body[1], ExprKind::If { .. }));
+    assert_eq!(program[4], ExprKind::Error);
+    assert!(matches!(program[5], ExprKind::Call { .. }));
+
+    // A missing '}' is reported once at the end of the input
+    let mut parser = Parser::new(lexer_with_spans("fn f() { let a: num = ;").unwrap());
+    assert_eq!(
+        parser
+            .parse()
+            .unwrap_err()
+            .iter()
+            .map(|err| err.message.as_str())
+            .collect::<Vec<_>>(),
+        vec![
+            "Unexpected token Semicolon",
+            "Expected '}' at the end of block"
+        ]
+    );
+
+    // The token an error is found at can start the next statement
+    let errors = |code: &str| {
+        let mut parser = Parser::new(lexer_with_spans(code).unwrap());
+        parser
+            .parse()
+            .unwrap_err()
+            .into_iter()
+            .map(|err| (err.kind, err.span))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        errors("let a: num = 1\nlet b: num = ;"),
+        vec![
+            (ErrorKind::Syntax, Some(Span::new(15, 18))),
+            (ErrorKind::Syntax, Some(Span::new(28, 29))),
+        ]
+    );
+    assert_eq!(
+        errors("struct S { a: }\nprintln(1 +);"),
+        vec![
+            (ErrorKind::Syntax, Some(Span::new(14, 15))),
+            (ErrorKind::Syntax, Some(Span::new(27, 28))),
+        ]
+    );
+}
+
+#[test]
+pub fn test_comments() {
+    let code =
+        "let a /* x /* nested */ y */ = 1; // end\n/// doc\n//// not doc\nprintln(\"a // b\");";
+    let tokens = lexer_with_spans(code).unwrap();
+    assert_eq!(
+        tokens[..5],
+        [
+            (Token::KwLet, Span::new(0, 3)),
+            (Token::Identifier("a".to_string()), Span::new(4, 5)),
+            (
+                Token::BlockComment(" x /* nested */ y ".to_string()),
+                Span::new(6, 28)
+            ),
+            (Token::Assign, Span::new(29, 30)),
+            (Token::LitNumber(1), Span::new(31, 32)),
+        ]
+    );
+    let trivia: Vec<&Token> = tokens
+        .iter()
+        .map(|(token, _)| token)
+        .filter(|token| token.is_trivia())
+        .collect();
+    assert_eq!(
+        trivia[1..],
+        [
+            &Token::LineComment(" end".to_string()),
+            &Token::DocComment(" doc".to_string()),
+            &Token::LineComment("// not doc".to_string()),
+        ]
+    );
+    assert!(tokens.contains(&(Token::LitString("a // b".to_string()), Span::new(70, 78))));
+    let code = "let a: num /* x */ = 1; // end\n/// doc\nprintln(a /* a */);";
+    assert_eq!(compile(code).map(|_| ()), Ok(()));
+
+    let error = lexer_with_spans("let a = 1; /* /* */").unwrap_err();
+    assert_eq!(
+        (error.kind, error.span),
+        (ErrorKind::UnterminatedComment, Some(Span::new(11, 13)))
+    );
+
+    // Comments survive preprocessing, those of directives in their columns
+    let source = preprocess("#define N 2 // two\nlet a: num = N; /* N */", &[]).unwrap();
+    assert_eq!(source.code, "            // two\nlet a: num = 2; /* N */");
+    let code = "#define A 1 /* one\n#define B 2 */ let b: num = A;\n#ifdef B\nb\n#endif";
+    let source = preprocess(code, &[]).unwrap();
+    assert_eq!(
+        source.code,
+        "            /* one\n#define B 2 */ let b: num = 1;\n        \n \n      "
+    );
+}
+
+#[test]
+pub fn test_lints() {
+    let lints = |args: &[&str]| {
+        let args = ["frustc"].iter().chain(args).chain(&["tests/lints.fr"]);
+        let options = parse_args(args.map(|arg| arg.to_string()).collect());
+        let options = options.map_err(|err| err.message).unwrap();
+        let mut sources = SourceMap::new();
+        let mut program = load_program("tests/lints.fr", &[], &mut sources).unwrap();
+        TypeChecker::new().check(&mut program).unwrap();
+        let warnings = lint(&program, &options.lints, Target::Rv32);
+        let rendered: Vec<String> = warnings
+            .iter()
+            .map(|warning| sources.render(warning))
+            .collect();
+        (warnings, rendered)
+    };
+    // Variables read through a reference, `_` names, conditions using constants
+    // and loops ended by a break aren't linted
+    let (warnings, rendered) = lints(&[]);
+    assert_eq!(
+        warnings
+            .iter()
+            .map(|warning| (warning.code(), warning.severity))
+            .collect::<Vec<_>>(),
+        vec![
+            ("W0001", Severity::Warning),
+            ("W0002", Severity::Warning),
+            ("W0003", Severity::Warning),
+            ("W0004", Severity::Warning),
+            ("W0005", Severity::Warning),
+        ]
+    );
+    assert_eq!(
+        rendered[1],
+        [
+            "warning[W0002]: Value assigned to result is never read",
+            " --> tests/lints.fr:4:5",
+            "  |",
+            "4 |     let result: num = 0;",
+            "  |     ^^^^^^^^^^^^^^^^^^^^",
+            "  = note: `-W unused_assignments` is on by default",
+        ]
+        .join("\n")
+    );
+    assert!(rendered[2].contains("tests/lints.fr:25:5"));
+    // Unused variables point at their name
+    assert_eq!(
+        rendered[0],
+        [
+            "warning[W0001]: Unused variable unused",
+            " --> tests/lints.fr:3:22",
+            "  |",
+            "3 | fn first(value: num, unused: num) -> num {",
+            "  |                      ^^^^^^^^^^^",
+            "  = note: `-W unused_variables` is on by default",
+            "  = help: if this is intentional, prefix it with an underscore: _unused",
+        ]
+        .join("\n")
+    );
+    let code = "enum E { A(num, num) } let e: E = E::A(1, 2); match e { E::A(x, y) => println(x) }";
+    let mut program = Parser::new(lexer_with_spans(code).unwrap())
+        .parse()
+        .unwrap();
+    TypeChecker::new().check(&mut program).unwrap();
+    let warnings = lint(&program, &LintLevels::new(), Target::Rv32);
+    let start = code.find("y)").unwrap();
+    assert_eq!(warnings[0].span, Some(Span::new(start, start + 1)));
+
+    let (warnings, _) = lints(&["-D", "warnings", "-A", "unused_variables"]);
+    assert_eq!(warnings.len(), 4);
+    assert!(warnings
+        .iter()
+        .all(|warning| warning.severity == Severity::Error));
+    assert_eq!(warnings[0].notes, vec!["`-D warnings` is set".to_string()]);
+    let (warnings, _) = lints(&["-Awarnings", "-Wwhile_false", "--define", "LIMIT=3"]);
+    assert_eq!(
+        (warnings.len(), warnings[0].kind),
+        (1, ErrorKind::Lint(Lint::WhileFalse))
+    );
+
+    let args = [
+        "frustc",
+        "-D",
+        "unused_variables",
+        "--define=warnings",
+        "a.fr",
+    ];
+    let options = parse_args(args.iter().map(|arg| arg.to_string()).collect())
+        .map_err(|err| err.message)
+        .unwrap();
+    assert_eq!(
+        options.defines,
+        vec![("warnings".to_string(), "1".to_string())]
+    );
+    assert_eq!(options.lints.level(Lint::UnusedVariables), Level::Deny);
+    let args = ["frustc", "-W", "unused", "a.fr"];
+    let error = parse_args(args.iter().map(|arg| arg.to_string()).collect()).err();
+    assert_eq!(error.unwrap().message, "Unknown lint unused");
+    // `-D` defines a macro when it isn't given a lint
+    let args = ["frustc", "-D", "N=5", "-D", "DEBUG", "-Dwarnings", "a.fr"];
+    let options = parse_args(args.iter().map(|arg| arg.to_string()).collect()).unwrap();
+    assert_eq!(
+        options.defines,
+        vec![
+            ("N".to_string(), "5".to_string()),
+            ("DEBUG".to_string(), "1".to_string())
+        ]
+    );
+    assert_eq!(options.lints.level(Lint::WhileFalse), Level::Deny);
+
+    assert_eq!(
+        lint_messages("fn f() -> num { return 1; 2 }"),
+        vec!["Unreachable code after 'return'".to_string()]
+    );
+    assert_eq!(
+        lint_messages("while true { for i in 0..3 { if i > 1 { break; } } } println(1);"),
+        vec!["Unreachable code after a loop that never ends".to_string()]
+    );
+
+    // Values are followed to the end of the scope of their variable, through every branch
+    let never_read = |name: &str| format!("Value assigned to {} is never read", name);
+    assert_eq!(
+        lint_messages("let x: num = 1; x = 2; println(x); x = 3;"),
+        vec![never_read("x"), never_read("x")]
+    );
+    assert_eq!(
+        lint_messages("fn f() { let y: num = 5; println(y); y = 6; }"),
+        vec![never_read("y")]
+    );
+    assert_eq!(
+        lint_messages(
+            "enum Shape { Empty, Dot(num) } let a: Shape = Shape::Dot(1); \
+             match a { Shape::Dot(n) => { println(n); a = Shape::Empty; } _ => println(0) }"
+        ),
+        vec![never_read("a")]
+    );
+    assert_eq!(
+        lint_messages("let x: num = 0; while true { x = 1; if x > 0 { break; } x = 3; }"),
+        vec![never_read("x"), never_read("x")]
+    );
+    // A variable that is never read is only reported as unused
+    assert_eq!(
+        lint_messages("let x: num = 1; x = 2; x = 3;"),
+        vec!["Unused variable x".to_string()]
+    );
+    // Values read by the next run of a loop, after a break or after a shadowing block are used
+    assert!(lint_messages("let i: num = 0; while i < 3 { println(i); i = i + 1; }").is_empty());
+    assert!(lint_messages(
+        "let x: num = 0; for i in 0..3 { if i == 1 { x = i; break; } } println(x);"
+    )
+    .is_empty());
+    assert!(
+        lint_messages("let x: num = 1; { let x: num = 2; println(x); } println(x);").is_empty()
+    );
+}
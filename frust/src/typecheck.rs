@@ -0,0 +1,1475 @@
+use crate::ast::*;
+use crate::codegen::{Target, MAX_ARGUMENTS, RUNTIME_PREFIX};
+use crate::consteval::{evaluate, is_constant, Constant};
+use crate::diagnostic::{CompileError, ErrorKind, SourceMap};
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+#[derive(Clone)]
+struct Variable {
+    var_type: VarType,
+    value: Option<i64>, // constants keep their value, their uses can be evaluated
+    lifetime: usize,    // deepest scope a reference can point into, 0 for other variables
+}
+
+struct FunctionSignature {
+    params: Vec<VarType>,
+    ret_type: VarType,
+}
+
+/// Semantic pass between parsing and codegen,
+/// infers the type of every expression and rejects ill-typed programs
+pub struct TypeChecker<'a> {
+    functions: HashMap<String, FunctionSignature>,
+    structs: HashMap<String, Vec<Param>>, // struct -> fields
+    enums: HashMap<String, Vec<EnumVariant>>,
+    variables: Vec<HashMap<String, Variable>>, // scope stack
+    expired: HashSet<String>,                  // variables of already closed scopes
+    current_function: Option<(String, VarType)>, // name, return type
+    loop_depth: usize,
+    span: Span, // statement being checked
+    target: Target,
+    sources: Option<&'a SourceMap>,
+}
+
+impl<'a> TypeChecker<'a> {
+    pub fn new() -> Self {
+        TypeChecker {
+            functions: HashMap::new(),
+            structs: HashMap::new(),
+            enums: HashMap::new(),
+            variables: vec![HashMap::new()],
+            expired: HashSet::new(),
+            current_function: None,
+            loop_depth: 0,
+            span: Span::default(),
+            target: Target::Rv32,
+            sources: None,
+        }
+    }
+
+    /// Constants are evaluated with the integer semantics of the target
+    pub fn set_target(&mut self, target: Target) {
+        self.target = target;
+    }
+
+    /// Lets messages quote the source, e.g. a literal as it was written
+    pub fn set_sources(&mut self, sources: &'a SourceMap) {
+        self.sources = Some(sources);
+    }
+
+    /// Integer literals get the type their context expects, literals of types other than num
+    /// are wrapped into a cast to that type, so codegen doesn't have to infer it again.
+    /// An error points at the innermost expression it was found in
+    /// and notes the function it belongs to
+    pub fn check(&mut self, program: &mut [Stmt]) -> Result<(), CompileError> {
+        self.check_program(program)
+            .map_err(|error| match &self.current_function {
+                Some((name, _)) => error.note(format!("in function '{}'", name)),
+                None => error,
+            })
+    }
+
+    fn check_program(&mut self, program: &mut [Stmt]) -> Result<(), CompileError> {
+        // Types and functions can be used before their definition
+        let mut declarations = HashMap::new();
+        for Stmt { expr, span } in program.iter() {
+            self.span = *span;
+            let (kind, name) = match &expr.kind {
+                ExprKind::Struct { name, .. } => ("Struct", name),
+                ExprKind::Enum { name, .. } => ("Enum", name),
+                _ => continue,
+            };
+            if self.structs.contains_key(name) || self.enums.contains_key(name) {
+                let message = format!("{} {} is defined multiple times", kind, name);
+                return Err(self.error(ErrorKind::DuplicateDefinition, message));
+            }
+            if let ExprKind::Struct { fields, .. } = &expr.kind {
+                self.structs.insert(name.clone(), fields.clone());
+            }
+            if let ExprKind::Enum { variants, .. } = &expr.kind {
+                self.enums.insert(name.clone(), variants.clone());
+            }
+            declarations.insert(name.clone(), *span);
+        }
+        let mut types: Vec<VarType> = (self
+            .structs
+            .keys()
+            .map(|name| VarType::Struct(name.clone())))
+        .chain(self.enums.keys().map(|name| VarType::Enum(name.clone())))
+        .collect();
+        types.sort_by_key(|var_type| var_type.to_string());
+        for var_type in &types {
+            self.span = declarations[&var_type.to_string()];
+            self.check_member(var_type, &mut vec![])?;
+        }
+
+        for Stmt { expr, span } in program.iter() {
+            self.span = *span;
+            if let ExprKind::Function {
+                name,
+                params,
+                ret_type,
+                ..
+            } = &expr.kind
+            {
+                if params.len() > MAX_ARGUMENTS {
+                    let message = format!(
+                        "Function '{}' has more than {} parameters",
+                        name, MAX_ARGUMENTS
+                    );
+                    return Err(self.error(ErrorKind::TooManyArguments, message));
+                }
+                for param in params {
+                    let by_reference = matches!(param.var_type, VarType::Ref(..));
+                    if !param.var_type.is_scalar() && !param.var_type.is_compound() && !by_reference
+                    {
+                        let message = format!(
+                            "Parameter {} of function '{}' has type {}, only integers, bool, structs, enums and references can be parameters",
+                            param.name, name, param.var_type
+                        );
+                        return Err(self.error(ErrorKind::InvalidType, message));
+                    }
+                    self.validate_type(&param.var_type)?;
+                }
+                if let Some(ret_type) = ret_type.as_ref().filter(|t| !t.is_scalar()) {
+                    let message = format!(
+                        "Function '{}' cannot return a value of type {}",
+                        name, ret_type
+                    );
+                    return Err(self.error(ErrorKind::InvalidType, message));
+                }
+                if let Some(ret_type) = ret_type {
+                    self.validate_type(ret_type)?;
+                }
+                let signature = FunctionSignature {
+                    params: params.iter().map(|p| p.var_type.clone()).collect(),
+                    ret_type: ret_type.clone().unwrap_or(VarType::Unit),
+                };
+                if is_builtin(name) {
+                    let message =
+                        format!("Function '{}' is a builtin and can't be redefined", name);
+                    return Err(self.error(ErrorKind::ReservedName, message));
+                }
+                if is_reserved(name) {
+                    let message =
+                        format!("Function '{}' uses a name reserved by the compiler", name);
+                    return Err(self.error(ErrorKind::ReservedName, message));
+                }
+                if self.functions.insert(name.clone(), signature).is_some() {
+                    let message = format!("Function '{}' is defined multiple times", name);
+                    return Err(self.error(ErrorKind::DuplicateDefinition, message));
+                }
+            }
+        }
+
+        for stmt in program {
+            self.check_statement(stmt)?;
+        }
+        Ok(())
+    }
+
+    fn check_statement(&mut self, stmt: &mut Stmt) -> Result<VarType, CompileError> {
+        let outer = mem::replace(&mut self.span, stmt.span);
+        let var_type = self.infer(&mut stmt.expr)?;
+        self.span = outer;
+        Ok(var_type)
+    }
+
+    /// Error at the expression or statement being checked
+    fn error(&self, kind: ErrorKind, message: String) -> CompileError {
+        CompileError::new(kind, message).at(self.span)
+    }
+
+    /// Whether a type can be a field of a struct or a variant, the types of the fields
+    /// are checked too since a struct or an enum can't contain itself
+    fn check_member(
+        &self,
+        var_type: &VarType,
+        enclosing: &mut Vec<String>,
+    ) -> Result<bool, CompileError> {
+        let (kind, name) = match var_type {
+            VarType::Int(_) | VarType::Bool => return self.validate_type(var_type).map(|_| true),
+            VarType::Struct(name) => ("Struct", name),
+            VarType::Enum(name) => ("Enum", name),
+            _ => return Ok(false),
+        };
+        if enclosing.iter().any(|outer| outer == name) {
+            let message = format!("{} {} contains itself", kind, name);
+            return Err(self.error(ErrorKind::InvalidType, message));
+        }
+        enclosing.push(name.clone());
+        match (var_type, self.structs.get(name), self.enums.get(name)) {
+            (VarType::Struct(_), Some(fields), _) => self.check_struct(name, fields, enclosing)?,
+            (VarType::Enum(_), _, Some(variants)) => self.check_enum(name, variants, enclosing)?,
+            _ => {
+                let message = format!("Unknown type {}", name);
+                return Err(self.error(ErrorKind::UnknownName, message));
+            }
+        }
+        enclosing.pop();
+        Ok(true)
+    }
+
+    fn check_struct(
+        &self,
+        name: &str,
+        fields: &[Param],
+        enclosing: &mut Vec<String>,
+    ) -> Result<(), CompileError> {
+        if fields.is_empty() {
+            let message = format!("Struct {} must have at least one field", name);
+            return Err(self.error(ErrorKind::InvalidType, message));
+        }
+        for (i, field) in fields.iter().enumerate() {
+            if fields[..i].iter().any(|other| other.name == field.name) {
+                let message = format!(
+                    "Field {} is declared multiple times in struct {}",
+                    field.name, name
+                );
+                return Err(self.error(ErrorKind::DuplicateDefinition, message));
+            }
+            if !self.check_member(&field.var_type, enclosing)? {
+                let message = format!(
+                    "Field {} of struct {} has type {}, only integers, bool, structs and enums can be fields",
+                    field.name, name, field.var_type
+                );
+                return Err(self.error(ErrorKind::InvalidType, message));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_enum(
+        &self,
+        name: &str,
+        variants: &[EnumVariant],
+        enclosing: &mut Vec<String>,
+    ) -> Result<(), CompileError> {
+        if variants.is_empty() {
+            let message = format!("Enum {} must have at least one variant", name);
+            return Err(self.error(ErrorKind::InvalidType, message));
+        }
+        for (i, variant) in variants.iter().enumerate() {
+            if variants[..i].iter().any(|other| other.name == variant.name) {
+                let message = format!(
+                    "Variant {} is declared multiple times in enum {}",
+                    variant.name, name
+                );
+                return Err(self.error(ErrorKind::DuplicateDefinition, message));
+            }
+            for field in &variant.fields {
+                if !self.check_member(field, enclosing)? {
+                    let message = format!(
+                        "Variant {}::{} has a field of type {}, only integers, bool, structs and enums can be fields",
+                        name, variant.name, field
+                    );
+                    return Err(self.error(ErrorKind::InvalidType, message));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn variant_fields(&self, name: &str, variant: &str) -> Result<Vec<VarType>, CompileError> {
+        self.enums[name]
+            .iter()
+            .find(|declared| declared.name == variant)
+            .map(|declared| declared.fields.clone())
+            .ok_or_else(|| {
+                let message = format!("Enum {} has no variant {}", name, variant);
+                self.error(ErrorKind::UnknownName, message)
+            })
+    }
+
+    fn field_type(&self, name: &str, field: &str) -> Result<VarType, CompileError> {
+        self.structs[name]
+            .iter()
+            .find(|declared| declared.name == field)
+            .map(|declared| declared.var_type.clone())
+            .ok_or_else(|| {
+                let message = format!("Struct {} has no field {}", name, field);
+                self.error(ErrorKind::UnknownName, message)
+            })
+    }
+
+    /// Arrays hold integer or bool elements and can't be empty,
+    /// integers wider than a register need the 64-bit target
+    fn validate_type(&self, var_type: &VarType) -> Result<(), CompileError> {
+        match var_type {
+            VarType::Int(int_type) if int_type.bits() > self.target.xlen() => {
+                let message = format!("Type {} is only supported with --target=rv64", int_type);
+                Err(self.error(ErrorKind::UnsupportedType, message))
+            }
+            VarType::Unit => {
+                let message = "Variables cannot have type ()".to_string();
+                Err(self.error(ErrorKind::InvalidType, message))
+            }
+            VarType::Array(element, len) => {
+                if !element.is_scalar() {
+                    let message = format!("Arrays of {} are not supported", element);
+                    return Err(self.error(ErrorKind::InvalidType, message));
+                }
+                if *len == 0 {
+                    let message = "Arrays must have at least one element".to_string();
+                    return Err(self.error(ErrorKind::InvalidType, message));
+                }
+                self.validate_type(element)
+            }
+            VarType::Struct(name) if !self.structs.contains_key(name) => {
+                let message = format!("Unknown type {}", name);
+                Err(self.error(ErrorKind::UnknownName, message))
+            }
+            VarType::Enum(name) if !self.enums.contains_key(name) => {
+                let message = format!("Unknown type {}", name);
+                Err(self.error(ErrorKind::UnknownName, message))
+            }
+            VarType::Ref(pointee, _) => self.validate_type(pointee),
+            _ => Ok(()),
+        }
+    }
+
+    /// A `&mut T` can be used where a `&T` is expected, a mismatch points at the expression
+    fn expect(
+        &mut self,
+        expr: &mut Expr,
+        expected: &VarType,
+        context: &str,
+    ) -> Result<(), CompileError> {
+        let found = self.infer_as(expr, Some(expected))?;
+        let shared = match &found {
+            VarType::Ref(pointee, true) => *expected == VarType::Ref(pointee.clone(), false),
+            _ => false,
+        };
+        if found != *expected && found != VarType::Never && !shared {
+            let message = format!(
+                "Mismatched types in {}: expected {}, found {}",
+                context, expected, found
+            );
+            let error = CompileError::new(ErrorKind::MismatchedTypes, message);
+            return Err(error.at(expr.span));
+        }
+        Ok(())
+    }
+
+    /// Type of the block is the type of its trailing expression,
+    /// a block without one is `!` if some statement never completes and `()` otherwise
+    fn check_block(
+        &mut self,
+        block: &mut Block,
+        hint: Option<&VarType>,
+    ) -> Result<VarType, CompileError> {
+        self.variables.push(HashMap::new());
+        let mut diverges = false;
+        for stmt in &mut block.statements {
+            diverges |= self.check_statement(stmt)? == VarType::Never;
+        }
+        let block_type = match &mut block.value {
+            Some(value) => self.infer_as(value, hint)?,
+            None if diverges => VarType::Never,
+            None => VarType::Unit,
+        };
+        let outer_depth = self.variables.len() - 2;
+        if let (VarType::Ref(..), Some(value)) = (&block_type, &block.value) {
+            if self.lifetime(value) > outer_depth {
+                let message =
+                    "A block cannot evaluate to a reference to one of its variables".to_string();
+                return Err(self.error(ErrorKind::DanglingReference, message));
+            }
+        }
+        let scope = self.variables.pop().expect("Scope stack is empty");
+        self.expired.extend(scope.into_keys());
+        Ok(block_type)
+    }
+
+    fn declare_variable(&mut self, name: String, var_type: VarType) -> Option<VarType> {
+        self.declare(name, var_type, None)
+    }
+
+    fn declare_constant(&mut self, name: String, var_type: VarType, value: i64) {
+        self.declare(name, var_type, Some(value));
+    }
+
+    fn declare(&mut self, name: String, var_type: VarType, value: Option<i64>) -> Option<VarType> {
+        self.variables
+            .last_mut()
+            .expect("Scope stack is empty")
+            .insert(
+                name,
+                Variable {
+                    var_type,
+                    value,
+                    lifetime: 0,
+                },
+            )
+            .map(|variable| variable.var_type)
+    }
+
+    fn is_constant(&self, name: &str) -> bool {
+        self.lookup_constant(name).is_some()
+    }
+
+    fn lookup_constant(&self, name: &str) -> Option<Constant> {
+        let variable = self
+            .variables
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))?;
+        Some(Constant {
+            value: variable.value?,
+            var_type: variable.var_type.clone(),
+        })
+    }
+
+    /// Value of an expression if it is known at compile time
+    fn constant_value(&self, expr: &Expr) -> Result<Option<i64>, CompileError> {
+        if !is_constant(expr, &|name| self.is_constant(name)) {
+            return Ok(None);
+        }
+        let constant = evaluate(expr, self.target, &|name| self.lookup_constant(name))?;
+        Ok(Some(constant.value))
+    }
+
+    /// Values in patterns are compared with the matched value, they must be known at compile time
+    fn check_pattern(
+        &mut self,
+        value: &mut Expr,
+        scrutinee_type: &VarType,
+    ) -> Result<(), CompileError> {
+        self.expect(value, scrutinee_type, "match pattern")?;
+        if !is_constant(value, &|name| self.is_constant(name)) {
+            let message = "Match patterns can only be literals and constants".to_string();
+            return Err(self.error(ErrorKind::InvalidPattern, message));
+        }
+        Ok(())
+    }
+
+    fn check_variant_pattern(
+        &mut self,
+        enum_name: &str,
+        variant: &str,
+        bindings: &[Option<Binding>],
+        scrutinee_type: &VarType,
+    ) -> Result<(), CompileError> {
+        if *scrutinee_type != VarType::Enum(enum_name.to_string()) {
+            let message = format!(
+                "Mismatched types in match pattern: expected {}, found {}",
+                scrutinee_type, enum_name
+            );
+            return Err(self.error(ErrorKind::MismatchedTypes, message));
+        }
+        let fields = self.variant_fields(enum_name, variant)?;
+        if bindings.len() != fields.len() {
+            let message = format!(
+                "Pattern {}::{} binds {} fields, but the variant has {}",
+                enum_name,
+                variant,
+                bindings.len(),
+                fields.len()
+            );
+            return Err(self.error(ErrorKind::ArgumentCount, message));
+        }
+        for (i, binding) in bindings.iter().enumerate() {
+            let Some(binding) = binding else { continue };
+            if bindings[..i]
+                .iter()
+                .flatten()
+                .any(|other| other.name == binding.name)
+            {
+                let message = format!(
+                    "Variable {} is bound multiple times in {}::{} pattern",
+                    binding.name, enum_name, variant
+                );
+                let error = CompileError::new(ErrorKind::DuplicateDefinition, message);
+                return Err(error.at(binding.span));
+            }
+        }
+        Ok(())
+    }
+
+    /// Indexing and field access see through one reference, `base` is rewritten to `*base`
+    fn infer_auto_deref(&mut self, base: &mut Box<Expr>) -> Result<VarType, CompileError> {
+        let VarType::Ref(pointee, _) = self.infer(base)? else {
+            return self.infer(base);
+        };
+        let span = base.span;
+        let pointer = mem::replace(base.as_mut(), ExprKind::Bool(false).into());
+        **base = Expr::new(ExprKind::Deref(Box::new(pointer)), span);
+        base.var_type = Some(*pointee.clone());
+        Ok(*pointee)
+    }
+
+    /// A place can be changed unless it is a constant or it is reached through a `&` reference
+    fn check_mutable(&mut self, place: &mut Expr, action: &str) -> Result<(), CompileError> {
+        match &mut place.kind {
+            // Constants have no address, only assignments reach them
+            ExprKind::Var(name) if self.is_constant(name) => {
+                let message = format!("Cannot assign to constant {}", name);
+                Err(self.error(ErrorKind::ImmutableAssign, message))
+            }
+            ExprKind::Index { array: base, .. } | ExprKind::Field { base, .. } => {
+                self.check_mutable(base, action)
+            }
+            ExprKind::Deref(pointer) => match self.infer(pointer)? {
+                VarType::Ref(_, true) => Ok(()),
+                pointer_type => {
+                    let message = format!(
+                        "Cannot {} through an immutable reference of type {}",
+                        action, pointer_type
+                    );
+                    Err(self.error(ErrorKind::ImmutableAssign, message))
+                }
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Scope depth of a variable and the variable
+    fn find_variable(&self, name: &str) -> Option<(usize, &Variable)> {
+        self.variables
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(depth, scope)| scope.get(name).map(|variable| (depth, variable)))
+    }
+
+    /// Deepest scope a reference value can point into. Parameters point into the caller,
+    /// which outlives every scope, blocks may point anywhere up to the current scope
+    fn lifetime(&self, expr: &Expr) -> usize {
+        match &expr.kind {
+            ExprKind::Ref { expr: place, .. } => self.place_lifetime(place),
+            ExprKind::Var(name) => self
+                .find_variable(name)
+                .map_or(0, |(_, variable)| variable.lifetime),
+            ExprKind::Deref(pointer) => self.lifetime(pointer),
+            ExprKind::Index { array: base, .. } | ExprKind::Field { base, .. } => {
+                self.lifetime(base)
+            }
+            _ => self.variables.len() - 1,
+        }
+    }
+
+    /// Deepest scope holding the storage of a place
+    fn place_lifetime(&self, place: &Expr) -> usize {
+        match &place.kind {
+            ExprKind::Var(name) => self.find_variable(name).map_or(0, |(depth, _)| depth),
+            ExprKind::Deref(pointer) => self.lifetime(pointer),
+            ExprKind::Index { array: base, .. } | ExprKind::Field { base, .. } => {
+                self.place_lifetime(base)
+            }
+            _ => self.variables.len() - 1,
+        }
+    }
+
+    /// A reference can only be stored where it doesn't outlive what it points to
+    fn store_reference(&mut self, target: &Expr, value: &Expr) -> Result<(), CompileError> {
+        let lifetime = self.lifetime(value);
+        let ExprKind::Var(name) = &target.kind else {
+            // The place reached through another reference can be anywhere, even in the caller
+            if lifetime > 0 {
+                let message =
+                    "Cannot store a reference to a local variable through another reference"
+                        .to_string();
+                return Err(self.error(ErrorKind::DanglingReference, message));
+            }
+            return Ok(());
+        };
+        let depth = self.find_variable(name).map_or(0, |(depth, _)| depth);
+        if lifetime > depth {
+            let message = format!(
+                "Reference stored in {} would outlive the variable it points to",
+                name
+            );
+            return Err(self.error(ErrorKind::DanglingReference, message));
+        }
+        if let Some(variable) = self.variables[depth].get_mut(name) {
+            variable.lifetime = variable.lifetime.max(lifetime);
+        }
+        Ok(())
+    }
+
+    fn lookup_variable(&self, name: &str) -> Result<VarType, CompileError> {
+        let var_type = self
+            .variables
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).map(|variable| variable.var_type.clone()));
+        match var_type {
+            Some(var_type) => Ok(var_type),
+            None if self.expired.contains(name) => {
+                let message = format!("Variable {} is used outside of its scope", name);
+                Err(self.error(ErrorKind::OutOfScope, message))
+            }
+            None => {
+                let message = format!("Variable {} not declared", name);
+                Err(self.error(ErrorKind::UnknownName, message))
+            }
+        }
+    }
+
+    fn infer(&mut self, expr: &mut Expr) -> Result<VarType, CompileError> {
+        self.infer_as(expr, None)
+    }
+
+    /// `hint` is the type expected by the context, it decides the type of integer literals
+    fn infer_as(
+        &mut self,
+        expr: &mut Expr,
+        hint: Option<&VarType>,
+    ) -> Result<VarType, CompileError> {
+        let found = self.infer_hinted(expr, hint)?;
+        if found != VarType::NUM && expr.is_untyped_int() {
+            let span = expr.span;
+            let literal = mem::replace(expr, ExprKind::Number(0).into());
+            let cast = ExprKind::Cast {
+                expr: Box::new(literal),
+                to: found.clone(),
+            };
+            *expr = Expr::new(cast, span);
+            expr.var_type = Some(found.clone());
+        }
+        Ok(found)
+    }
+
+    /// Operands of a literal-only expression are left as they are, it is cast as a whole
+    fn infer_operand(
+        &mut self,
+        expr: &mut Expr,
+        hint: Option<&VarType>,
+        untyped: bool,
+    ) -> Result<VarType, CompileError> {
+        if untyped {
+            self.infer_hinted(expr, hint)
+        } else {
+            self.infer_as(expr, hint)
+        }
+    }
+
+    /// A literal operand takes the type of the other operand
+    fn infer_operands(
+        &mut self,
+        left: &mut Expr,
+        right: &mut Expr,
+        hint: Option<&VarType>,
+    ) -> Result<(VarType, VarType), CompileError> {
+        let untyped = left.is_untyped_int() && right.is_untyped_int();
+        if left.is_untyped_int() && !untyped {
+            let right_type = self.infer_as(right, hint)?;
+            let left_type = self.infer_as(left, Some(&right_type))?;
+            return Ok((left_type, right_type));
+        }
+        let left_type = self.infer_operand(left, hint, untyped)?;
+        let right_type = self.infer_operand(right, Some(&left_type), untyped)?;
+        Ok((left_type, right_type))
+    }
+
+    /// An error points at the innermost expression it was found in, the type is kept on the node
+    fn infer_hinted(
+        &mut self,
+        expr: &mut Expr,
+        hint: Option<&VarType>,
+    ) -> Result<VarType, CompileError> {
+        let outer = mem::replace(&mut self.span, expr.span);
+        let var_type = self.infer_kind(expr, hint)?;
+        self.span = outer;
+        expr.var_type = Some(var_type.clone());
+        Ok(var_type)
+    }
+
+    fn infer_kind(
+        &mut self,
+        expr: &mut Expr,
+        hint: Option<&VarType>,
+    ) -> Result<VarType, CompileError> {
+        let span = expr.span;
+        match &mut expr.kind {
+            ExprKind::Number(n) => self.literal_type(*n, false, int_hint(hint), span),
+            ExprKind::Bool(_) => Ok(VarType::Bool),
+            ExprKind::Str(_) => {
+                let message = "String literals can only be used as arguments of print and println"
+                    .to_string();
+                Err(self.error(ErrorKind::Misplaced, message))
+            }
+            ExprKind::Var(name) => self.lookup_variable(name),
+            // Errors about the operands point at the operator and the right operand
+            ExprKind::Binary {
+                left,
+                op,
+                op_span,
+                right,
+            } => {
+                let operator = Span::new(op_span.start, span.end);
+                match op {
+                    BinaryOp::And | BinaryOp::Or => {
+                        let left = self.infer(left)?;
+                        let right = self.infer(right)?;
+                        if left != VarType::Bool || right != VarType::Bool {
+                            let message = format!(
+                                "Operator '{}' expects bool operands, found {} and {}",
+                                op, left, right
+                            );
+                            let error = CompileError::new(ErrorKind::InvalidOperand, message);
+                            return Err(error.at(operator));
+                        }
+                        Ok(VarType::Bool)
+                    }
+                    // The shift amount doesn't have to match the type of the shifted value
+                    BinaryOp::Shl | BinaryOp::Shr | BinaryOp::Ushr => {
+                        let untyped = left.is_untyped_int() && right.is_untyped_int();
+                        let value = self.infer_operand(left, hint, untyped)?;
+                        let amount = self.infer_operand(right, None, untyped)?;
+                        if !matches!(value, VarType::Int(_)) || !matches!(amount, VarType::Int(_)) {
+                            return Err(operand_error(op, &value, &amount).at(operator));
+                        }
+                        Ok(value)
+                    }
+                    BinaryOp::Add
+                    | BinaryOp::Sub
+                    | BinaryOp::Mul
+                    | BinaryOp::Div
+                    | BinaryOp::Mod
+                    | BinaryOp::BitAnd
+                    | BinaryOp::BitOr
+                    | BinaryOp::BitXor => {
+                        let (left, right) = self.infer_operands(left, right, hint)?;
+                        let bitwise =
+                            matches!(op, BinaryOp::BitAnd | BinaryOp::BitOr | BinaryOp::BitXor);
+                        match (&left, &right) {
+                            (VarType::Int(_), _) if left == right => return Ok(left),
+                            (VarType::Bool, VarType::Bool) if bitwise => return Ok(left),
+                            _ => (),
+                        }
+                        if !bitwise {
+                            return Err(operand_error(op, &left, &right).at(operator));
+                        }
+                        let message = format!(
+                            "Operator '{}' expects two {} or two bool operands, found {} and {}",
+                            op,
+                            expected_int(&left, &right),
+                            left,
+                            right
+                        );
+                        Err(CompileError::new(ErrorKind::InvalidOperand, message).at(operator))
+                    }
+                    BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge => {
+                        let (left, right) = self.infer_operands(left, right, None)?;
+                        if !matches!(left, VarType::Int(_)) || left != right {
+                            return Err(operand_error(op, &left, &right).at(operator));
+                        }
+                        Ok(VarType::Bool)
+                    }
+                    BinaryOp::Eq | BinaryOp::Neq => {
+                        let (left, right) = self.infer_operands(left, right, None)?;
+                        if left != right || !left.is_scalar() {
+                            let message =
+                                format!("Operator '{}' cannot compare {} with {}", op, left, right);
+                            let error = CompileError::new(ErrorKind::InvalidOperand, message);
+                            return Err(error.at(operator));
+                        }
+                        Ok(VarType::Bool)
+                    }
+                }
+            }
+            ExprKind::Unary { op, expr } => {
+                if let (UnaryOp::Neg, ExprKind::Number(n)) = (&op, &expr.kind) {
+                    return self.literal_type(*n, true, int_hint(hint), span);
+                }
+                let untyped = expr.is_untyped_int();
+                match op {
+                    UnaryOp::Not => {
+                        let found = self.infer(expr)?;
+                        if found != VarType::Bool {
+                            let message = format!(
+                                "Operator '{}' expects a bool operand, found {}",
+                                op, found
+                            );
+                            return Err(self.error(ErrorKind::InvalidOperand, message));
+                        }
+                        Ok(found)
+                    }
+                    UnaryOp::Neg => match self.infer_operand(expr, hint, untyped)? {
+                        VarType::Int(int_type) if int_type.is_signed() => {
+                            Ok(VarType::Int(int_type))
+                        }
+                        found => {
+                            let message = format!(
+                                "Operator '{}' expects a signed integer operand, found {}",
+                                op, found
+                            );
+                            Err(self.error(ErrorKind::InvalidOperand, message))
+                        }
+                    },
+                    UnaryOp::BitNot => match self.infer_operand(expr, hint, untyped)? {
+                        VarType::Int(int_type) => Ok(VarType::Int(int_type)),
+                        found => {
+                            let message = format!(
+                                "Operator '{}' expects an integer operand, found {}",
+                                op, found
+                            );
+                            Err(self.error(ErrorKind::InvalidOperand, message))
+                        }
+                    },
+                }
+            }
+            // Literals take the type of the cast, so `300 as u8` is out of range
+            ExprKind::Cast { expr, to } => {
+                if !matches!(to, VarType::Int(_)) {
+                    let message = format!("Cannot cast to {}, only integer types are allowed", to);
+                    return Err(self.error(ErrorKind::InvalidType, message));
+                }
+                self.validate_type(to)?;
+                let from = self.infer_as(expr, Some(to))?;
+                if !from.is_scalar() {
+                    let message = format!("Cannot cast a value of type {} to {}", from, to);
+                    return Err(self.error(ErrorKind::InvalidOperand, message));
+                }
+                Ok(to.clone())
+            }
+            ExprKind::Let {
+                name,
+                var_type,
+                expr,
+                ..
+            } => {
+                self.validate_type(var_type)?;
+                self.expect(expr, var_type, &format!("'let {}'", name))?;
+                let lifetime = self.lifetime(expr);
+                self.declare_variable(name.clone(), var_type.clone());
+                if let VarType::Ref(..) = var_type {
+                    let scope = self.variables.last_mut().expect("Scope stack is empty");
+                    scope
+                        .get_mut(name)
+                        .expect("Variable was just declared")
+                        .lifetime = lifetime;
+                }
+                Ok(VarType::Unit)
+            }
+            ExprKind::Const {
+                name,
+                var_type,
+                expr,
+            } => {
+                if !var_type.is_scalar() {
+                    let message = format!(
+                        "Constant {} has type {}, only integers and bool can be constants",
+                        name, var_type
+                    );
+                    return Err(self.error(ErrorKind::InvalidType, message));
+                }
+                self.validate_type(var_type)?;
+                self.expect(expr, var_type, &format!("'const {}'", name))?;
+                let Some(value) = self.constant_value(expr)? else {
+                    let message = format!(
+                        "Constant {} must be initialized with literals, other constants and operators",
+                        name
+                    );
+                    return Err(self.error(ErrorKind::NotConstant, message));
+                };
+                self.declare_constant(name.clone(), var_type.clone(), value);
+                Ok(VarType::Unit)
+            }
+            ExprKind::Assign { target, expr } => {
+                let var_type = self.infer(target)?;
+                self.check_mutable(target, "assign")?;
+                let context = match &target.kind {
+                    ExprKind::Var(name) => format!("assignment to {}", name),
+                    _ if matches!(var_type, VarType::Array(..)) => {
+                        let message = "Arrays can only be assigned to variables".to_string();
+                        return Err(self.error(ErrorKind::InvalidPlace, message));
+                    }
+                    _ => "assignment".to_string(),
+                };
+                self.expect(expr, &var_type, &context)?;
+                if let VarType::Ref(..) = var_type {
+                    self.store_reference(target, expr)?;
+                }
+                Ok(VarType::Unit)
+            }
+            // Same rules as for `target op expr`, whose result has to fit back into the target
+            ExprKind::CompoundAssign { target, op, expr } => {
+                let var_type = self.infer(target)?;
+                self.check_mutable(target, "assign")?;
+                let shift = matches!(op, BinaryOp::Shl | BinaryOp::Shr | BinaryOp::Ushr);
+                let found = if shift {
+                    self.infer(expr)?
+                } else {
+                    self.infer_as(expr, Some(&var_type))?
+                };
+                let is_int = |var_type: &VarType| matches!(var_type, VarType::Int(_));
+                let valid = match op {
+                    _ if shift => is_int(&var_type) && is_int(&found),
+                    BinaryOp::BitAnd | BinaryOp::BitOr | BinaryOp::BitXor => {
+                        var_type.is_scalar() && found == var_type
+                    }
+                    _ => is_int(&var_type) && found == var_type,
+                };
+                if !valid {
+                    let message = format!(
+                        "Operator '{}=' cannot be applied to {} and {}",
+                        op, var_type, found
+                    );
+                    return Err(self.error(ErrorKind::InvalidOperand, message));
+                }
+                Ok(VarType::Unit)
+            }
+            ExprKind::Array(elements) => {
+                let len = elements.len();
+                let element_hint = match hint {
+                    Some(VarType::Array(element, _)) => Some(element.as_ref()),
+                    _ => None,
+                };
+                let Some(first) = elements.first_mut() else {
+                    let message = "Empty array literals are not supported".to_string();
+                    return Err(self.error(ErrorKind::InvalidType, message));
+                };
+                let element = self.infer_as(first, element_hint)?;
+                if !element.is_scalar() {
+                    let message = format!("Arrays of {} are not supported", element);
+                    return Err(self.error(ErrorKind::InvalidType, message));
+                }
+                for (i, expr) in elements.iter_mut().enumerate().skip(1) {
+                    self.expect(expr, &element, &format!("array element {}", i))?;
+                }
+                Ok(VarType::Array(Box::new(element), len))
+            }
+            ExprKind::ArrayRepeat { value, count } => {
+                let element_hint = match hint {
+                    Some(VarType::Array(element, _)) => Some(element.as_ref()),
+                    _ => None,
+                };
+                let element = self.infer_as(value, element_hint)?;
+                let array = VarType::Array(Box::new(element), *count);
+                self.validate_type(&array)?;
+                Ok(array)
+            }
+            ExprKind::Index { array, index } => {
+                if !matches!(array.kind, ExprKind::Var(_) | ExprKind::Deref(_)) {
+                    let message = "Only array variables and references can be indexed".to_string();
+                    return Err(self.error(ErrorKind::InvalidPlace, message));
+                }
+                let (element, len) = match self.infer_auto_deref(array)? {
+                    VarType::Array(element, len) => (element, len),
+                    other => {
+                        let message = format!("Cannot index into a value of type {}", other);
+                        return Err(self.error(ErrorKind::InvalidOperand, message));
+                    }
+                };
+                self.expect(index, &VarType::NUM, "array index")?;
+                if let Some(n) = self.constant_value(index)? {
+                    if n < 0 || n >= len as i64 {
+                        let message =
+                            format!("Index {} is out of bounds for array of length {}", n, len);
+                        return Err(self.error(ErrorKind::IndexOutOfBounds, message));
+                    }
+                }
+                Ok(*element)
+            }
+            ExprKind::Struct { name, .. } => {
+                if self.variables.len() > 1 {
+                    let message = format!("Struct {} can only be declared at the top level", name);
+                    return Err(self.error(ErrorKind::Misplaced, message));
+                }
+                Ok(VarType::Unit)
+            }
+            ExprKind::Enum { name, .. } => {
+                if self.variables.len() > 1 {
+                    let message = format!("Enum {} can only be declared at the top level", name);
+                    return Err(self.error(ErrorKind::Misplaced, message));
+                }
+                Ok(VarType::Unit)
+            }
+            ExprKind::Variant {
+                enum_name,
+                variant,
+                args,
+            } => {
+                if !self.enums.contains_key(enum_name) {
+                    let message = format!("Unknown type {}", enum_name);
+                    return Err(self.error(ErrorKind::UnknownName, message));
+                }
+                let fields = self.variant_fields(enum_name, variant)?;
+                if args.len() != fields.len() {
+                    let message = format!(
+                        "Variant {}::{} takes {} fields, but {} were supplied",
+                        enum_name,
+                        variant,
+                        fields.len(),
+                        args.len()
+                    );
+                    return Err(self.error(ErrorKind::ArgumentCount, message));
+                }
+                for (i, (arg, field)) in args.iter_mut().zip(&fields).enumerate() {
+                    let context = format!("field {} of {}::{}", i, enum_name, variant);
+                    self.expect(arg, field, &context)?;
+                }
+                Ok(VarType::Enum(enum_name.clone()))
+            }
+            ExprKind::StructLiteral { name, fields } => {
+                if !self.structs.contains_key(name) {
+                    let message = format!("Unknown type {}", name);
+                    return Err(self.error(ErrorKind::UnknownName, message));
+                }
+                for (i, (field, _)) in fields.iter().enumerate() {
+                    if fields[..i].iter().any(|(other, _)| other == field) {
+                        let message = format!(
+                            "Field {} is initialized multiple times in {} literal",
+                            field, name
+                        );
+                        return Err(self.error(ErrorKind::DuplicateDefinition, message));
+                    }
+                }
+                for (field, value) in fields.iter_mut() {
+                    let field_type = self.field_type(name, field)?;
+                    self.expect(value, &field_type, &format!("field {} of {}", field, name))?;
+                }
+                if let Some(missing) = self.structs[name.as_str()]
+                    .iter()
+                    .find(|declared| fields.iter().all(|(field, _)| *field != declared.name))
+                {
+                    let message = format!("Missing field {} in {} literal", missing.name, name);
+                    return Err(self.error(ErrorKind::MissingField, message));
+                }
+                Ok(VarType::Struct(name.clone()))
+            }
+            ExprKind::Field { base, field } => match self.infer_auto_deref(base)? {
+                VarType::Struct(name) => self.field_type(&name, field),
+                other => {
+                    let message =
+                        format!("Cannot access field {} of a value of type {}", field, other);
+                    Err(self.error(ErrorKind::InvalidOperand, message))
+                }
+            },
+            ExprKind::Ref { mutable, expr } => {
+                match &expr.kind {
+                    ExprKind::Var(name) if self.is_constant(name) => {
+                        let message = format!("Cannot take the address of constant {}", name);
+                        return Err(self.error(ErrorKind::InvalidPlace, message));
+                    }
+                    _ if !expr.is_place() => {
+                        let message =
+                            "Only variables, array elements and fields have an address".to_string();
+                        return Err(self.error(ErrorKind::InvalidPlace, message));
+                    }
+                    _ => {}
+                }
+                let var_type = self.infer(expr)?;
+                if *mutable {
+                    self.check_mutable(expr, "borrow mutably")?;
+                }
+                Ok(VarType::Ref(Box::new(var_type), *mutable))
+            }
+            ExprKind::Deref(pointer) => match self.infer(pointer)? {
+                VarType::Ref(pointee, _) => Ok(*pointee),
+                other => {
+                    let message = format!("Cannot dereference a value of type {}", other);
+                    Err(self.error(ErrorKind::InvalidOperand, message))
+                }
+            },
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expect(condition, &VarType::Bool, "'if' condition")?;
+                let then_type = self.check_block(then_branch, hint)?;
+                let Some(else_branch) = else_branch else {
+                    if !matches!(then_type, VarType::Unit | VarType::Never) {
+                        let message =
+                            format!("'if' without 'else' must have type (), found {}", then_type);
+                        return Err(self.error(ErrorKind::MismatchedTypes, message));
+                    }
+                    return Ok(VarType::Unit);
+                };
+                let else_type = self.check_block(else_branch, hint)?;
+                match (then_type, else_type) {
+                    (VarType::Never, other) | (other, VarType::Never) => Ok(other),
+                    (then_type, else_type) if then_type == else_type => Ok(then_type),
+                    (then_type, else_type) => {
+                        let message = format!(
+                            "'if' and 'else' have incompatible types: {} and {}",
+                            then_type, else_type
+                        );
+                        Err(self.error(ErrorKind::MismatchedTypes, message))
+                    }
+                }
+            }
+            ExprKind::Match { scrutinee, arms } => {
+                let scrutinee_type = self.infer(scrutinee)?;
+                if !scrutinee_type.is_scalar() && !matches!(scrutinee_type, VarType::Enum(_)) {
+                    let message = format!("Cannot match on a value of type {}", scrutinee_type);
+                    return Err(self.error(ErrorKind::InvalidOperand, message));
+                }
+                if arms
+                    .iter()
+                    .any(|arm| arm.patterns.len() > 1 && binds_variables(&arm.patterns))
+                {
+                    let message = "Patterns combined with '|' can't bind variables".to_string();
+                    return Err(self.error(ErrorKind::InvalidPattern, message));
+                }
+                let mut covered = HashSet::new();
+                let mut covered_variants = HashSet::new();
+                let mut wildcard = false;
+                for pattern in arms.iter_mut().flat_map(|arm| &mut arm.patterns) {
+                    match pattern {
+                        Pattern::Wildcard => wildcard = true,
+                        Pattern::Value(value) => {
+                            self.check_pattern(value, &scrutinee_type)?;
+                            if scrutinee_type == VarType::Bool {
+                                let value = self.constant_value(value)?;
+                                covered.extend(value.map(|value| value != 0));
+                            }
+                        }
+                        Pattern::Range(start, end) => {
+                            if scrutinee_type == VarType::Bool {
+                                let message = "Range patterns can only match integers, found bool"
+                                    .to_string();
+                                return Err(self.error(ErrorKind::InvalidPattern, message));
+                            }
+                            self.check_pattern(start, &scrutinee_type)?;
+                            self.check_pattern(end, &scrutinee_type)?;
+                            let (low, high) =
+                                (self.constant_value(start)?, self.constant_value(end)?);
+                            // Bounds are ordered as the matched type is, unsigned or signed
+                            let (low, high) = match &scrutinee_type {
+                                VarType::Int(int_type) if !int_type.is_signed() => (
+                                    low.map(|value| value as u64 as i128),
+                                    high.map(|value| value as u64 as i128),
+                                ),
+                                _ => (low.map(i128::from), high.map(i128::from)),
+                            };
+                            if let (Some(low), Some(high)) = (low, high) {
+                                if low > high {
+                                    let message =
+                                        format!("Range pattern {}..={} is empty", low, high);
+                                    return Err(self.error(ErrorKind::EmptyRange, message));
+                                }
+                            }
+                        }
+                        Pattern::Variant {
+                            enum_name,
+                            variant,
+                            bindings,
+                        } => {
+                            self.check_variant_pattern(
+                                enum_name,
+                                variant,
+                                bindings,
+                                &scrutinee_type,
+                            )?;
+                            covered_variants.insert(variant.clone());
+                        }
+                    }
+                }
+                if !wildcard {
+                    match &scrutinee_type {
+                        VarType::Bool => {
+                            if let Some(missing) =
+                                [true, false].into_iter().find(|b| !covered.contains(b))
+                            {
+                                let message =
+                                    format!("Match on bool is missing an arm for {}", missing);
+                                return Err(self.error(ErrorKind::NonExhaustiveMatch, message));
+                            }
+                        }
+                        VarType::Enum(name) => {
+                            if let Some(missing) = self.enums[name]
+                                .iter()
+                                .find(|variant| !covered_variants.contains(&variant.name))
+                            {
+                                let message = format!(
+                                    "Match on {} is missing an arm for {}::{}",
+                                    name, name, missing.name
+                                );
+                                return Err(self.error(ErrorKind::NonExhaustiveMatch, message));
+                            }
+                        }
+                        _ => {
+                            let message =
+                                format!("Match on {} must have a '_' arm", scrutinee_type);
+                            return Err(self.error(ErrorKind::NonExhaustiveMatch, message));
+                        }
+                    }
+                }
+
+                // Arms that never complete don't decide the type
+                let mut match_type = VarType::Never;
+                for arm in arms {
+                    // Bound fields are variables of the arm
+                    self.variables.push(HashMap::new());
+                    if let [Pattern::Variant {
+                        enum_name,
+                        variant,
+                        bindings,
+                    }] = arm.patterns.as_slice()
+                    {
+                        let fields = self.variant_fields(enum_name, variant)?;
+                        for (binding, field) in bindings.iter().zip(fields) {
+                            if let Some(binding) = binding {
+                                self.declare_variable(binding.name.clone(), field);
+                            }
+                        }
+                    }
+                    let arm_type = self.check_block(&mut arm.body, hint)?;
+                    let scope = self.variables.pop().expect("Scope stack is empty");
+                    self.expired.extend(scope.into_keys());
+                    match (&match_type, arm_type) {
+                        (_, VarType::Never) => (),
+                        (VarType::Never, arm_type) => match_type = arm_type,
+                        (match_type, arm_type) if *match_type == arm_type => (),
+                        (match_type, arm_type) => {
+                            let message = format!(
+                                "Match arms have incompatible types: {} and {}",
+                                match_type, arm_type
+                            );
+                            return Err(self.error(ErrorKind::MismatchedTypes, message));
+                        }
+                    }
+                }
+                Ok(match_type)
+            }
+            ExprKind::Block(block) => self.check_block(block, hint),
+            ExprKind::While { condition, body } => {
+                self.expect(condition, &VarType::Bool, "'while' condition")?;
+                self.loop_depth += 1;
+                self.check_block(body, None)?;
+                self.loop_depth -= 1;
+                Ok(VarType::Unit)
+            }
+            ExprKind::For {
+                var,
+                start,
+                end,
+                step,
+                body,
+                ..
+            } => {
+                self.expect(start, &VarType::NUM, "range start")?;
+                self.expect(end, &VarType::NUM, "range end")?;
+                if let Some(step) = step {
+                    self.expect(step, &VarType::NUM, "range step")?;
+                    if let Some(n) = self.constant_value(step)?.filter(|n| *n <= 0) {
+                        let message = format!("Step of a 'for' loop must be positive, found {}", n);
+                        return Err(self.error(ErrorKind::InvalidStep, message));
+                    }
+                }
+                self.variables.push(HashMap::new());
+                self.declare_variable(var.clone(), VarType::NUM);
+                self.loop_depth += 1;
+                self.check_block(body, None)?;
+                self.loop_depth -= 1;
+                let scope = self.variables.pop().expect("Scope stack is empty");
+                self.expired.extend(scope.into_keys());
+                Ok(VarType::Unit)
+            }
+            ExprKind::Function {
+                name,
+                params,
+                ret_type,
+                body,
+            } => {
+                if self.current_function.is_some() {
+                    let message = format!("Nested function {} is not supported", name);
+                    return Err(self.error(ErrorKind::Misplaced, message));
+                }
+                let ret_type = ret_type.clone().unwrap_or(VarType::Unit);
+                // The body sees the constants declared before the function at the top level
+                let globals = self.variables[0]
+                    .iter()
+                    .filter(|(_, variable)| variable.value.is_some())
+                    .map(|(name, variable)| (name.clone(), variable.clone()))
+                    .collect();
+                let outer_variables =
+                    mem::replace(&mut self.variables, vec![globals, HashMap::new()]);
+                for param in params {
+                    if self
+                        .declare_variable(param.name.clone(), param.var_type.clone())
+                        .is_some()
+                    {
+                        let message = format!(
+                            "Parameter {} is declared multiple times in function '{}'",
+                            param.name, name
+                        );
+                        return Err(self.error(ErrorKind::DuplicateDefinition, message));
+                    }
+                }
+                self.current_function = Some((name.clone(), ret_type.clone()));
+                let outer_loop_depth = mem::replace(&mut self.loop_depth, 0);
+                let body_type = self.check_block(body, Some(&ret_type))?;
+                self.loop_depth = outer_loop_depth;
+                // The trailing expression of the body is the return value
+                if body_type == VarType::Unit && ret_type != VarType::Unit {
+                    let message = format!(
+                        "Function '{}' must return a value of type {} on every path",
+                        name, ret_type
+                    );
+                    return Err(self.error(ErrorKind::MismatchedTypes, message));
+                }
+                if body_type != ret_type && body_type != VarType::Never {
+                    let message = format!(
+                        "Mismatched types in return from '{}': expected {}, found {}",
+                        name, ret_type, body_type
+                    );
+                    return Err(self.error(ErrorKind::MismatchedTypes, message));
+                }
+                self.current_function = None;
+                self.variables = outer_variables;
+                Ok(VarType::Unit)
+            }
+            ExprKind::Call { name, args } if is_builtin(name) => {
+                for (i, arg) in args.iter_mut().enumerate() {
+                    if let ExprKind::Str(_) = arg.kind {
+                        continue;
+                    }
+                    let arg_type = self.infer(arg)?;
+                    if !arg_type.is_scalar() {
+                        let message = format!(
+                            "Argument {} of call to '{}' has type {}, only integers, bool and strings can be printed",
+                            i + 1,
+                            name,
+                            arg_type
+                        );
+                        return Err(self.error(ErrorKind::InvalidOperand, message));
+                    }
+                }
+                Ok(VarType::Unit)
+            }
+            ExprKind::Call { name, args } => {
+                let Some(signature) = self.functions.get(name) else {
+                    let message = format!("Function '{}' not declared", name);
+                    return Err(self.error(ErrorKind::UnknownName, message));
+                };
+                if signature.params.len() != args.len() {
+                    let message = format!(
+                        "Function '{}' takes {} arguments, but {} were supplied",
+                        name,
+                        signature.params.len(),
+                        args.len()
+                    );
+                    return Err(self.error(ErrorKind::ArgumentCount, message));
+                }
+                let params = signature.params.clone();
+                let ret_type = signature.ret_type.clone();
+                for (i, (arg, param)) in args.iter_mut().zip(&params).enumerate() {
+                    self.expect(
+                        arg,
+                        param,
+                        &format!("argument {} of call to '{}'", i + 1, name),
+                    )?;
+                }
+                Ok(ret_type)
+            }
+            ExprKind::Import { name, .. } => {
+                let message = format!(
+                    "Module {} can only be imported at the top level of a file",
+                    name
+                );
+                Err(self.error(ErrorKind::Misplaced, message))
+            }
+            ExprKind::Break | ExprKind::Continue => {
+                if self.loop_depth == 0 {
+                    let keyword = if matches!(expr.kind, ExprKind::Break) {
+                        "break"
+                    } else {
+                        "continue"
+                    };
+                    let message = format!("'{}' outside of a loop", keyword);
+                    return Err(self.error(ErrorKind::Misplaced, message));
+                }
+                Ok(VarType::Never)
+            }
+            // A program with syntax errors isn't checked, a partial one can be
+            // without the error spreading to the code around it
+            ExprKind::Error => Ok(VarType::Never),
+            ExprKind::Return(expr) => {
+                let Some((name, ret_type)) = self.current_function.clone() else {
+                    let message = "'return' outside of function".to_string();
+                    return Err(self.error(ErrorKind::Misplaced, message));
+                };
+                match expr {
+                    Some(expr) => {
+                        self.expect(expr, &ret_type, &format!("return from '{}'", name))?
+                    }
+                    None if ret_type != VarType::Unit => {
+                        let message = format!(
+                            "Function '{}' must return a value of type {}",
+                            name, ret_type
+                        );
+                        return Err(self.error(ErrorKind::MismatchedTypes, message));
+                    }
+                    None => (),
+                }
+                Ok(VarType::Never)
+            }
+        }
+    }
+
+    /// A '-' in front of the literal lets it reach the minimum of a signed type
+    fn literal_type(
+        &self,
+        value: u64,
+        negative: bool,
+        int_type: IntType,
+        span: Span,
+    ) -> Result<VarType, CompileError> {
+        let bits = int_type.bits();
+        let max = match (int_type.is_signed(), negative) {
+            (true, false) => (1 << (bits - 1)) - 1,
+            (true, true) => 1 << (bits - 1),
+            (false, false) => u64::MAX >> (64 - bits),
+            (false, true) => 0,
+        };
+        if value > max {
+            let sign = if negative { "-" } else { "" };
+            let literal = match self.sources.and_then(|sources| sources.snippet(span)) {
+                Some(text) => text.to_string(),
+                None => format!("{}{}", sign, value),
+            };
+            let message = format!("Literal {} is out of range for {}", literal, int_type);
+            return Err(self.error(ErrorKind::LiteralOutOfRange, message));
+        }
+        Ok(VarType::Int(int_type))
+    }
+}
+
+pub fn is_builtin(name: &str) -> bool {
+    matches!(name, "print" | "println")
+}
+
+/// Names whose labels would clash with the symbols the generated program defines
+fn is_reserved(name: &str) -> bool {
+    name == "_start" || name.starts_with(RUNTIME_PREFIX)
+}
+
+fn int_hint(hint: Option<&VarType>) -> IntType {
+    match hint {
+        Some(VarType::Int(int_type)) => *int_type,
+        _ => IntType::I32,
+    }
+}
+
+/// Integer type the operands of an operator should have agreed on
+fn expected_int(left: &VarType, right: &VarType) -> String {
+    [left, right]
+        .into_iter()
+        .find(|operand| matches!(operand, VarType::Int(_)))
+        .map_or("integer".to_string(), |operand| operand.to_string())
+}
+
+/// Without a span, operand errors point at the operator
+fn operand_error(op: &BinaryOp, left: &VarType, right: &VarType) -> CompileError {
+    let message = format!(
+        "Operator '{}' expects {} operands, found {} and {}",
+        op,
+        expected_int(left, right),
+        left,
+        right
+    );
+    CompileError::new(ErrorKind::InvalidOperand, message)
+}
+
+fn binds_variables(patterns: &[Pattern]) -> bool {
+    patterns.iter().any(|pattern| match pattern {
+        Pattern::Variant { bindings, .. } => bindings.iter().any(Option::is_some),
+        _ => false,
+    })
+}